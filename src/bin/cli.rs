@@ -0,0 +1,147 @@
+//! Command-line entrypoint for running the pipeline from cron or CI without Node.
+//!
+//! Usage: `cli <upload [path] | dry-run | download | delete <uid> | reconcile | rebuild-metadata | check>`
+
+use std::env;
+use std::path::Path;
+use std::process::ExitCode;
+
+use file_upload::config::UploadOptions;
+use file_upload::mount_s3::S3Mount;
+use file_upload::upload_s3::S3Upload;
+use file_upload::{
+    check_connection, download_all_images, init_logging, process_and_upload_all, process_and_upload_path, rebuild_metadata,
+    FileResult,
+};
+
+const USAGE: &str = "usage: cli <upload [path] | dry-run | download | delete <uid> | reconcile | rebuild-metadata | check>";
+
+fn report(file: &FileResult) {
+    match &file.error {
+        Some(error) => eprintln!("failed  {}: {}", file.path, error),
+        None if file.skipped => println!("skipped {}: metadata already exists", file.path),
+        None => {
+            println!("ok      {} ({} bytes)", file.path, file.bytes_uploaded);
+            for url in file.original_url.iter().chain(&file.variant_urls) {
+                println!("        {}", url);
+            }
+        }
+    }
+}
+
+async fn upload(dry_run: bool) -> Result<bool, String> {
+    let mut options = UploadOptions::from_env();
+    options.dry_run = dry_run;
+
+    let summary = process_and_upload_all(&options, report)
+        .await
+        .map_err(|e| e.to_string())?;
+    if summary.inbox_missing {
+        eprintln!("no inbox at content/uploads/_inbox; run from the site root or create the folder");
+    }
+    println!(
+        "{} of {} files processed, {} skipped, {} failed, {} bytes uploaded",
+        summary.processed,
+        summary.total,
+        summary.skipped,
+        summary.failed,
+        summary.bytes_uploaded
+    );
+    if summary.remaining > 0 {
+        println!("{} files left for the next run (BATCH_LIMIT)", summary.remaining);
+    }
+    Ok(summary.failed == 0)
+}
+
+async fn upload_path(path: &str) -> Result<bool, String> {
+    let options = UploadOptions::from_env();
+    let summary = process_and_upload_path(Path::new(path), &options, report)
+        .await
+        .map_err(|e| e.to_string())?;
+    println!(
+        "{} of {} files processed, {} skipped, {} failed, {} bytes uploaded",
+        summary.processed,
+        summary.total,
+        summary.skipped,
+        summary.failed,
+        summary.bytes_uploaded
+    );
+    Ok(summary.failed == 0)
+}
+
+async fn download() -> Result<bool, String> {
+    let summary = download_all_images().await.map_err(|e| e.to_string())?;
+    for error in &summary.errors {
+        eprintln!("{}", error);
+    }
+    println!("{} downloaded, {} failed, {} bytes", summary.succeeded, summary.failed, summary.bytes);
+    Ok(summary.failed == 0)
+}
+
+async fn delete(uid: &str) -> Result<bool, String> {
+    let upload = S3Upload::new().await.map_err(|e| e.to_string())?;
+    let count = upload.delete(uid).await.map_err(|e| e.to_string())?;
+    println!("Deleted {} objects for {}", count, uid);
+    Ok(true)
+}
+
+async fn reconcile() -> Result<bool, String> {
+    let mount = S3Mount::new().await.map_err(|e| e.to_string())?;
+    let report = mount.reconcile().await.map_err(|e| e.to_string())?;
+    let json = serde_json::to_string_pretty(&report).map_err(|e| e.to_string())?;
+    println!("{}", json);
+    Ok(report.missing.is_empty() && report.orphaned.is_empty())
+}
+
+async fn rebuild() -> Result<bool, String> {
+    let summary = rebuild_metadata().await.map_err(|e| e.to_string())?;
+    for path in &summary.written {
+        println!("wrote   {}", path);
+    }
+    for error in &summary.errors {
+        eprintln!("{}", error);
+    }
+    println!(
+        "{} written, {} already present, {} failed",
+        summary.written.len(),
+        summary.skipped.len(),
+        summary.errors.len()
+    );
+    Ok(summary.errors.is_empty())
+}
+
+async fn check() -> Result<bool, String> {
+    let message = check_connection().await.map_err(|e| e.to_string())?;
+    println!("{}", message);
+    Ok(true)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    init_logging();
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["upload"] => upload(false).await,
+        ["upload", path] => upload_path(path).await,
+        ["dry-run"] => upload(true).await,
+        ["download"] => download().await,
+        ["delete", uid] => delete(uid).await,
+        ["reconcile"] => reconcile().await,
+        ["rebuild-metadata"] => rebuild().await,
+        ["check"] => check().await,
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
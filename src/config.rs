@@ -0,0 +1,805 @@
+use aws_sdk_s3::types::{ObjectCannedAcl, ServerSideEncryption, StorageClass};
+use chrono::{DateTime, FixedOffset, Local, Utc};
+use image::imageops::FilterType;
+use log::warn;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_BUCKET: &str = "digitalgov";
+const DEFAULT_REGION: &str = "us-east-1";
+const DEFAULT_IMAGE_S3_PREFIX: &str = "";
+const DEFAULT_STATIC_S3_PREFIX: &str = "static/";
+
+/// Matches the image crate's own default JPEG quality.
+const DEFAULT_VARIANT_QUALITY: u8 = 75;
+
+const DEFAULT_MAX_FILE_SIZE_MB: u64 = 50;
+
+/// Static files stream rather than decode, so they get far more room.
+const DEFAULT_MAX_STATIC_FILE_SIZE_MB: u64 = 2048;
+
+const DEFAULT_MULTIPART_THRESHOLD_MB: u64 = 100;
+
+const DEFAULT_DOWNLOAD_CONCURRENCY: usize = 8;
+
+const DEFAULT_S3_TIMEOUT_SECS: u64 = 60;
+
+const DEFAULT_THUMBNAIL_SIZE: u32 = 150;
+
+const DEFAULT_INBOX_MAX_DEPTH: usize = 3;
+
+/// Content column width at desktop sizes, full width below it.
+const DEFAULT_SRCSET_SIZES: &str = "(max-width: 800px) 100vw, 800px";
+
+/// The `date` layout Hugo reads, e.g. `2024-01-02 03:04:05 -0500`.
+const DEFAULT_METADATA_DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S %z";
+
+const DEFAULT_WATERMARK_OPACITY: f32 = 0.5;
+
+const DEFAULT_WATERMARK_SCALE: f32 = 0.15;
+
+/// Reads a boolean flag from the environment. `1`, `true` and `yes` (any
+/// case) turn it on; anything else, or an unset variable, leaves it off.
+pub fn env_flag(name: &str) -> bool {
+    env::var(name)
+        .map(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+/// Whether re-uploads of identical bytes to the same key are skipped, from
+/// `IDEMPOTENT_SKIP`. On unless set to something other than `1`/`true`/`yes`.
+pub fn idempotent_skip() -> bool {
+    env::var("IDEMPOTENT_SKIP").is_err() || env_flag("IDEMPOTENT_SKIP")
+}
+
+/// Reads and parses a value from the environment, falling back to `default`
+/// when the variable is unset or doesn't parse.
+pub fn env_parse<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+/// Where uploads go in S3. Read once from `AWS_BUCKET_NAME`, `AWS_REGION`,
+/// `IMAGE_S3_PREFIX` and `STATIC_S3_PREFIX` (after loading `.env`), so the
+/// same build can target staging or production.
+#[derive(Debug, Clone)]
+pub struct S3Location {
+    pub bucket: String,
+    /// Bucket for images and their variants, from `IMAGE_BUCKET`; `bucket`
+    /// when unset.
+    pub image_bucket: String,
+    /// Bucket for static files, from `FILE_BUCKET`; `bucket` when unset.
+    pub file_bucket: String,
+    pub region: String,
+    pub image_prefix: String,
+    pub static_prefix: String,
+    /// An S3-compatible endpoint such as MinIO or R2, from `AWS_ENDPOINT_URL`.
+    /// Requests then use path-style addressing.
+    pub endpoint_url: Option<String>,
+}
+
+impl S3Location {
+    fn from_env() -> Self {
+        dotenv::dotenv().ok();
+
+        let var = |name: &str, default: &str| env::var(name).unwrap_or_else(|_| default.to_string());
+        let bucket = var("AWS_BUCKET_NAME", DEFAULT_BUCKET);
+        let split_bucket = |name: &str| {
+            env::var(name)
+                .ok()
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+                .unwrap_or_else(|| bucket.clone())
+        };
+        S3Location {
+            image_bucket: split_bucket("IMAGE_BUCKET"),
+            file_bucket: split_bucket("FILE_BUCKET"),
+            bucket,
+            region: var("AWS_REGION", DEFAULT_REGION),
+            image_prefix: var("IMAGE_S3_PREFIX", DEFAULT_IMAGE_S3_PREFIX),
+            static_prefix: var("STATIC_S3_PREFIX", DEFAULT_STATIC_S3_PREFIX),
+            endpoint_url: env::var("AWS_ENDPOINT_URL")
+                .ok()
+                .map(|url| url.trim_end_matches('/').to_string())
+                .filter(|url| !url.is_empty()),
+        }
+    }
+}
+
+/// The buckets, region and key prefixes shared by every upload and download path.
+pub fn s3() -> &'static S3Location {
+    static LOCATION: Lazy<S3Location> = Lazy::new(S3Location::from_env);
+    &LOCATION
+}
+
+/// Content types `mime_guess` gets wrong or leaves generic, fixed by default.
+const DEFAULT_CONTENT_TYPE_OVERRIDES: &[(&str, &str)] = &[
+    // So browsers render them inline rather than offering a download
+    ("svg", "image/svg+xml"),
+    ("csv", "text/csv"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("md", "text/markdown"),
+];
+
+/// Parses `ext=mime` pairs separated by commas, e.g. `csv=text/csv,log=text/plain`.
+/// Extensions are lowercased and may carry a leading dot; malformed pairs are skipped.
+fn parse_content_type_overrides(value: &str) -> HashMap<String, String> {
+    value
+        .split(',')
+        .filter_map(|pair| {
+            let (extension, mime) = pair.split_once('=')?;
+            let extension = extension.trim().trim_start_matches('.').to_lowercase();
+            let mime = mime.trim();
+            (!extension.is_empty() && mime.contains('/')).then(|| (extension, mime.to_string()))
+        })
+        .collect()
+}
+
+/// Extension -> content type corrections applied before `mime_guess`: the
+/// built-in defaults plus any from `CONTENT_TYPE_OVERRIDES`, which win.
+pub fn content_type_overrides() -> &'static HashMap<String, String> {
+    static OVERRIDES: Lazy<HashMap<String, String>> = Lazy::new(|| {
+        let mut overrides: HashMap<String, String> = DEFAULT_CONTENT_TYPE_OVERRIDES
+            .iter()
+            .map(|(extension, mime)| (extension.to_string(), mime.to_string()))
+            .collect();
+        if let Ok(value) = env::var("CONTENT_TYPE_OVERRIDES") {
+            overrides.extend(parse_content_type_overrides(&value));
+        }
+        overrides
+    });
+    &OVERRIDES
+}
+
+/// Parses `key=value` tags separated by `&` or commas, e.g.
+/// `project=digitalgov&team=web`. Pairs without a key are skipped.
+fn parse_tags(value: &str) -> Vec<(String, String)> {
+    value
+        .split(['&', ','])
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            let key = key.trim();
+            (!key.is_empty()).then(|| (key.to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+/// The URL-encoded `Tagging` header for an uploaded object: the base tags
+/// from `OBJECT_TAGS` followed by `extra`, whose keys win over base tags of
+/// the same name. Bucket lifecycle rules match on these.
+pub fn object_tagging(extra: &[(&str, &str)]) -> String {
+    static BASE: Lazy<Vec<(String, String)>> =
+        Lazy::new(|| env::var("OBJECT_TAGS").map(|value| parse_tags(&value)).unwrap_or_default());
+    tagging(&BASE, extra)
+}
+
+fn tagging(base: &[(String, String)], extra: &[(&str, &str)]) -> String {
+    base.iter()
+        .filter(|(key, _)| !extra.iter().any(|(extra_key, _)| extra_key == key))
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .chain(extra.iter().copied())
+        .map(|(key, value)| format!("{}={}", urlencoding::encode(key), urlencoding::encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Extensions accepted for upload when `ALLOWED_EXTENSIONS` isn't set.
+const DEFAULT_ALLOWED_EXTENSIONS: &[&str] = &[
+    // Images
+    "jpg", "jpeg", "png", "gif", "bmp", "tiff", "webp",
+    // Vector graphics, uploaded as static files without resizing
+    "svg",
+    // Documents
+    "doc", "docx", "pdf", "txt", "rtf", "xls", "xlsx", "csv", "ppt", "pptx",
+    // Other common formats
+    "zip", "rar", "7z",
+];
+
+/// Parses a comma-separated extension list such as `mp4, .MP3`. Entries are
+/// lowercased and may carry a leading dot; empty entries are skipped.
+fn parse_extension_list(value: &str) -> HashSet<String> {
+    value
+        .split(',')
+        .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+        .filter(|extension| !extension.is_empty())
+        .collect()
+}
+
+/// Which file extensions may be uploaded. A blocked extension is refused even
+/// when it's also allowed.
+#[derive(Debug, Clone)]
+pub struct ExtensionPolicy {
+    allowed: HashSet<String>,
+    blocked: HashSet<String>,
+}
+
+impl ExtensionPolicy {
+    /// Reads `ALLOWED_EXTENSIONS`, falling back to the built-in list when it's
+    /// unset or empty, and `BLOCKED_EXTENSIONS`.
+    pub fn from_env() -> Self {
+        let allowed = env::var("ALLOWED_EXTENSIONS")
+            .map(|value| parse_extension_list(&value))
+            .ok()
+            .filter(|allowed| !allowed.is_empty())
+            .unwrap_or_else(|| DEFAULT_ALLOWED_EXTENSIONS.iter().map(|e| e.to_string()).collect());
+        let blocked = env::var("BLOCKED_EXTENSIONS")
+            .map(|value| parse_extension_list(&value))
+            .unwrap_or_default();
+
+        ExtensionPolicy { allowed, blocked }
+    }
+
+    /// Whether `extension` (any case, without the dot) may be uploaded.
+    pub fn allows(&self, extension: &str) -> bool {
+        let extension = extension.to_lowercase();
+        self.allowed.contains(&extension) && !self.blocked.contains(&extension)
+    }
+}
+
+/// The extension policy from the environment, read once.
+pub fn extension_policy() -> &'static ExtensionPolicy {
+    static POLICY: Lazy<ExtensionPolicy> = Lazy::new(ExtensionPolicy::from_env);
+    &POLICY
+}
+
+/// Parses a `METADATA_TZ` value: `UTC`/`Z` or a fixed offset like `-05:00`
+/// or `+0530`.
+fn parse_utc_offset(value: &str) -> Option<FixedOffset> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("utc") || value.eq_ignore_ascii_case("z") {
+        return FixedOffset::east_opt(0);
+    }
+    let sign = match value.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+    let digits: String = value[1..].chars().filter(|c| *c != ':').collect();
+    if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i32 = digits[..2].parse().ok()?;
+    let minutes: i32 = digits[2..].parse().ok()?;
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+fn format_metadata_date(now: DateTime<Utc>, offset: Option<FixedOffset>, format: &str) -> String {
+    match offset {
+        Some(offset) => now.with_timezone(&offset).format(format).to_string(),
+        None => now.with_timezone(&Local).format(format).to_string(),
+    }
+}
+
+/// The current time for metadata `date` fields, in `METADATA_DATE_FORMAT`
+/// (chrono syntax). The offset is the server's real local offset, DST
+/// included, unless `METADATA_TZ` pins one.
+pub fn metadata_date() -> String {
+    let format = env::var("METADATA_DATE_FORMAT").unwrap_or_else(|_| DEFAULT_METADATA_DATE_FORMAT.to_string());
+    format_metadata_date(Utc::now(), metadata_offset(), &format)
+}
+
+/// The offset pinned by `METADATA_TZ`, if any.
+fn metadata_offset() -> Option<FixedOffset> {
+    env::var("METADATA_TZ").ok().and_then(|value| {
+        let offset = parse_utc_offset(&value);
+        if offset.is_none() {
+            warn!("Unknown METADATA_TZ value {:?}, using the local offset", value);
+        }
+        offset
+    })
+}
+
+/// The `YYYY/MM/` key segment for objects uploaded now, in the same time
+/// zone as the metadata dates.
+pub fn date_partition() -> String {
+    format_metadata_date(Utc::now(), metadata_offset(), "%Y/%m/")
+}
+
+/// Encoder quality (1-100) for resized JPEG variants, from `VARIANT_QUALITY`.
+pub fn variant_quality() -> u8 {
+    env_parse("VARIANT_QUALITY", DEFAULT_VARIANT_QUALITY).clamp(1, 100)
+}
+
+/// Resampling filter used for every variant, from `RESIZE_FILTER`:
+///
+/// | value                    | filter       | good for                  |
+/// |--------------------------|--------------|---------------------------|
+/// | `nearest`                | `Nearest`    | pixel art, screenshots    |
+/// | `triangle` / `bilinear`  | `Triangle`   | fast, slightly soft       |
+/// | `catmullrom` / `bicubic` | `CatmullRom` | general purpose           |
+/// | `gaussian`               | `Gaussian`   | smooth, softest           |
+/// | `lanczos3` (default)     | `Lanczos3`   | photos, sharpest          |
+pub fn resize_filter() -> FilterType {
+    match env::var("RESIZE_FILTER").map(|v| v.trim().to_lowercase()).as_deref() {
+        Ok("nearest") => FilterType::Nearest,
+        Ok("triangle" | "bilinear") => FilterType::Triangle,
+        Ok("catmullrom" | "bicubic") => FilterType::CatmullRom,
+        Ok("gaussian") => FilterType::Gaussian,
+        Ok("lanczos3") | Err(_) => FilterType::Lanczos3,
+        Ok(other) => {
+            warn!("Unknown RESIZE_FILTER value {:?}, defaulting to lanczos3", other);
+            FilterType::Lanczos3
+        }
+    }
+}
+
+/// Parses a `#rrggbb` or `#rgb` colour; the `#` is optional.
+fn parse_hex_color(value: &str) -> Option<[u8; 3]> {
+    let hex = value.trim().trim_start_matches('#');
+    if !hex.is_ascii() {
+        return None;
+    }
+    let channel = |digits: &str| u8::from_str_radix(digits, 16).ok();
+    match hex.len() {
+        6 => Some([channel(&hex[0..2])?, channel(&hex[2..4])?, channel(&hex[4..6])?]),
+        3 => {
+            let [r, g, b] = [channel(&hex[0..1])?, channel(&hex[1..2])?, channel(&hex[2..3])?];
+            Some([r * 17, g * 17, b * 17])
+        }
+        _ => None,
+    }
+}
+
+/// Colour transparent areas are composited onto when an image is written in
+/// a format without alpha, such as JPEG, from `FLATTEN_BG`; white by default.
+pub fn flatten_background() -> [u8; 3] {
+    const WHITE: [u8; 3] = [255, 255, 255];
+    match env::var("FLATTEN_BG") {
+        Err(_) => WHITE,
+        Ok(value) => parse_hex_color(&value).unwrap_or_else(|| {
+            warn!("Invalid FLATTEN_BG value {:?}, defaulting to white", value);
+            WHITE
+        }),
+    }
+}
+
+/// Largest source image accepted for upload, from `MAX_FILE_SIZE_MB`.
+pub fn max_file_size_mb() -> u64 {
+    env_parse("MAX_FILE_SIZE_MB", DEFAULT_MAX_FILE_SIZE_MB)
+}
+
+/// Largest static file accepted for upload, from `MAX_STATIC_FILE_SIZE_MB`.
+pub fn max_static_file_size_mb() -> u64 {
+    env_parse("MAX_STATIC_FILE_SIZE_MB", DEFAULT_MAX_STATIC_FILE_SIZE_MB)
+}
+
+/// Files larger than this many bytes are sent with the multipart API instead
+/// of a single `put_object`, from `MULTIPART_THRESHOLD_MB`.
+pub fn multipart_threshold_bytes() -> u64 {
+    env_parse("MULTIPART_THRESHOLD_MB", DEFAULT_MULTIPART_THRESHOLD_MB).saturating_mul(1024 * 1024)
+}
+
+/// How long connecting to S3, or any wait for data mid-request, may take
+/// before the attempt is abandoned and retried, from `S3_TIMEOUT_SECS`;
+/// `0` waits forever.
+pub fn s3_timeout() -> Option<Duration> {
+    Some(env_parse("S3_TIMEOUT_SECS", DEFAULT_S3_TIMEOUT_SECS))
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// How many bucket downloads run at once, from `DOWNLOAD_CONCURRENCY`.
+pub fn download_concurrency() -> usize {
+    env_parse("DOWNLOAD_CONCURRENCY", DEFAULT_DOWNLOAD_CONCURRENCY).max(1)
+}
+
+/// How many levels of subfolders under the inbox are searched for files,
+/// from `INBOX_MAX_DEPTH`; `0` reads only the inbox itself.
+pub fn inbox_max_depth() -> usize {
+    env_parse("INBOX_MAX_DEPTH", DEFAULT_INBOX_MAX_DEPTH)
+}
+
+/// Edge length of the square thumbnail. Thumbnails are opt-in: they're made
+/// when `THUMBNAILS` is set, at 150px, or when `THUMBNAIL_SIZE` names a size;
+/// `0` turns them off.
+pub fn thumbnail_size() -> Option<u32> {
+    if env::var("THUMBNAIL_SIZE").is_err() && !env_flag("THUMBNAILS") {
+        return None;
+    }
+    Some(env_parse("THUMBNAIL_SIZE", DEFAULT_THUMBNAIL_SIZE)).filter(|&size| size > 0)
+}
+
+/// Smallest width or height an image may have, from `MIN_DIMENSION`; unset
+/// or `0` accepts any size.
+pub fn min_dimension() -> Option<u32> {
+    Some(env_parse("MIN_DIMENSION", 0)).filter(|&size| size > 0)
+}
+
+/// The `sizes` attribute written next to each image's `srcset`, from
+/// `SRCSET_SIZES`.
+pub fn srcset_sizes() -> String {
+    env::var("SRCSET_SIZES")
+        .ok()
+        .filter(|sizes| !sizes.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_SRCSET_SIZES.to_string())
+}
+
+/// Reads an S3 storage class such as `STANDARD_IA` from the environment,
+/// falling back to `STANDARD` when unset or unrecognised.
+fn storage_class(name: &str) -> StorageClass {
+    match env::var(name) {
+        Err(_) => StorageClass::Standard,
+        Ok(value) => match StorageClass::from(value.trim().to_uppercase().as_str()) {
+            StorageClass::Unknown(_) => {
+                warn!("Unknown {} value {:?}, defaulting to STANDARD", name, value);
+                StorageClass::Standard
+            }
+            class => class,
+        },
+    }
+}
+
+/// Storage class for full-size originals, from `ORIGINAL_STORAGE_CLASS`.
+pub fn original_storage_class() -> StorageClass {
+    storage_class("ORIGINAL_STORAGE_CLASS")
+}
+
+/// Storage class for resized variants, from `VARIANT_STORAGE_CLASS`.
+pub fn variant_storage_class() -> StorageClass {
+    storage_class("VARIANT_STORAGE_CLASS")
+}
+
+/// Canned ACL sent with every upload, from `OBJECT_ACL`: `public-read` (the
+/// default), `private`, `bucket-owner-full-control`, or `none` to leave the
+/// ACL off the request for buckets that enforce bucket-owner ownership.
+pub fn object_acl() -> Option<ObjectCannedAcl> {
+    parse_object_acl(env::var("OBJECT_ACL").ok().as_deref())
+}
+
+fn parse_object_acl(value: Option<&str>) -> Option<ObjectCannedAcl> {
+    match value.map(|v| v.trim().to_lowercase()).as_deref() {
+        None | Some("" | "public-read") => Some(ObjectCannedAcl::PublicRead),
+        Some("private") => Some(ObjectCannedAcl::Private),
+        Some("bucket-owner-full-control") => Some(ObjectCannedAcl::BucketOwnerFullControl),
+        Some("none") => None,
+        Some(other) => {
+            warn!("Unknown OBJECT_ACL value {:?}, defaulting to public-read", other);
+            Some(ObjectCannedAcl::PublicRead)
+        }
+    }
+}
+
+/// What to do when an upload's S3 key is already taken, from `ON_COLLISION`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    /// Replace the existing object (`overwrite`, the default).
+    #[default]
+    Overwrite,
+    /// Append a numeric suffix until the key is free (`suffix`).
+    Suffix,
+    /// Fail the file (`error`).
+    Error,
+}
+
+impl CollisionPolicy {
+    fn from_env() -> Self {
+        match env::var("ON_COLLISION").map(|v| v.to_lowercase()).as_deref() {
+            Ok("suffix") => CollisionPolicy::Suffix,
+            Ok("error") => CollisionPolicy::Error,
+            Ok("overwrite") | Err(_) => CollisionPolicy::Overwrite,
+            Ok(other) => {
+                warn!("Unknown ON_COLLISION value {:?}, defaulting to overwrite", other);
+                CollisionPolicy::Overwrite
+            }
+        }
+    }
+}
+
+/// What to do when an image's `data/images/{uid}.yml` already exists, from
+/// `ON_EXISTING`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExistingPolicy {
+    /// Replace the metadata and objects with a warning (`overwrite`, the default).
+    #[default]
+    Overwrite,
+    /// Leave the existing image alone and skip the file, keeping the source
+    /// (`skip`).
+    Skip,
+    /// Append a numeric suffix until the uid is free (`rename`).
+    Rename,
+}
+
+impl ExistingPolicy {
+    fn from_env() -> Self {
+        match env::var("ON_EXISTING").map(|v| v.to_lowercase()).as_deref() {
+            Ok("skip") => ExistingPolicy::Skip,
+            Ok("rename") => ExistingPolicy::Rename,
+            Ok("overwrite") | Err(_) => ExistingPolicy::Overwrite,
+            Ok(other) => {
+                warn!("Unknown ON_EXISTING value {:?}, defaulting to overwrite", other);
+                ExistingPolicy::Overwrite
+            }
+        }
+    }
+}
+
+/// How an upload's uid (and so its keys and metadata file) is derived, from
+/// `UID_STRATEGY`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UidStrategy {
+    /// The sanitized file stem (`name`, the default).
+    #[default]
+    Name,
+    /// The first 12 hex digits of the content hash (`hash`), so identical
+    /// names from different editors can't overwrite each other.
+    Hash,
+    /// The stem with the first 8 hash digits appended (`name-hash`), e.g.
+    /// `photo-3f2a9c1b`.
+    NameHash,
+}
+
+impl UidStrategy {
+    fn from_env() -> Self {
+        match env::var("UID_STRATEGY").map(|v| v.to_lowercase()).as_deref() {
+            Ok("hash") => UidStrategy::Hash,
+            Ok("name-hash") => UidStrategy::NameHash,
+            Ok("name") | Err(_) => UidStrategy::Name,
+            Ok(other) => {
+                warn!("Unknown UID_STRATEGY value {:?}, defaulting to name", other);
+                UidStrategy::Name
+            }
+        }
+    }
+
+    /// The uid for a file with sanitized `stem` and hex content `hash`.
+    pub fn uid(self, stem: &str, hash: &str) -> String {
+        match self {
+            UidStrategy::Name => stem.to_string(),
+            UidStrategy::Hash => hash.chars().take(12).collect(),
+            UidStrategy::NameHash => format!("{}-{}", stem, &hash[..hash.len().min(8)]),
+        }
+    }
+}
+
+/// Corner (or centre) a watermark is placed in, from `WATERMARK_POSITION`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    /// `bottom-right`, the default.
+    #[default]
+    BottomRight,
+    Center,
+}
+
+/// A logo overlaid on every image, configured by `WATERMARK_PATH` (a PNG),
+/// `WATERMARK_POSITION`, `WATERMARK_OPACITY` (0-1, default 0.5) and
+/// `WATERMARK_SCALE` (watermark width as a fraction of the image's, default 0.15).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watermark {
+    pub path: PathBuf,
+    pub position: WatermarkPosition,
+    pub opacity: f32,
+    pub scale: f32,
+}
+
+impl Watermark {
+    /// The configured watermark, or `None` when `WATERMARK_PATH` is unset.
+    pub fn from_env() -> Option<Self> {
+        let path = env::var("WATERMARK_PATH").ok().filter(|p| !p.is_empty())?;
+        let position = match env::var("WATERMARK_POSITION").map(|v| v.to_lowercase()).as_deref() {
+            Ok("top-left") => WatermarkPosition::TopLeft,
+            Ok("top-right") => WatermarkPosition::TopRight,
+            Ok("bottom-left") => WatermarkPosition::BottomLeft,
+            Ok("center") | Ok("centre") => WatermarkPosition::Center,
+            Ok("bottom-right") | Err(_) => WatermarkPosition::BottomRight,
+            Ok(other) => {
+                warn!("Unknown WATERMARK_POSITION value {:?}, defaulting to bottom-right", other);
+                WatermarkPosition::BottomRight
+            }
+        };
+
+        Some(Watermark {
+            path: PathBuf::from(path),
+            position,
+            opacity: env_parse("WATERMARK_OPACITY", DEFAULT_WATERMARK_OPACITY).clamp(0.0, 1.0),
+            scale: env_parse("WATERMARK_SCALE", DEFAULT_WATERMARK_SCALE).clamp(0.01, 1.0),
+        })
+    }
+}
+
+/// Server-side encryption applied to every `put_object`, from `SSE_MODE`
+/// (`aes256` or `aws:kms`) and, for KMS, `SSE_KMS_KEY_ID`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Encryption {
+    /// Leave encryption to the bucket's defaults (unset `SSE_MODE`).
+    #[default]
+    None,
+    /// S3-managed keys (`aes256`).
+    Aes256,
+    /// KMS keys (`aws:kms`); without a key id S3 uses the account's default key.
+    Kms { key_id: Option<String> },
+}
+
+impl Encryption {
+    pub fn from_env() -> Self {
+        match env::var("SSE_MODE").map(|v| v.to_lowercase()).as_deref() {
+            Err(_) | Ok("") => Encryption::None,
+            Ok("aes256") => Encryption::Aes256,
+            Ok("aws:kms") => Encryption::Kms { key_id: env::var("SSE_KMS_KEY_ID").ok() },
+            Ok(other) => {
+                warn!("Unknown SSE_MODE value {:?}, uploading without server-side encryption", other);
+                Encryption::None
+            }
+        }
+    }
+
+    /// The `x-amz-server-side-encryption` value, if any.
+    pub fn server_side_encryption(&self) -> Option<ServerSideEncryption> {
+        match self {
+            Encryption::None => None,
+            Encryption::Aes256 => Some(ServerSideEncryption::Aes256),
+            Encryption::Kms { .. } => Some(ServerSideEncryption::AwsKms),
+        }
+    }
+
+    /// The KMS key id to encrypt with, if one was configured.
+    pub fn kms_key_id(&self) -> Option<String> {
+        match self {
+            Encryption::Kms { key_id } => key_id.clone(),
+            _ => None,
+        }
+    }
+}
+
+/// Run-level switches for the upload pipeline.
+#[derive(Debug, Clone, Default)]
+pub struct UploadOptions {
+    /// Report intended S3 writes and deletions instead of performing them.
+    pub dry_run: bool,
+    pub on_collision: CollisionPolicy,
+    pub on_existing: ExistingPolicy,
+    pub uid_strategy: UidStrategy,
+    /// Fail images that have no alt text instead of writing an empty `alt`.
+    pub require_alt: bool,
+    /// Regenerate and re-upload everything for a file even when its content
+    /// hash or key already exists, overwriting the metadata.
+    pub force_reprocess: bool,
+    /// Leave the working directories, uploaded sources and resized
+    /// intermediates on disk for inspection instead of cleaning them up.
+    pub keep_working: bool,
+    /// Inserted between the configured prefix and the file name of every key,
+    /// e.g. `2024/06/` with `DATE_PARTITION`. Fixed for the run, so a batch
+    /// that crosses midnight at month end still lands in one place.
+    pub key_partition: Option<String>,
+    /// Keep the subfolders files were queued in under the inbox as part of
+    /// their keys, e.g. `images/events/2024/photo.png`, instead of flattening
+    /// them into the prefix.
+    pub preserve_inbox_paths: bool,
+    /// Process at most this many working files per run, leaving the rest
+    /// for the next one, so a huge import can go in resumable chunks.
+    pub batch_limit: Option<usize>,
+    /// Upload objects without writing `data/images` or `data/files` YAML,
+    /// for sites whose own tooling builds the metadata from the result.
+    pub skip_metadata: bool,
+}
+
+impl UploadOptions {
+    /// Builds the options from the environment, loading `.env` first.
+    pub fn from_env() -> Self {
+        dotenv::dotenv().ok();
+
+        UploadOptions {
+            dry_run: env_flag("DRY_RUN"),
+            on_collision: CollisionPolicy::from_env(),
+            on_existing: ExistingPolicy::from_env(),
+            uid_strategy: UidStrategy::from_env(),
+            require_alt: env_flag("REQUIRE_ALT"),
+            force_reprocess: env_flag("FORCE_REPROCESS"),
+            keep_working: env_flag("KEEP_WORKING"),
+            key_partition: env_flag("DATE_PARTITION").then(date_partition),
+            preserve_inbox_paths: env_flag("PRESERVE_INBOX_PATHS"),
+            batch_limit: Some(env_parse("BATCH_LIMIT", 0)).filter(|&limit| limit > 0),
+            skip_metadata: env_flag("SKIP_METADATA"),
+        }
+    }
+
+    /// These options for files kept in an inbox subfolder such as
+    /// `events/2024/`, which goes after any partition in their keys.
+    pub fn in_subfolder(&self, subfolder: &str) -> Self {
+        let partition = format!("{}{}", self.key_partition.as_deref().unwrap_or(""), subfolder);
+        UploadOptions { key_partition: Some(partition).filter(|p| !p.is_empty()), ..self.clone() }
+    }
+
+    /// `prefix` (an image or static prefix) with the run's partition, if any.
+    pub fn key_prefix(&self, prefix: &str) -> String {
+        format!("{}{}", prefix, self.key_partition.as_deref().unwrap_or(""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_type_overrides_parse_extension_pairs() {
+        let overrides = parse_content_type_overrides(" .CSV = text/csv , log=text/plain,broken,ext=notamime");
+        assert_eq!(overrides.len(), 2);
+        assert_eq!(overrides["csv"], "text/csv");
+        assert_eq!(overrides["log"], "text/plain");
+    }
+
+    #[test]
+    fn blocked_extensions_win_over_allowed_ones() {
+        let policy = ExtensionPolicy {
+            allowed: parse_extension_list("png, .MP4,mp3,zip,,"),
+            blocked: parse_extension_list("ZIP"),
+        };
+        assert!(policy.allows("mp4"));
+        assert!(policy.allows("PNG"));
+        assert!(!policy.allows("zip"));
+        assert!(!policy.allows("pdf"));
+    }
+
+    #[test]
+    fn tagging_encodes_and_lets_extra_tags_win() {
+        let base = parse_tags("project=digital gov&type=misc, ,=orphan");
+        assert_eq!(base.len(), 2);
+        assert_eq!(
+            tagging(&base, &[("type", "image"), ("role", "variant/2x")]),
+            "project=digital%20gov&type=image&role=variant%2F2x"
+        );
+    }
+
+    #[test]
+    fn hex_colors_parse_in_long_and_short_form() {
+        assert_eq!(parse_hex_color("#1a2B3c"), Some([0x1a, 0x2b, 0x3c]));
+        assert_eq!(parse_hex_color("fff"), Some([255, 255, 255]));
+        assert_eq!(parse_hex_color("#f0a"), Some([255, 0, 170]));
+        assert_eq!(parse_hex_color("white"), None);
+        assert_eq!(parse_hex_color("#ééé"), None);
+    }
+
+    #[test]
+    fn object_acl_can_be_omitted() {
+        assert_eq!(parse_object_acl(None), Some(ObjectCannedAcl::PublicRead));
+        assert_eq!(parse_object_acl(Some(" Private ")), Some(ObjectCannedAcl::Private));
+        assert_eq!(
+            parse_object_acl(Some("bucket-owner-full-control")),
+            Some(ObjectCannedAcl::BucketOwnerFullControl)
+        );
+        assert_eq!(parse_object_acl(Some("none")), None);
+    }
+
+    #[test]
+    fn key_prefix_adds_the_partition() {
+        let options = UploadOptions { key_partition: Some("2024/06/".to_string()), ..UploadOptions::default() };
+        assert_eq!(options.key_prefix("images/"), "images/2024/06/");
+        assert_eq!(UploadOptions::default().key_prefix("images/"), "images/");
+
+        let nested = options.in_subfolder("events/");
+        assert_eq!(nested.key_prefix("images/"), "images/2024/06/events/");
+        assert_eq!(UploadOptions::default().in_subfolder("").key_partition, None);
+    }
+
+    #[test]
+    fn uid_strategies() {
+        let hash = "3f2a9c1b7d4e5f60718293a4b5c6d7e8";
+        assert_eq!(UidStrategy::Name.uid("photo", hash), "photo");
+        assert_eq!(UidStrategy::Hash.uid("photo", hash), "3f2a9c1b7d4e");
+        assert_eq!(UidStrategy::NameHash.uid("photo", hash), "photo-3f2a9c1b");
+    }
+
+    #[test]
+    fn metadata_dates_use_the_pinned_offset() {
+        let now = DateTime::parse_from_rfc3339("2024-01-15T12:00:00Z").unwrap().with_timezone(&Utc);
+        assert_eq!(
+            format_metadata_date(now, parse_utc_offset("-05:00"), DEFAULT_METADATA_DATE_FORMAT),
+            "2024-01-15 07:00:00 -0500"
+        );
+        assert_eq!(
+            format_metadata_date(now, parse_utc_offset("+0530"), DEFAULT_METADATA_DATE_FORMAT),
+            "2024-01-15 17:30:00 +0530"
+        );
+        assert_eq!(format_metadata_date(now, parse_utc_offset("UTC"), "%Y-%m-%d"), "2024-01-15");
+        assert_eq!(parse_utc_offset("EST"), None);
+    }
+}
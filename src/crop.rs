@@ -0,0 +1,110 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage};
+
+use crate::variants::{CropRegion, CropStrategy};
+
+/// Longest side of the greyscale copy entropy is measured on. Plenty to find
+/// the subject and keeps the scan cheap on large sources.
+const ANALYSIS_SIZE: u32 = 256;
+
+/// How many window positions are tried along the free axis.
+const CROP_POSITIONS: u32 = 32;
+
+/// Cuts `region` out of `img`. Entropy crops first slide the window to the
+/// busiest part of the image and record where it landed in `region`.
+pub fn apply(img: &DynamicImage, region: &mut CropRegion) -> DynamicImage {
+    if region.strategy == CropStrategy::Entropy {
+        (region.x, region.y) = entropy_offset(img, region.width, region.height);
+    }
+    img.crop_imm(region.x, region.y, region.width, region.height)
+}
+
+/// Top-left corner of the `width` x `height` window with the most luminance
+/// entropy. Ties go to the window nearest the centre, so flat images crop
+/// the same way a centre crop would.
+fn entropy_offset(img: &DynamicImage, width: u32, height: u32) -> (u32, u32) {
+    let scale = (ANALYSIS_SIZE as f32 / img.width().max(img.height()) as f32).min(1.0);
+    let scaled = |length: u32, limit: u32| ((length as f32 * scale).round() as u32).clamp(1, limit);
+
+    let luma = img
+        .resize_exact(scaled(img.width(), u32::MAX), scaled(img.height(), u32::MAX), FilterType::Triangle)
+        .to_luma8();
+    let window_width = scaled(width, luma.width());
+    let window_height = scaled(height, luma.height());
+    let (free_x, free_y) = (luma.width() - window_width, luma.height() - window_height);
+    let step = (free_x.max(free_y) / CROP_POSITIONS).max(1) as usize;
+
+    let distance = |x: u32, y: u32| (x as f32 - free_x as f32 / 2.0).abs() + (y as f32 - free_y as f32 / 2.0).abs();
+    let mut best = (f32::MIN, f32::MAX, 0, 0);
+    for y in (0..=free_y).step_by(step) {
+        for x in (0..=free_x).step_by(step) {
+            let score = (entropy(&luma, x, y, window_width, window_height), distance(x, y), x, y);
+            if score.0 > best.0 + f32::EPSILON || ((score.0 - best.0).abs() <= f32::EPSILON && score.1 < best.1) {
+                best = score;
+            }
+        }
+    }
+
+    let unscale = |offset: u32, limit: u32| ((offset as f32 / scale).round() as u32).min(limit);
+    (unscale(best.2, img.width() - width), unscale(best.3, img.height() - height))
+}
+
+/// Shannon entropy, in bits, of the luminance histogram of a window.
+fn entropy(luma: &GrayImage, x: u32, y: u32, width: u32, height: u32) -> f32 {
+    let mut histogram = [0u32; 256];
+    for row in y..y + height {
+        for column in x..x + width {
+            histogram[luma.get_pixel(column, row)[0] as usize] += 1;
+        }
+    }
+
+    let total = (width * height) as f32;
+    histogram
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f32 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn region(strategy: CropStrategy) -> CropRegion {
+        CropRegion { aspect: "1:1".to_string(), strategy, x: 150, y: 0, width: 100, height: 100 }
+    }
+
+    #[test]
+    fn entropy_crop_moves_towards_detail() {
+        // Flat grey with a noisy patch near the right edge
+        let mut img = RgbImage::from_pixel(400, 100, Rgb([128, 128, 128]));
+        for y in 0..100 {
+            for x in 300..400 {
+                let v = ((x * 31 + y * 17) % 256) as u8;
+                img.put_pixel(x, y, Rgb([v, v, v]));
+            }
+        }
+        let img = DynamicImage::ImageRgb8(img);
+
+        let mut entropy = region(CropStrategy::Entropy);
+        let cropped = apply(&img, &mut entropy);
+        assert_eq!((cropped.width(), cropped.height()), (100, 100));
+        assert!(entropy.x >= 250, "crop stayed at x={}", entropy.x);
+
+        let mut centre = region(CropStrategy::Center);
+        apply(&img, &mut centre);
+        assert_eq!(centre.x, 150);
+    }
+
+    #[test]
+    fn flat_images_crop_from_the_centre() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(400, 100, Rgb([10, 20, 30])));
+        let mut entropy = region(CropStrategy::Entropy);
+        apply(&img, &mut entropy);
+        assert_eq!((entropy.x, entropy.y), (150, 0));
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// How far into a file to look for an XMP packet. JPEG keeps it in an early
+/// APP1 segment and PNG in an iTXt chunk ahead of the pixel data.
+const XMP_SCAN_BYTES: u64 = 1024 * 1024;
+
+/// Caption and credit embedded in an image by the camera or the agency.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct EmbeddedText {
+    pub caption: String,
+    pub credit: String,
+}
+
+/// Reads the caption and credit embedded in an image, preferring XMP
+/// (`dc:description`, `photoshop:Credit`, `dc:creator`) and falling back to
+/// the EXIF `ImageDescription`, `Artist` and `Copyright` tags. Fields the file
+/// doesn't carry are left empty.
+pub fn read(path: &Path) -> EmbeddedText {
+    let xmp = read_xmp_packet(path).map(|packet| parse_xmp(&packet)).unwrap_or_default();
+    let exif = read_exif(path).unwrap_or_default();
+
+    EmbeddedText {
+        caption: first_non_empty([xmp.caption, exif.caption]),
+        credit: first_non_empty([xmp.credit, exif.credit]),
+    }
+}
+
+fn first_non_empty<const N: usize>(values: [String; N]) -> String {
+    values.into_iter().find(|value| !value.is_empty()).unwrap_or_default()
+}
+
+fn read_exif(path: &Path) -> Option<EmbeddedText> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    let ascii = |tag| {
+        match &exif.get_field(tag, exif::In::PRIMARY)?.value {
+            exif::Value::Ascii(parts) => {
+                let text = parts.iter().map(|part| String::from_utf8_lossy(part)).collect::<Vec<_>>().join(" ");
+                Some(text.trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_string())
+            }
+            _ => None,
+        }
+    };
+
+    Some(EmbeddedText {
+        caption: ascii(exif::Tag::ImageDescription).unwrap_or_default(),
+        credit: first_non_empty([
+            ascii(exif::Tag::Artist).unwrap_or_default(),
+            ascii(exif::Tag::Copyright).unwrap_or_default(),
+        ]),
+    })
+}
+
+/// The `<x:xmpmeta>` packet from the start of the file, if there is one.
+fn read_xmp_packet(path: &Path) -> Option<String> {
+    let mut bytes = Vec::new();
+    File::open(path).ok()?.take(XMP_SCAN_BYTES).read_to_end(&mut bytes).ok()?;
+
+    let start = find(&bytes, b"<x:xmpmeta")?;
+    let end = start + find(&bytes[start..], b"</x:xmpmeta>")? + b"</x:xmpmeta>".len();
+    Some(String::from_utf8_lossy(&bytes[start..end]).into_owned())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn parse_xmp(packet: &str) -> EmbeddedText {
+    EmbeddedText {
+        caption: xmp_property(packet, "dc:description").unwrap_or_default(),
+        credit: first_non_empty([
+            xmp_property(packet, "photoshop:Credit").unwrap_or_default(),
+            xmp_property(packet, "dc:creator").unwrap_or_default(),
+        ]),
+    }
+}
+
+/// The text of an XMP property, written either as an attribute
+/// (`photoshop:Credit="..."`) or as an element, in which case the first
+/// `rdf:li` of an Alt/Seq/Bag container is used.
+fn xmp_property(packet: &str, name: &str) -> Option<String> {
+    let attribute = format!("{}=\"", name);
+    if let Some(start) = packet.find(&attribute).map(|i| i + attribute.len()) {
+        let value = &packet[start..];
+        return non_empty(&value[..value.find('"')?]);
+    }
+
+    let open = packet.find(&format!("<{}", name))?;
+    let close = open + packet[open..].find(&format!("</{}>", name))?;
+    let element = &packet[open..close];
+    // Skip the opening tag itself
+    let content = &element[element.find('>')? + 1..];
+    let content = match content.find("<rdf:li") {
+        Some(li) => {
+            let item = &content[li..];
+            let item = &item[item.find('>')? + 1..];
+            &item[..item.find("</rdf:li>")?]
+        }
+        None => content,
+    };
+    non_empty(content)
+}
+
+fn non_empty(text: &str) -> Option<String> {
+    let text = unescape_xml(text.trim());
+    (!text.is_empty()).then_some(text)
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&#xA;", "\n")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKET: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" photoshop:Credit="Agency &amp; Partners">
+   <dc:description>
+    <rdf:Alt>
+     <rdf:li xml:lang="x-default">Lake at dusk</rdf:li>
+    </rdf:Alt>
+   </dc:description>
+   <dc:creator>
+    <rdf:Seq>
+     <rdf:li>Jane Doe</rdf:li>
+    </rdf:Seq>
+   </dc:creator>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+
+    #[test]
+    fn xmp_caption_and_credit() {
+        assert_eq!(
+            parse_xmp(PACKET),
+            EmbeddedText { caption: "Lake at dusk".to_string(), credit: "Agency & Partners".to_string() }
+        );
+    }
+
+    #[test]
+    fn xmp_creator_is_the_fallback_credit() {
+        let packet = PACKET.replace(r#" photoshop:Credit="Agency &amp; Partners""#, "");
+        assert_eq!(parse_xmp(&packet).credit, "Jane Doe");
+    }
+
+    #[test]
+    fn packet_is_found_inside_an_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file-upload-xmp-test.jpg");
+        let mut bytes = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        bytes.extend_from_slice(b"http://ns.adobe.com/xap/1.0/\0");
+        bytes.extend_from_slice(PACKET.as_bytes());
+        bytes.extend_from_slice(&[0xFF, 0xD9]);
+        std::fs::write(&path, bytes).unwrap();
+
+        let text = read(&path);
+
+        assert_eq!(text.caption, "Lake at dusk");
+        assert_eq!(text.credit, "Agency & Partners");
+    }
+
+    #[test]
+    fn files_without_metadata_give_empty_text() {
+        assert_eq!(read(Path::new("does-not-exist.jpg")), EmbeddedText::default());
+    }
+}
@@ -0,0 +1,83 @@
+use aws_sdk_s3::error::{DisplayErrorContext, SdkError};
+use aws_sdk_s3::Error as S3Error;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum UploadError {
+    S3Error(Box<S3Error>),
+    /// A failed S3 request: network, credential or service errors.
+    RequestError(String),
+    IoError(std::io::Error),
+    ImageError(image::ImageError),
+    /// The file's contents don't match what its extension claims.
+    InvalidFile(String),
+    Other(String),
+}
+
+impl UploadError {
+    /// Short category name for the error, surfaced to Node so callers can
+    /// tell an S3 failure from a bad image without parsing the message.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            UploadError::S3Error(_) | UploadError::RequestError(_) => "s3",
+            UploadError::IoError(_) => "io",
+            UploadError::ImageError(_) => "image",
+            UploadError::InvalidFile(_) => "invalid",
+            UploadError::Other(_) => "other",
+        }
+    }
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::S3Error(err) => write!(f, "S3 Error: {}", err),
+            UploadError::RequestError(err) => write!(f, "S3 Request Error: {}", err),
+            UploadError::IoError(err) => write!(f, "IO Error: {}", err),
+            UploadError::ImageError(err) => write!(f, "Image Error: {}", err),
+            UploadError::InvalidFile(err) => write!(f, "Invalid File: {}", err),
+            UploadError::Other(err) => write!(f, "Error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+impl From<S3Error> for UploadError {
+    fn from(err: S3Error) -> Self {
+        UploadError::S3Error(Box::new(err))
+    }
+}
+
+impl From<std::io::Error> for UploadError {
+    fn from(err: std::io::Error) -> Self {
+        UploadError::IoError(err)
+    }
+}
+
+impl From<image::ImageError> for UploadError {
+    fn from(err: image::ImageError) -> Self {
+        UploadError::ImageError(err)
+    }
+}
+
+impl From<String> for UploadError {
+    fn from(err: String) -> Self {
+        UploadError::Other(err)
+    }
+}
+
+impl From<&str> for UploadError {
+    fn from(err: &str) -> Self {
+        UploadError::Other(err.to_string())
+    }
+}
+
+impl<E> From<SdkError<E>> for UploadError
+where
+    E: std::error::Error + 'static,
+{
+    fn from(err: SdkError<E>) -> Self {
+        UploadError::RequestError(DisplayErrorContext(&err).to_string())
+    }
+}
@@ -0,0 +1,106 @@
+use image::DynamicImage;
+use log::warn;
+use once_cell::sync::Lazy;
+use std::fs;
+use std::path::Path;
+
+use crate::config::env_flag;
+use crate::error::UploadError;
+
+/// Whether re-encoded JPEG originals are written progressive, from
+/// `PROGRESSIVE_JPEG`. The image crate only writes baseline JPEGs, so without
+/// the `progressive-jpeg` feature the flag is ignored with a warning.
+pub fn progressive_enabled() -> bool {
+    static ENABLED: Lazy<bool> = Lazy::new(|| {
+        let requested = env_flag("PROGRESSIVE_JPEG");
+        if requested && !cfg!(feature = "progressive-jpeg") {
+            warn!("PROGRESSIVE_JPEG needs a build with the progressive-jpeg feature; writing baseline JPEGs");
+        }
+        requested && cfg!(feature = "progressive-jpeg")
+    });
+    *ENABLED
+}
+
+/// Whether the file is a JPEG whose frame is sequential (baseline or
+/// extended) rather than progressive, read from its start-of-frame marker.
+pub fn is_sequential(path: &Path) -> bool {
+    let Ok(bytes) = fs::read(path) else {
+        return false;
+    };
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return false;
+    }
+
+    let mut i = 2;
+    while i + 4 <= bytes.len() {
+        if bytes[i] != 0xFF {
+            return false;
+        }
+        match bytes[i + 1] {
+            0xC0 | 0xC1 => return true,
+            0xC2 => return false,
+            // Fill bytes may pad out a marker
+            0xFF => {
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+        let length = u16::from_be_bytes([bytes[i + 2], bytes[i + 3]]) as usize;
+        i += 2 + length;
+    }
+    false
+}
+
+/// Encodes `img` as a progressive JPEG at `quality` (1-100) with mozjpeg, so
+/// browsers can paint a coarse version while the rest loads.
+#[cfg(feature = "progressive-jpeg")]
+pub fn encode_progressive(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, UploadError> {
+    let to_error = |e: std::io::Error| UploadError::Other(format!("Progressive JPEG encode failed: {}", e));
+
+    let rgb = img.to_rgb8();
+    let mut compress = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    compress.set_size(rgb.width() as usize, rgb.height() as usize);
+    compress.set_quality(f32::from(quality.clamp(1, 100)));
+    compress.set_progressive_mode();
+
+    let mut started = compress.start_compress(Vec::new()).map_err(to_error)?;
+    started.write_scanlines(rgb.as_raw()).map_err(to_error)?;
+    started.finish().map_err(to_error)
+}
+
+#[cfg(not(feature = "progressive-jpeg"))]
+pub fn encode_progressive(_img: &DynamicImage, _quality: u8) -> Result<Vec<u8>, UploadError> {
+    Err(UploadError::Other("Progressive JPEGs need a build with the progressive-jpeg feature".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::ImageFormat;
+
+    #[test]
+    fn baseline_jpegs_are_sequential() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file-upload-sequential-test.jpg");
+        let jpeg = crate::encode_image(&DynamicImage::new_rgb8(16, 16), ImageFormat::Jpeg, 90).unwrap();
+        fs::write(&path, jpeg).unwrap();
+        let sequential = is_sequential(&path);
+        fs::write(&path, b"not a jpeg").unwrap();
+        let not_jpeg = is_sequential(&path);
+
+        assert!(sequential);
+        assert!(!not_jpeg);
+    }
+
+    #[cfg(feature = "progressive-jpeg")]
+    #[test]
+    fn progressive_encodes_are_not_sequential() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file-upload-progressive-test.jpg");
+        fs::write(&path, encode_progressive(&DynamicImage::new_rgb8(16, 16), 90).unwrap()).unwrap();
+        let sequential = is_sequential(&path);
+
+        assert!(!sequential);
+    }
+}
@@ -1,74 +1,65 @@
 use aws_config::meta::region::RegionProviderChain;
+use aws_config::timeout::TimeoutConfig;
+use aws_credential_types::provider::ProvideCredentials;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use md5::Md5;
 use aws_sdk_s3::config::Credentials;
 use aws_sdk_s3::config::Region;
-use aws_sdk_s3::primitives::ByteStream;
-use aws_sdk_s3::types::ObjectCannedAcl;
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::types::{ObjectCannedAcl, StorageClass};
 use aws_sdk_s3::Client;
 use dotenv::dotenv;
-use image::imageops::FilterType;
-use image::ImageFormat;
-use lazy_static::lazy_static;
+use image::codecs::gif::GifDecoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::{AnimationDecoder, DynamicImage, ImageFormat};
 use mime_guess::from_path as mime_from_path;
 use neon::prelude::*;
-use once_cell::sync::OnceCell;
-use std::collections::HashMap;
+use once_cell::sync::{Lazy, OnceCell};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::error::Error;
 use std::ffi::OsStr;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{BufReader, Cursor, Read};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use tokio::runtime::Runtime;
-use colored::*;
-use chrono::Local;
-use urlencoding::encode;
-mod mount_s3;
-use mount_s3::S3Mount;
-
-pub const REGION: &str = "us-east-1";
-pub const BUCKET_NAME: &str = "digitalgov";
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+pub mod config;
+mod crop;
+mod embedded;
+pub mod error;
+mod jpeg;
+pub mod mount_s3;
+mod multipart;
+mod pdf;
+mod retry;
+pub mod store;
+pub mod upload_s3;
+mod variants;
+mod webp;
+use config::{CollisionPolicy, ExistingPolicy, UidStrategy, UploadOptions, Watermark, WatermarkPosition};
+use error::UploadError;
+use mount_s3::{DownloadSummary, MountError, S3Mount};
+use store::{ObjectStore, PutOptions, Stores};
+use upload_s3::S3Upload;
+use variants::{CropRegion, PlannedVariant, ResizeMode, SquareThumbnail};
+
 const INBOX_DIR: &str = "content/uploads/_inbox";
+/// Alt/caption/credit for a batch of inbox images, one row per file name.
+const BATCH_METADATA_FILE: &str = "metadata.csv";
 const WORKING_IMAGES_DIR: &str = "content/uploads/_working-images/to-process";
 const WORKING_FILES_DIR: &str = "content/uploads/_working-files/to-process";
-const IMAGE_S3_PREFIX: &str = "";
-const STATIC_S3_PREFIX: &str = "static/";
 const LOCAL_IMAGE_DIR: &str = "./assets/s3-images";
-
-// Image variant settings
-lazy_static! {
-    static ref VARIANT_SETTINGS: HashMap<&'static str, VariantSetting> = {
-        let mut m = HashMap::new();
-        m.insert(
-            "mobile",
-            VariantSetting {
-                width: 200,
-            },
-        );
-        m.insert(
-            "tablet",
-            VariantSetting {
-                width: 400,
-            },
-        );
-        m.insert(
-            "desktop_md",
-            VariantSetting {
-                width: 800,
-            },
-        );
-        m.insert(
-            "desktop_lg",
-            VariantSetting {
-                width: 1200
-            },
-        );
-        m
-    };
-}
-
-/// Settings for an image variant.
-struct VariantSetting {
-    width: u32,
-}
+const IMAGE_DATA_DIR: &str = "data/images";
+const FILE_DATA_DIR: &str = "data/files";
+const ORIGINAL_QUALITY: u8 = 90;
+const MANIFEST_FILE: &str = "upload-manifest.json";
+/// Hex digits of the content hash put in variant keys by `HASHED_FILENAMES`.
+pub(crate) const FILENAME_HASH_LEN: usize = 8;
 
 // Return a global tokio runtime or create one if it doesn't exist.
 fn runtime() -> &'static Runtime {
@@ -76,11 +67,21 @@ fn runtime() -> &'static Runtime {
     RUNTIME.get_or_init(|| Runtime::new().expect("Failed to create Tokio runtime"))
 }
 
-fn is_image(file_path: &Path) -> bool {
+/// Sets up logging to stderr, filtered by `RUST_LOG` (default `info`). Safe
+/// to call more than once; only the first call installs the logger.
+pub fn init_logging() {
+    let env = env_logger::Env::default().default_filter_or("info");
+    let _ = env_logger::Builder::from_env(env).try_init();
+}
+
+/// Whether the file is a raster image the pipeline resizes into variants.
+/// Every module routes files through this, so they agree on what's an image.
+/// AVIF isn't listed: the `image` build here can encode it but not decode it.
+pub(crate) fn is_image(file_path: &Path) -> bool {
     file_path
         .extension()
         .and_then(|ext| ext.to_str())
-        .map_or(false, |ext| {
+        .is_some_and(|ext| {
             matches!(
                 ext.to_lowercase().as_str(),
                 "png" | "jpg" | "jpeg" | "gif" | "bmp" | "tiff" | "webp"
@@ -88,446 +89,3965 @@ fn is_image(file_path: &Path) -> bool {
         })
 }
 
+/// Whether the extension is one this deployment accepts, per
+/// `ALLOWED_EXTENSIONS` and `BLOCKED_EXTENSIONS`.
 fn is_valid_file_type(path: &Path) -> bool {
-    let extension = path.extension().and_then(OsStr::to_str).unwrap_or("").to_lowercase();
-    matches!(
-        extension.as_str(),
-        // Images
-        "jpg" |
-            "jpeg" |
-            "png" |
-            "gif" |
-            "bmp" |
-            "tiff" |
-            "webp" |
-            // Documents
-            "doc" |
-            "docx" |
-            "pdf" |
-            "txt" |
-            "rtf" |
-            "xls" |
-            "xlsx" |
-            "csv" |
-            "ppt" |
-            "pptx" |
-            // Other common formats
-            "zip" |
-            "rar" |
-            "7z"
-    )
+    let extension = path.extension().and_then(OsStr::to_str).unwrap_or("");
+    config::extension_policy().allows(extension)
 }
 
-fn sanitize_filename(filename: &str) -> String {
-    filename
-        .to_lowercase()
-        .replace(' ', "-")
-        .chars()
-        .filter(|c| c.is_alphanumeric() || *c == '-' || *c == '_' || *c == '.')
-        .collect()
+/// Whether the path is a placeholder kept in the upload folders, such as
+/// `.gitkeep` or `__add image or static files to this folder__`, rather
+/// than something to upload: any dotfile, or a name that starts or ends
+/// with a double underscore.
+fn is_placeholder(path: &Path) -> bool {
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .is_some_and(|name| name.starts_with('.') || name.starts_with("__") || name.ends_with("__"))
 }
 
-/// Moves files from inbox to appropriate working directories
-fn prepare_working_directories() -> Result<(), Box<dyn Error + Send + Sync>> {
-    let inbox = Path::new(INBOX_DIR);
-    if !inbox.exists() {
-        println!("Inbox directory not found at {:?}", inbox);
-        return Ok(());
-    }
-
-    // Create working directories if they don't exist
-    fs::create_dir_all(WORKING_IMAGES_DIR)?;
-    fs::create_dir_all(WORKING_FILES_DIR)?;
-
-    let files: Vec<_> = fs::read_dir(inbox)?
-        .filter_map(|entry| entry.ok())
-        .filter(|entry| {
-            let path = entry.path();
-            path.is_file() && is_valid_file_type(&path) && 
-            path.file_name().map_or(false, |name| name != "__add image or static files to this folder__")
+/// Suffixes of files that carry extra data for the upload they sit next to,
+/// e.g. `photo.jpg.alt.txt`. They travel with that file and are never
+/// uploaded themselves.
+const SIDECAR_SUFFIXES: &[&str] = &[".alt.txt", ".yml"];
+
+/// Whether `path` is the sidecar of a file next to it. A `notes.yml` with no
+/// `notes` beside it is just a file, handled like any other.
+fn is_sidecar(path: &Path) -> bool {
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .is_some_and(|name| {
+            SIDECAR_SUFFIXES.iter().any(|suffix| {
+                name.strip_suffix(suffix)
+                    .is_some_and(|owner| !owner.is_empty() && path.with_file_name(owner).is_file())
+            })
         })
-        .collect();
+}
 
-    for entry in files {
-        let path = entry.path();
-        let target_dir = if is_image(&path) {
-            WORKING_IMAGES_DIR
-        } else {
-            WORKING_FILES_DIR
-        };
+/// The file name of `path` as text. Bytes that aren't UTF-8, which some
+/// Linux filesystems allow, are replaced so the name can still be sanitized;
+/// only a path with no file name at all, such as `..`, is an error.
+pub(crate) fn file_name_lossy(path: &Path) -> Result<Cow<'_, str>, UploadError> {
+    path.file_name()
+        .map(OsStr::to_string_lossy)
+        .ok_or_else(|| UploadError::InvalidFile(format!("{:?} has no file name", path)))
+}
 
-        let file_name = path.file_name().unwrap();
-        let sanitized_name = sanitize_filename(file_name.to_str().unwrap());
-        let target_path = Path::new(target_dir).join(&sanitized_name);
-        
-        // Move file to appropriate working directory
-        fs::rename(&path, &target_path)?;
-        println!("Moved {:?} to {:?}", path, target_path);
-    }
+/// The file stem of `path` as text, like [`file_name_lossy`].
+pub(crate) fn file_stem_lossy(path: &Path) -> Result<Cow<'_, str>, UploadError> {
+    path.file_stem()
+        .map(OsStr::to_string_lossy)
+        .ok_or_else(|| UploadError::InvalidFile(format!("{:?} has no file name", path)))
+}
 
-    Ok(())
+/// The extension of `path` as text, like [`file_name_lossy`].
+pub(crate) fn extension_lossy(path: &Path) -> Result<Cow<'_, str>, UploadError> {
+    path.extension()
+        .map(OsStr::to_string_lossy)
+        .ok_or_else(|| UploadError::InvalidFile(format!("{:?} has no file extension", path)))
 }
 
-/// Converts a JPG image to PNG format
-async fn convert_jpg_to_png(image_path: &Path) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
-    println!("Converting image {:?} to PNG", image_path);
-    let img = image::open(image_path)?;
-    let output_path = image_path.with_extension("png");
-    img.save_with_format(&output_path, ImageFormat::Png)?;
-    
-    if image_path.exists() {
-        fs::remove_file(image_path)?;
-    }
-    
-    Ok(output_path)
+/// The sidecar for `path` with the given suffix: the full file name plus the suffix.
+fn sidecar_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
 }
 
-/// Resizes an image while maintaining its aspect ratio.
-pub fn resize_image(
-    image_path: &Path,
-    output_path: &Path,
-    width: u32
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let img = image::open(image_path)?;
-    let aspect_ratio = (img.height() as f32) / (img.width() as f32);
-    let height = ((width as f32) * aspect_ratio).round() as u32;
-    let resized_img = img.resize_exact(width, height, FilterType::CatmullRom);
-    resized_img.save(output_path)?;
-    Ok(())
+/// Splits an `alt=` token off a file name, so `sunset alt=Sun over the lake.jpg`
+/// becomes `sunset.jpg` with the alt text "Sun over the lake".
+fn split_alt_token(file_name: &str) -> (String, Option<String>) {
+    let (stem, extension) = match file_name.rsplit_once('.') {
+        Some((stem, extension)) => (stem, Some(extension)),
+        None => (file_name, None),
+    };
+    let Some((name, alt)) = stem.split_once("alt=") else {
+        return (file_name.to_string(), None);
+    };
+
+    let name = name.trim_end_matches([' ', '-', '_']);
+    let alt = alt.trim();
+    let file_name = match extension {
+        Some(extension) => format!("{}.{}", name, extension),
+        None => name.to_string(),
+    };
+    (file_name, (!alt.is_empty()).then(|| alt.to_string()))
 }
 
-/// Generates and writes YML metadata for an image
-fn write_image_metadata(uid: &str, width: u32, height: u32, format: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
-    println!("Generating metadata for image - dimensions: {}x{}", width, height);
-    let metadata = format!(
-        r#"
-# https://s3.amazonaws.com/digitalgov/{uid}.{format}
-# Image shortcode: {{{{ img src="{uid}" }}}}
-date     :  {}
-uid      :  {}
-width    :  {}
-height   :  {}
-format   :  {}
+/// File name prefix choosing a file's uid, as in `uid__agency-seal-2024.png`.
+const UID_TOKEN: &str = "uid__";
 
-# REQUIRED alternative text for accessibility.
-# Keep within 150 characters. https://capitalizemytitle.com/character-counter/ will count characters.
-alt      :  ""
+/// Longest uid accepted from a file name or sidecar.
+const MAX_UID_LEN: usize = 100;
 
-# Caption text appears below the image; usually the attribution for stock images.
-# Must be different from the alt text.
-caption  :  ""
+/// The uid chosen by a `uid__` file name prefix, taken verbatim so it can be
+/// validated rather than quietly slugified.
+fn uid_token(file_name: &str) -> Option<&str> {
+    let stem = file_name.rsplit_once('.').map_or(file_name, |(stem, _)| stem);
+    stem.strip_prefix(UID_TOKEN)
+}
 
-# Credit text appears after the caption text, separated by an m-dash.
-# Example https://digital.gov/2023/12/08/making-gsa-public-art-collection-more-accessible/ 
-credit   :  ""
-"#,
-        Local::now().format("%Y-%m-%d %H:%M:%S -0400"),
-        uid,
-        width,
-        height,
-        format
-    );
+/// Whether a chosen uid can be used as-is in keys, metadata file names and
+/// shortcodes: lowercase ASCII letters, digits, `-` and `_`, starting and
+/// ending with a letter or digit.
+fn is_valid_uid(uid: &str) -> bool {
+    let edges_ok = |c: Option<char>| c.is_some_and(|c| c.is_ascii_lowercase() || c.is_ascii_digit());
+    uid.len() <= MAX_UID_LEN
+        && edges_ok(uid.chars().next())
+        && edges_ok(uid.chars().last())
+        && uid.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
 
-    fs::create_dir_all("data/images")?;
-    fs::write(format!("data/images/{}.yml", uid), metadata)?;
-    Ok(())
+/// A `uid` field in a file's `.yml` sidecar.
+#[derive(Debug, Default, Deserialize)]
+struct SidecarUid {
+    uid: Option<String>,
 }
 
-/// Generates and writes YML metadata for a file
-fn write_file_metadata(uid: &str, format: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let metadata = format!(
-        r#"
-# https://s3.amazonaws.com/digitalgov/static/{uid}.{format}
-# File shortcode: {{{{ asset-static file="{uid}.{format}" label="{uid} ({format})" }}}}
-date     :  {}
-uid      :  {}
-format   :  {}
-"#,
-        Local::now().format("%Y-%m-%d %H:%M:%S -0400"),
-        uid,
-        format
-    );
+/// The uid an editor chose for a file, from a `uid__` file name prefix or
+/// else a `uid` in its `.yml` sidecar. A chosen uid that isn't slug-safe
+/// fails the file rather than being rewritten behind the editor's back.
+fn explicit_uid(file_path: &Path, file_name: &str) -> Result<Option<String>, UploadError> {
+    let from_sidecar = || {
+        let content = fs::read_to_string(sidecar_path(file_path, ".yml")).ok()?;
+        serde_yaml::from_str::<SidecarUid>(&content).ok()?.uid
+    };
+    let Some(uid) = uid_token(file_name).map(String::from).or_else(from_sidecar) else {
+        return Ok(None);
+    };
 
-    fs::create_dir_all("data/files")?;
-    fs::write(format!("data/files/{}.yml", uid), metadata)?;
-    Ok(())
+    let uid = uid.trim().to_string();
+    if !is_valid_uid(&uid) {
+        return Err(
+            UploadError::InvalidFile(
+                format!(
+                    "{:?} asks for uid {:?}, which must be lowercase letters, digits, '-' or '_' (at most {} characters)",
+                    file_path,
+                    uid,
+                    MAX_UID_LEN
+                )
+            )
+        );
+    }
+    Ok(Some(uid))
 }
 
-/// Uploads a file to an Amazon S3 bucket.
-pub async fn upload_to_s3(
-    file_path: &Path,
-    key: &str,
-    content_type: Option<&str>
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    println!("Attempting to upload file: {:?}", file_path);
+/// Editorial text for an image's metadata, pre-filled from sidecars.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+struct ImageText {
+    #[serde(default)]
+    alt: String,
+    #[serde(default)]
+    caption: String,
+    #[serde(default)]
+    credit: String,
+}
 
-    // Load .env file
-    dotenv().ok();
+/// Collects alt/caption/credit for an image from its `{filename}.yml` sidecar,
+/// with a `.alt.txt` sidecar or an alt= token supplying the alt text when
+/// the YAML doesn't, and the caption and credit embedded in the file filling
+/// in whatever's still blank. Anything not found is left empty.
+fn read_image_text(file_path: &Path, token_alt: Option<String>) -> ImageText {
+    let yml_sidecar = sidecar_path(file_path, ".yml");
+    let mut text = match fs::read_to_string(&yml_sidecar) {
+        Ok(content) => serde_yaml::from_str::<ImageText>(&content).unwrap_or_else(|e| {
+            warn!("Ignoring invalid sidecar {:?}: {}", yml_sidecar, e);
+            ImageText::default()
+        }),
+        Err(_) => ImageText::default(),
+    };
 
-    let region_provider = RegionProviderChain::first_try(Region::new(REGION.to_string()))
-        .or_default_provider()
-        .or_else(Region::new("us-east-1"));
+    if text.alt.trim().is_empty() {
+        let alt_sidecar = fs::read_to_string(sidecar_path(file_path, ".alt.txt")).ok();
+        text.alt = alt_sidecar.or(token_alt).unwrap_or_default();
+    }
+    text.alt = text.alt.trim().to_string();
 
-    println!("Loading AWS config...");
-    let mut config_loader = aws_config::from_env().region(region_provider);
+    if text.caption.trim().is_empty() || text.credit.trim().is_empty() {
+        let embedded = embedded::read(file_path);
+        if text.caption.trim().is_empty() {
+            text.caption = embedded.caption;
+        }
+        if text.credit.trim().is_empty() {
+            text.credit = embedded.credit;
+        }
+    }
+    text
+}
 
-    // Check for credentials in .env
-    if let (Ok(access_key), Ok(secret_key)) = (
-        env::var("AWS_ACCESS_KEY_ID"),
-        env::var("AWS_SECRET_ACCESS_KEY"),
-    ) {
-        println!("Using credentials from .env file");
-        let creds = Credentials::new(access_key, secret_key, None, None, "dotenv");
-        config_loader = config_loader.credentials_provider(creds);
-    } else {
-        println!("No credentials in .env, falling back to default credential provider chain");
+/// A row of the inbox's `metadata.csv`.
+#[derive(Debug, Deserialize)]
+struct BatchRow {
+    filename: String,
+    #[serde(default)]
+    alt: String,
+    #[serde(default)]
+    caption: String,
+    #[serde(default)]
+    credit: String,
+}
+
+/// Reads a `filename,alt,caption,credit` CSV, as stock suppliers send with
+/// a batch, keyed by lowercased file name. Rows that can't be read are
+/// skipped with a warning.
+fn read_batch_metadata(path: &Path) -> Result<HashMap<String, ImageText>, UploadError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_path(path)
+        .map_err(|e| UploadError::Other(format!("Failed to read {:?}: {}", path, e)))?;
+
+    let mut rows = HashMap::new();
+    for row in reader.deserialize::<BatchRow>() {
+        match row {
+            Ok(row) if !row.filename.is_empty() => {
+                let text = ImageText { alt: row.alt, caption: row.caption, credit: row.credit };
+                rows.insert(row.filename.to_lowercase(), text);
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Skipping row of {:?}: {}", path, e),
+        }
     }
+    Ok(rows)
+}
 
-    let config = config_loader.load().await;
+/// Writes `text` into the `.yml` sidecar of `path`, keeping anything the
+/// sidecar already says, so a hand-written sidecar wins over the batch CSV.
+/// A sidecar that doesn't parse is an error and is left untouched.
+fn apply_batch_metadata(path: &Path, text: &ImageText) -> Result<(), UploadError> {
+    let sidecar = sidecar_path(path, ".yml");
+    let mut fields = match fs::read_to_string(&sidecar) {
+        Ok(content) => serde_yaml::from_str::<Option<serde_yaml::Mapping>>(&content)
+            .map_err(|e| UploadError::InvalidFile(format!("Invalid sidecar {:?}: {}", sidecar, e)))?
+            .unwrap_or_default(),
+        Err(_) => serde_yaml::Mapping::new(),
+    };
 
-    println!("Creating S3 client...");
-    let client = Client::new(&config);
-
-    let file = fs::read(file_path)?;
-    let stream = ByteStream::from(file);
-
-    let request = client
-        .put_object()
-        .bucket(BUCKET_NAME)
-        .key(key)
-        .body(stream)
-        .content_type(content_type.unwrap_or("application/octet-stream"))
-        .acl(ObjectCannedAcl::PublicRead);
-
-    println!("Uploading file: {:?} to S3 key: {}", file_path, key);
-    request.send().await?;
-    println!(
-        "Upload completed. File should be accessible at: https://s3.amazonaws.com/{}/{}",
-        BUCKET_NAME,
-        key
-    );
+    for (field, value) in [("alt", &text.alt), ("caption", &text.caption), ("credit", &text.credit)] {
+        let filled = fields.get(field).and_then(serde_yaml::Value::as_str).is_some_and(|current| !current.trim().is_empty());
+        if !filled && !value.is_empty() {
+            fields.insert(field.into(), value.as_str().into());
+        }
+    }
 
+    let yaml = serde_yaml::to_string(&fields).map_err(|e| UploadError::Other(e.to_string()))?;
+    fs::write(sidecar, yaml)?;
     Ok(())
 }
 
-pub async fn process_and_upload_file(file_path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let file_name = file_path
-        .file_name()
-        .and_then(|s| s.to_str())
-        .ok_or("Invalid file name")?;
-    
-    // Sanitize the filename
-    let sanitized_name = sanitize_filename(file_name);
-    let content_type = mime_from_path(file_path).first_raw();
+/// Whether a signature detected by `infer` is consistent with the extension.
+/// Office formats share the ZIP and OLE containers, so those are accepted
+/// for any extension in the same family.
+fn signature_matches(extension: &str, detected: &str) -> bool {
+    let extension = extension.to_lowercase();
+    extension == detected ||
+        matches!(
+            (extension.as_str(), detected),
+            ("jpeg", "jpg") |
+                ("tiff", "tif") |
+                ("svg", "xml") |
+                ("docx" | "xlsx" | "pptx", "zip" | "docx" | "xlsx" | "pptx") |
+                ("doc" | "xls" | "ppt", "doc" | "xls" | "ppt" | "msi")
+        )
+}
 
-    if is_image(file_path) {
-        // Debug: Print file size
-        let metadata = fs::metadata(file_path)?;
-        println!("Original file size: {} bytes", metadata.len());
+/// Content type to upload a file with: `config::content_type_overrides()`
+/// for its extension, falling back to `mime_guess`.
+fn content_type_for(file_path: &Path) -> Option<&'static str> {
+    let extension = file_path.extension().and_then(OsStr::to_str).unwrap_or("").to_lowercase();
+    match config::content_type_overrides().get(&extension) {
+        Some(mime) => Some(mime.as_str()),
+        None => mime_from_path(file_path).first_raw(),
+    }
+}
 
-        // Convert JPG to PNG if needed
-        let file_path = if file_path.extension().and_then(|e| e.to_str()) == Some("jpg") 
-            || file_path.extension().and_then(|e| e.to_str()) == Some("jpeg") {
-            convert_jpg_to_png(file_path).await?
-        } else {
-            file_path.to_path_buf()
-        };
+/// The extension an image's bytes call for when it differs from the one it
+/// has, e.g. `png` for a PNG saved as `photo.jpg`. Only formats the pipeline
+/// resizes count; anything else is left for `check_file_signature` to refuse.
+fn mislabelled_image_extension(file_path: &Path) -> Option<&'static str> {
+    if !is_image(file_path) {
+        return None;
+    }
+    let mut header = Vec::new();
+    File::open(file_path).ok()?.take(64).read_to_end(&mut header).ok()?;
+    let extensions = image::guess_format(&header).ok()?.extensions_str();
 
-        let file_stem = Path::new(&sanitized_name)
-            .file_stem()
-            .and_then(|s| s.to_str())
-            .ok_or("Invalid file name")?;
-        let extension = file_path
-            .extension()
-            .and_then(|s| s.to_str())
-            .ok_or("Invalid file extension")?;
+    let extension = file_path.extension().and_then(OsStr::to_str)?.to_lowercase();
+    if extensions.contains(&extension.as_str()) {
+        return None;
+    }
+    extensions.iter().copied().find(|actual| is_image(Path::new(&format!("image.{}", actual))))
+}
 
-        // Read and validate image dimensions
-        let img = image::open(&file_path)?;
-        let (width, height) = (img.width(), img.height());
-        println!("Original image dimensions: {}x{}", width, height);
-        
-        if width < 100 || height < 100 {
-            println!("Warning: Image dimensions seem unusually small. This might indicate an issue with the image file.");
-            // Optional: Return an error if dimensions are too small
-            // return Err("Image dimensions are too small".into());
-        }
+/// Copies a mislabelled image next to itself under its real extension,
+/// removed when the returned guard drops. The source keeps its name.
+fn relabel_image(image_path: &Path, extension: &str) -> Result<TempFile, UploadError> {
+    let stem = image_path.file_stem().and_then(OsStr::to_str).unwrap_or("image");
+    // A dotfile, so an interrupted run's leftovers are ignored as placeholders
+    let relabelled = TempFile::new(image_path.with_file_name(format!(".{}.relabelled.{}", stem, extension)));
+    fs::copy(image_path, relabelled.path())?;
+    Ok(relabelled)
+}
 
-        // Upload the original file first
-        let original_s3_key = format!("{}{}.{}", IMAGE_S3_PREFIX, file_stem, extension);
-        upload_to_s3(&file_path, &original_s3_key, content_type).await?;
-        println!("Uploaded original file to S3: {}", original_s3_key);
-
-        // Generate metadata
-        println!("Generating metadata for image - dimensions: {}x{}", width, height);
-        write_image_metadata(file_stem, width, height, extension)?;
-
-        // Then process and upload resized versions
-        for (variant_name, variant) in VARIANT_SETTINGS.iter() {
-            let output_filename = format!("{}_w{}.{}", file_stem, variant.width, extension);
-            let output_path = Path::new(WORKING_IMAGES_DIR).join(&output_filename);
-
-            resize_image(&file_path, &output_path, variant.width)?;
-
-            // Verify resized dimensions
-            if let Ok(resized_img) = image::open(&output_path) {
-                println!(
-                    "Resized image dimensions for {} variant: {}x{}", 
-                    variant_name,
-                    resized_img.width(),
-                    resized_img.height()
-                );
-            }
+/// Reads the leading bytes of the file and rejects it when its signature does
+/// not match the extension. Images must carry a recognisable signature; plain
+/// text formats such as csv and txt have none and are let through. An image
+/// saved under another image format's extension passes, to be relabelled.
+fn check_file_signature(file_path: &Path) -> Result<(), UploadError> {
+    let extension = file_path.extension().and_then(OsStr::to_str).unwrap_or("");
+    match infer::get_from_path(file_path)? {
+        Some(kind) if signature_matches(extension, kind.extension()) => Ok(()),
+        Some(_) if mislabelled_image_extension(file_path).is_some() => Ok(()),
+        Some(kind) =>
+            Err(
+                UploadError::InvalidFile(
+                    format!(
+                        "{:?} has a .{} extension but its contents are {}",
+                        file_path,
+                        extension,
+                        kind.mime_type()
+                    )
+                )
+            ),
+        None if is_image(file_path) =>
+            Err(
+                UploadError::InvalidFile(
+                    format!("{:?} has a .{} extension but is not a recognised image", file_path, extension)
+                )
+            ),
+        None => Ok(()),
+    }
+}
 
-            let s3_key = format!("{}{}", IMAGE_S3_PREFIX, output_filename);
-            upload_to_s3(&output_path, &s3_key, content_type).await?;
-            println!("Uploaded resized file to S3: {}", s3_key);
+/// CJK ideographs, kana and hangul, which have no reliable transliteration.
+fn is_cjk(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x3040..=0x30FF | 0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xAC00..=0xD7AF | 0xF900..=0xFAFF | 0x20000..=0x2FFFF
+    )
+}
 
-            fs::remove_file(output_path)?;
-        }
-    } else {
-        // For non-image files, upload directly to the STATIC_S3_PREFIX
-        let s3_key = format!("{}{}", STATIC_S3_PREFIX, sanitized_name);
-        println!("Uploading non-image file to S3: {}", s3_key);
-        upload_to_s3(file_path, &s3_key, content_type).await?;
+/// Transliterates text to ASCII and reduces it to lowercase `[a-z0-9._-]`,
+/// with whitespace turned into dashes and runs of dashes collapsed.
+fn slugify(text: &str) -> String {
+    let without_cjk: String = text.chars().filter(|c| !is_cjk(*c)).collect();
+    let mut slug = String::new();
 
-        // Generate metadata for the file
-        let file_stem = Path::new(&sanitized_name)
-            .file_stem()
-            .and_then(|s| s.to_str())
-            .ok_or("Invalid file name")?;
-        let extension = file_path
-            .extension()
-            .and_then(|s| s.to_str())
-            .ok_or("Invalid file extension")?;
-        write_file_metadata(file_stem, extension)?;
+    for c in deunicode::deunicode(&without_cjk).to_lowercase().chars() {
+        let c = if c.is_whitespace() { '-' } else { c };
+        if !(c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+            continue;
+        }
+        if c == '-' && slug.ends_with('-') {
+            continue;
+        }
+        slug.push(c);
     }
 
-    Ok(())
+    slug.trim_matches('-').to_string()
 }
 
-async fn process_and_upload_all() -> Result<String, Box<dyn Error + Send + Sync>> {
-    println!("Starting file upload process...");
+/// Turns a filename into a URL-safe name, e.g. "Café Señor.png" becomes
+/// "cafe-senor.png". Names with nothing left to keep, such as all-CJK or
+/// all-symbol names, fall back to a short hash of the original so distinct
+/// files stay distinct and the key never starts with a bare extension.
+fn sanitize_filename(filename: &str) -> String {
+    let (stem, extension) = match filename.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(slugify(extension))),
+        _ => (filename, None),
+    };
+
+    let mut slug = slugify(stem);
+    if !slug.chars().any(|c| c.is_ascii_alphanumeric()) {
+        slug = format!("{:x}", Sha256::digest(stem.as_bytes()))[..12].to_string();
+    }
 
-    // First, move files from inbox to working directories
-    prepare_working_directories()?;
+    match extension {
+        Some(extension) if !extension.is_empty() => format!("{}.{}", slug, extension),
+        _ => slug,
+    }
+}
 
-    let image_dir = Path::new(WORKING_IMAGES_DIR);
-    let file_dir = Path::new(WORKING_FILES_DIR);
+/// The name a file is moved into a working directory under: sanitized,
+/// except that a `uid__` prefix keeps its uid verbatim for validation.
+fn working_name(file_name: &str) -> String {
+    let Some(uid) = uid_token(file_name) else {
+        return sanitize_filename(file_name);
+    };
+    match sanitize_filename(file_name).rsplit_once('.') {
+        Some((_, extension)) => format!("{}{}.{}", UID_TOKEN, uid, extension),
+        None => format!("{}{}", UID_TOKEN, uid),
+    }
+}
 
-    let mut total_count = 0;
-    let mut processed_count = 0;
+/// Files the pipeline would pick up from `dir` and, up to `depth` levels
+/// down, its subfolders. Deeper files are left where they are with a warning.
+fn inbox_files(dir: &Path, depth: usize) -> Result<Vec<PathBuf>, UploadError> {
+    let mut files = uploadable_files(dir)?;
 
-    for dir in &[image_dir, file_dir] {
-        if !dir.exists() {
-            println!("Working directory not found at {:?}", dir);
+    let mut subfolders: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir() && !is_placeholder(path))
+        .collect();
+    subfolders.sort();
+    for subfolder in subfolders {
+        if depth == 0 {
+            warn!("Skipping {:?}: nested deeper than INBOX_MAX_DEPTH", subfolder);
             continue;
         }
+        files.extend(inbox_files(&subfolder, depth - 1)?);
+    }
+    Ok(files)
+}
 
-        let files: Vec<_> = fs::read_dir(dir)?
-            .filter_map(|entry| entry.ok())
-            .filter(|entry| {
-                let path = entry.path();
-                path.is_file() && is_valid_file_type(&path)
-            })
-            .collect();
+/// `path`, or the first `{stem}-{n}.{ext}` next to it that isn't taken, so a
+/// move never replaces a file already waiting in the working directory.
+fn unused_path(path: PathBuf) -> PathBuf {
+    if !path.exists() {
+        return path;
+    }
+    let name = path.file_name().map(OsStr::to_string_lossy).unwrap_or_default().into_owned();
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) => (stem, format!(".{}", extension)),
+        None => (name.as_str(), String::new()),
+    };
+    (2..)
+        .map(|n| path.with_file_name(format!("{}-{}{}", stem, n, extension)))
+        .find(|candidate| !candidate.exists())
+        .expect("some numbered name is free")
+}
+
+/// Where an inbox file is moved to in `working_dir`, under `sanitized_name`.
+/// With `preserve_paths` its sanitized subfolders come along; otherwise it
+/// lands at the top level, prefixed with those subfolders only if another
+/// file already took the name. A name that's still taken gets a `-{n}`.
+fn working_path(working_dir: &Path, inbox: &Path, path: &Path, sanitized_name: &str, preserve_paths: bool) -> PathBuf {
+    let subfolders: Vec<String> = path
+        .parent()
+        .and_then(|parent| parent.strip_prefix(inbox).ok())
+        .map(|relative| {
+            relative
+                .components()
+                .map(|component| sanitize_filename(&component.as_os_str().to_string_lossy()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    if preserve_paths {
+        return unused_path(subfolders.iter().fold(working_dir.to_path_buf(), |dir, folder| dir.join(folder)).join(sanitized_name));
+    }
+    let flat = working_dir.join(sanitized_name);
+    if subfolders.is_empty() || !flat.exists() {
+        return unused_path(flat);
+    }
+    unused_path(working_dir.join(format!("{}-{}", subfolders.join("-"), sanitized_name)))
+}
+
+/// Moves files from the inbox, and its subfolders up to `INBOX_MAX_DEPTH`
+/// deep, to the appropriate working directories. Returns `false` when
+/// there's no inbox directory at all.
+fn prepare_working_directories(options: &UploadOptions) -> Result<bool, UploadError> {
+    let inbox = Path::new(INBOX_DIR);
+    if !inbox.exists() {
+        warn!("Inbox directory not found at {:?}", inbox);
+        return Ok(false);
+    }
 
-        total_count += files.len();
+    // Create working directories if they don't exist
+    fs::create_dir_all(WORKING_IMAGES_DIR)?;
+    fs::create_dir_all(WORKING_FILES_DIR)?;
 
-        println!("Found {} valid files in {:?}.", files.len(), dir);
+    // The batch CSV describes the images rather than being uploaded itself
+    let batch_csv = inbox.join(BATCH_METADATA_FILE);
+    let mut batch_metadata = if batch_csv.is_file() {
+        read_batch_metadata(&batch_csv)?
+    } else {
+        HashMap::new()
+    };
+    let files: Vec<PathBuf> = inbox_files(inbox, config::inbox_max_depth())?
+        .into_iter()
+        .filter(|path| *path != batch_csv)
+        .collect();
 
-        for entry in files {
-            let path = entry.path();
-            match process_and_upload_file(&path).await {
-                Ok(_) => {
-                    processed_count += 1;
-                    println!("Successfully processed and uploaded: {:?}", path);
-                    // Remove the original file after successful upload
-                    if let Err(e) = fs::remove_file(&path) {
-                        println!("Error removing file {:?}: {}", path, e);
-                    }
-                }
-                Err(e) => {
-                    println!("Error processing file {:?}: {}", path, e);
-                    // Continue with the next file
+    for path in files {
+        let target_dir = if is_image(&path) {
+            WORKING_IMAGES_DIR
+        } else {
+            WORKING_FILES_DIR
+        };
+
+        // Matched on the name as delivered, before the alt= token and sanitizing
+        if is_image(&path) {
+            if let Some(text) = batch_metadata.remove(&file_name_lossy(&path)?.to_lowercase()) {
+                if let Err(e) = apply_batch_metadata(&path, &text) {
+                    warn!("Not applying {} to {:?}: {}", BATCH_METADATA_FILE, path, e);
                 }
             }
         }
-    }
 
-    // Cleanup: remove working directories
-    let directories_to_remove = [
-        Path::new("content/uploads/_working-images"),
-        Path::new("content/uploads/_working-files"),
-    ];
+        let (file_name, token_alt) = split_alt_token(&file_name_lossy(&path)?);
+        let sanitized_name = working_name(&file_name);
+        let target_path = working_path(Path::new(target_dir), inbox, &path, &sanitized_name, options.preserve_inbox_paths);
+        if let Some(parent) = target_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
 
-    for dir in &directories_to_remove {
-        if dir.exists() {
-            if let Err(e) = fs::remove_dir_all(dir) {
-                println!("Error removing directory {:?}: {}", dir, e);
+        // Move file to appropriate working directory
+        fs::rename(&path, &target_path)?;
+        info!("Moved {:?} to {:?}", path, target_path);
+
+        // Sidecars follow their file under its new name
+        for suffix in SIDECAR_SUFFIXES {
+            let sidecar = sidecar_path(&path, suffix);
+            if sidecar.exists() {
+                fs::rename(&sidecar, sidecar_path(&target_path, suffix))?;
+            }
+        }
+
+        // The sanitized name can't hold an alt= token, so keep it as a sidecar
+        let alt_sidecar = sidecar_path(&target_path, ".alt.txt");
+        if let (Some(alt), false) = (token_alt, alt_sidecar.exists()) {
+            fs::write(alt_sidecar, alt)?;
+        }
+
+        // Tidy up subfolders the move emptied; anything still in them stays
+        for folder in path.ancestors().skip(1).take_while(|folder| *folder != inbox) {
+            if fs::remove_dir(folder).is_err() {
+                break;
             }
         }
     }
 
-    println!("Upload process completed successfully.");
-    if total_count == 0 {
-        Ok("No valid files to process.".into())
-    } else {
-        Ok(format!(
-            "Successfully processed and uploaded {} out of {} files.",
-            processed_count,
-            total_count
-        ))
+    if batch_csv.is_file() {
+        for file_name in batch_metadata.keys() {
+            warn!("{} lists {} but no such image is in the inbox", BATCH_METADATA_FILE, file_name);
+        }
+        // A dry run leaves the CSV for the real run
+        if !options.dry_run {
+            fs::remove_file(&batch_csv)?;
+        }
+        info!("Applied {:?} to the inbox images", batch_csv);
     }
+
+    Ok(true)
 }
 
-fn process_and_upload_js(mut cx: FunctionContext) -> JsResult<JsString> {
-    let result = runtime().block_on(async {
-        match process_and_upload_all().await {
-            Ok(message) => message,
-            Err(e) => format!("Error: {}", e),
+/// Uploadable files in `dir` and its subfolders, grouped by subfolder
+/// relative to `dir` as `events/2024/`, the top level first as `""`.
+fn working_files(dir: &Path) -> Result<Vec<(String, Vec<PathBuf>)>, UploadError> {
+    let mut groups = vec![(String::new(), uploadable_files(dir)?)];
+
+    let mut subfolders: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    subfolders.sort();
+    for subfolder in subfolders {
+        let name = subfolder.file_name().and_then(OsStr::to_str).unwrap_or_default().to_string();
+        for (relative, files) in working_files(&subfolder)? {
+            groups.push((format!("{}/{}", name, relative), files));
         }
-    });
+    }
+    Ok(groups)
+}
 
-    Ok(cx.string(result))
+/// An intermediate file that is deleted when dropped, so it's cleaned up
+/// however processing of its source ends.
+pub(crate) struct TempFile {
+    path: PathBuf,
 }
 
-fn mkdir_and_download_all_images_from_s3(mut cx: FunctionContext) -> JsResult<JsBoolean> {
-    let result = runtime().block_on(async {
-        println!("{}", "Creating S3 mount...".yellow().bold());
-        match S3Mount::new().await {
-            Ok(mount) => {
-                if let Err(e) = mount.create_local_dir(LOCAL_IMAGE_DIR) {
-                    println!("Error creating local directory: {}", e);
-                    return Err(());
-                }
-                
-                let keys = S3Mount::get_image_metadata();
-            
-                for key in keys {
-                    let local_path = format!("{}/{}", LOCAL_IMAGE_DIR, key.key);
-                    if let Err(e) = mount.download_file(&key.key, &local_path).await {
-                        println!("Error downloading file: {}", e);
-                    }
-                }
+impl TempFile {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        TempFile { path }
+    }
 
-                println!("{}", "All images downloaded successfully".green());
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
 
-                Ok(())
-            }
-            Err(e) => {
-                println!("Error creating S3 mount: {}", e);
-                Err(())
+    /// Disarms the guard, leaving the file on disk, and returns its path.
+    pub(crate) fn keep(mut self) -> PathBuf {
+        std::mem::take(&mut self.path)
+    }
+}
+
+/// Lets an intermediate file go as usual, or leaves it on disk when the run
+/// keeps its working files.
+fn finish_intermediate(file: TempFile, options: &UploadOptions) {
+    if options.keep_working {
+        let path = file.keep();
+        info!("Keeping intermediate {:?}", path);
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if self.path.exists() {
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!("Error removing temporary file {:?}: {}", self.path, e);
             }
         }
-    });
+    }
+}
 
-    Ok(JsBoolean::new(&mut cx, result.is_ok()))
+/// TIFF and BMP are too heavy, and too poorly supported, to serve on the web,
+/// so they're always normalized to PNG before upload.
+pub(crate) fn needs_png_normalization(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(OsStr::to_str).map(str::to_lowercase).as_deref(),
+        Some("tif" | "tiff" | "bmp")
+    )
 }
 
-#[neon::main]
-fn main(mut cx: ModuleContext) -> NeonResult<()> {
-    cx.export_function("mkdir_and_download_files", mkdir_and_download_all_images_from_s3)?;
-    cx.export_function("upload", process_and_upload_js)?;
-    Ok(())
+/// Converts an image to a temporary PNG next to it. The source is left in
+/// place; it's only removed once the whole file has uploaded successfully.
+fn convert_to_png(image_path: &Path) -> Result<TempFile, UploadError> {
+    debug!("Converting image {:?} to PNG", image_path);
+    let stem = image_path.file_stem().and_then(OsStr::to_str).unwrap_or("image");
+    // A dotfile, so an interrupted run's leftovers are ignored as placeholders
+    let converted = TempFile::new(image_path.with_file_name(format!(".{}.converting.png", stem)));
+
+    let img = image::open(image_path)?;
+    img.save_with_format(converted.path(), ImageFormat::Png)?;
+    Ok(converted)
+}
+
+/// Decodes an image, naming the file in the error when it can't be read, as
+/// happens with truncated or half-copied files, and rejecting images that
+/// decode to zero pixels.
+fn open_image(path: &Path) -> Result<DynamicImage, UploadError> {
+    let img = image::open(path).map_err(|e| {
+        let name = path.file_name().map_or_else(|| path.display().to_string(), |n| n.to_string_lossy().into_owned());
+        match e {
+            image::ImageError::Decoding(_) | image::ImageError::IoError(_) => {
+                image::ImageError::Decoding(
+                    image::error::DecodingError::new(
+                        image::error::ImageFormatHint::Name(name),
+                        format!("{} (the file may be truncated or corrupt)", e)
+                    )
+                )
+            }
+            other => other,
+        }
+    })?;
+
+    if img.width() == 0 || img.height() == 0 {
+        return Err(
+            UploadError::InvalidFile(format!("{:?} decodes to an empty {}x{} image", path, img.width(), img.height()))
+        );
+    }
+    Ok(img)
+}
+
+/// Reads the EXIF orientation tag from an image file, if present
+fn exif_orientation(path: &Path) -> Option<u32> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+        .value
+        .get_uint(0)
+}
+
+/// Rotates/flips an image according to an EXIF orientation value (1-8)
+fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// Rejects an image whose width or height is under `min` (`MIN_DIMENSION`).
+/// Without a minimum, images under 100px only get a warning, since they
+/// usually mean something went wrong.
+fn check_min_dimension(path: &Path, width: u32, height: u32, min: Option<u32>) -> Result<(), UploadError> {
+    match min {
+        Some(min) if width < min || height < min => Err(
+            UploadError::InvalidFile(
+                format!("{:?} is {}x{}, smaller than the {}px minimum (MIN_DIMENSION)", path, width, height, min)
+            )
+        ),
+        None if width < 100 || height < 100 => {
+            warn!("Image dimensions seem unusually small. This might indicate an issue with the image file.");
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Bakes any EXIF orientation into the pixels and rewrites the file without
+/// EXIF, so GPS coordinates and camera serials never reach the public bucket.
+fn strip_exif_and_orient(path: &Path) -> Result<(), UploadError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if exif::Reader::new().read_from_container(&mut reader).is_err() {
+        // No EXIF block, nothing to strip
+        return Ok(());
+    }
+
+    let orientation = exif_orientation(path).unwrap_or(1);
+    debug!("Stripping EXIF from {:?} (orientation {})", path, orientation);
+    let img = apply_orientation(image::open(path)?, orientation);
+    save_original(&img, path)
+}
+
+/// Overlays `mark` on `img`, scaled to `watermark.scale` of the image width,
+/// faded to `watermark.opacity` and inset from the chosen corner.
+fn apply_watermark(img: &mut DynamicImage, mark: &DynamicImage, watermark: &Watermark) {
+    let mark_width = ((img.width() as f32 * watermark.scale).round() as u32).clamp(1, img.width());
+    let (mark_width, mark_height) = variants::variant_dimensions(mark.width(), mark.height(), mark_width, Some(img.height()));
+    let mut mark = mark.resize_exact(mark_width, mark_height, config::resize_filter()).to_rgba8();
+    for pixel in mark.pixels_mut() {
+        pixel[3] = (pixel[3] as f32 * watermark.opacity).round() as u8;
+    }
+
+    let margin = img.width().min(img.height()) / 50;
+    let right = img.width().saturating_sub(mark_width + margin);
+    let bottom = img.height().saturating_sub(mark_height + margin);
+    let (x, y) = match watermark.position {
+        WatermarkPosition::TopLeft => (margin, margin),
+        WatermarkPosition::TopRight => (right, margin),
+        WatermarkPosition::BottomLeft => (margin, bottom),
+        WatermarkPosition::BottomRight => (right, bottom),
+        WatermarkPosition::Center => ((img.width() - mark_width) / 2, (img.height() - mark_height) / 2),
+    };
+    image::imageops::overlay(img, &mark, i64::from(x), i64::from(y));
+}
+
+/// Writes a watermarked copy of the image next to it, removed when the
+/// returned guard drops. The source is left as it was so a retry doesn't
+/// stamp the watermark twice.
+fn watermark_image(image_path: &Path, watermark: &Watermark) -> Result<TempFile, UploadError> {
+    debug!("Watermarking {:?} with {:?}", image_path, watermark.path);
+    let stem = image_path.file_stem().and_then(OsStr::to_str).unwrap_or("image");
+    let extension = image_path.extension().and_then(OsStr::to_str).unwrap_or("png");
+    // A dotfile, so an interrupted run's leftovers are ignored as placeholders
+    let watermarked = TempFile::new(image_path.with_file_name(format!(".{}.watermarked.{}", stem, extension)));
+
+    let mark = image::open(&watermark.path)?;
+    let mut img = image::open(image_path)?;
+    apply_watermark(&mut img, &mark, watermark);
+    save_original(&img, watermarked.path())?;
+    Ok(watermarked)
+}
+
+/// Writes a progressive re-encode of a sequential JPEG next to it, removed
+/// when the returned guard drops.
+fn progressive_copy(image_path: &Path) -> Result<TempFile, UploadError> {
+    debug!("Re-encoding {:?} as a progressive JPEG", image_path);
+    let stem = image_path.file_stem().and_then(OsStr::to_str).unwrap_or("image");
+    let extension = image_path.extension().and_then(OsStr::to_str).unwrap_or("jpg");
+    // A dotfile, so an interrupted run's leftovers are ignored as placeholders
+    let progressive = TempFile::new(image_path.with_file_name(format!(".{}.progressive.{}", stem, extension)));
+
+    save_original(&image::open(image_path)?, progressive.path())?;
+    Ok(progressive)
+}
+
+/// Whether the file is a GIF with more than one frame.
+fn is_animated_gif(path: &Path) -> bool {
+    if ImageFormat::from_path(path).ok() != Some(ImageFormat::Gif) {
+        return false;
+    }
+    let Ok(file) = File::open(path) else {
+        return false;
+    };
+    match GifDecoder::new(BufReader::new(file)) {
+        Ok(decoder) => decoder.into_frames().take(2).count() > 1,
+        Err(_) => false,
+    }
+}
+
+/// Writes an animated WebP copy of an animated GIF next to it, removed when
+/// the returned guard drops.
+fn gif_to_webp(gif_path: &Path) -> Result<TempFile, UploadError> {
+    debug!("Converting animated GIF {:?} to WebP", gif_path);
+    let stem = gif_path.file_stem().and_then(OsStr::to_str).unwrap_or("image");
+    // A dotfile, so an interrupted run's leftovers are ignored as placeholders
+    let output = TempFile::new(gif_path.with_file_name(format!(".{}.animated.webp", stem)));
+    webp::gif_to_animated_webp(gif_path, output.path(), config::variant_quality())?;
+    Ok(output)
+}
+
+/// Saves an image, encoding JPEG output at the given quality (1-100).
+/// Other formats use the image crate's default encoder.
+pub fn save_image(img: &DynamicImage, output_path: &Path, quality: u8) -> image::ImageResult<()> {
+    let bytes = encode_image(img, ImageFormat::from_path(output_path)?, quality)?;
+    fs::write(output_path, bytes)?;
+    Ok(())
+}
+
+/// Encodes an image in memory, JPEG at the given quality (1-100) and other
+/// formats with the image crate's default encoder. Transparent images headed
+/// for JPEG are flattened onto the `FLATTEN_BG` colour first.
+pub fn encode_image(img: &DynamicImage, format: ImageFormat, quality: u8) -> image::ImageResult<Vec<u8>> {
+    let mut bytes = Vec::new();
+    match format {
+        ImageFormat::Jpeg if img.color().has_alpha() => {
+            let flat = flatten(img, config::flatten_background());
+            flat.write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, quality))?
+        }
+        ImageFormat::Jpeg => img.write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, quality))?,
+        _ => img.write_to(&mut Cursor::new(&mut bytes), format)?,
+    }
+    Ok(bytes)
+}
+
+/// Saves a re-encoded original at `ORIGINAL_QUALITY`. JPEGs come out
+/// progressive when `PROGRESSIVE_JPEG` is on, or baseline if that fails.
+fn save_original(img: &DynamicImage, output_path: &Path) -> Result<(), UploadError> {
+    if jpeg::progressive_enabled() && ImageFormat::from_path(output_path).ok() == Some(ImageFormat::Jpeg) {
+        let flat;
+        let img = if img.color().has_alpha() {
+            flat = flatten(img, config::flatten_background());
+            &flat
+        } else {
+            img
+        };
+        match jpeg::encode_progressive(img, ORIGINAL_QUALITY) {
+            Ok(bytes) => {
+                fs::write(output_path, bytes)?;
+                return Ok(());
+            }
+            Err(e) => warn!("{}; writing a baseline JPEG", e),
+        }
+    }
+    save_image(img, output_path, ORIGINAL_QUALITY)?;
+    Ok(())
+}
+
+/// Composites `img` over a solid `background`, dropping the alpha channel.
+fn flatten(img: &DynamicImage, background: [u8; 3]) -> DynamicImage {
+    let mut flat = image::RgbImage::new(img.width(), img.height());
+    for (pixel, source) in flat.pixels_mut().zip(img.to_rgba8().pixels()) {
+        let alpha = source[3] as u32;
+        for channel in 0..3 {
+            let blended = source[channel] as u32 * alpha + background[channel] as u32 * (255 - alpha);
+            pixel[channel] = ((blended + 127) / 255) as u8;
+        }
+    }
+    DynamicImage::ImageRgb8(flat)
+}
+
+/// oxipng preset (0-6); 2 is its default balance of size and speed.
+const OXIPNG_PRESET: u8 = 2;
+
+/// Losslessly recompresses a PNG in place with oxipng when `OPTIMIZE_PNG` is
+/// set. Anything else is left alone, and if optimization fails the file is
+/// kept as written.
+pub(crate) fn optimize_png(path: &Path) {
+    let is_png = path.extension().and_then(OsStr::to_str).is_some_and(|e| e.eq_ignore_ascii_case("png"));
+    if !is_png || !config::env_flag("OPTIMIZE_PNG") {
+        return;
+    }
+
+    let result = fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|data| {
+            let optimized = oxipng::optimize_from_memory(&data, &oxipng::Options::from_preset(OXIPNG_PRESET))
+                .map_err(|e| e.to_string())?;
+            Ok((data.len(), optimized))
+        });
+    match result {
+        Ok((before, optimized)) if optimized.len() < before => {
+            // Written alongside and renamed over, so a failed write can't truncate the PNG
+            let staged = TempFile::new(path.with_extension("optimized.png"));
+            match fs::write(staged.path(), &optimized).and_then(|()| fs::rename(staged.path(), path)) {
+                Ok(()) => debug!("Optimized {:?}: {} -> {} bytes", path, before, optimized.len()),
+                Err(e) => warn!("Failed to write optimized PNG {:?}, keeping original: {}", path, e),
+            }
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Failed to optimize PNG {:?}, uploading unoptimized: {}", path, e),
+    }
+}
+
+/// Resizes an image to `size` as `mode` applies it (width, longest edge or a
+/// bounding box) while maintaining its aspect ratio, scaling further down
+/// when the result would be taller than `max_height`.
+pub fn resize_image(
+    image_path: &Path,
+    output_path: &Path,
+    mode: ResizeMode,
+    size: u32,
+    max_height: Option<u32>,
+    quality: u8
+) -> Result<(), UploadError> {
+    let format = ImageFormat::from_path(output_path)?;
+    let resized = resize_bytes(&fs::read(image_path)?, mode, size, max_height, format, quality)?;
+    fs::write(output_path, resized)?;
+    Ok(())
+}
+
+/// Resizes an encoded image to `width`, keeping its aspect ratio, and
+/// re-encodes it as `format` without touching the disk. JPEG output uses the
+/// `VARIANT_QUALITY` setting.
+pub fn resize_image_bytes(input: &[u8], width: u32, format: ImageFormat) -> Result<Vec<u8>, UploadError> {
+    resize_bytes(input, ResizeMode::Width, width, None, format, config::variant_quality())
+}
+
+/// Images with an alpha channel as 8-bit RGBA, whatever layout they were
+/// decoded into (expanded palettes, grey + alpha, 16-bit). Resampling and
+/// the PNG encoder then carry transparency through instead of leaving
+/// black where it was.
+pub(crate) fn rgba_if_transparent(img: DynamicImage) -> DynamicImage {
+    match img {
+        DynamicImage::ImageRgba8(_) => img,
+        img if img.color().has_alpha() => DynamicImage::ImageRgba8(img.to_rgba8()),
+        img => img,
+    }
+}
+
+fn resize_bytes(
+    input: &[u8],
+    mode: ResizeMode,
+    size: u32,
+    max_height: Option<u32>,
+    format: ImageFormat,
+    quality: u8
+) -> Result<Vec<u8>, UploadError> {
+    let img = rgba_if_transparent(image::load_from_memory(input)?);
+    let (width, height) = mode.dimensions(img.width(), img.height(), size, max_height);
+    let resized = img.resize_exact(width, height, config::resize_filter());
+    Ok(encode_image(&resized, format, quality)?)
+}
+
+/// Cuts `region` out of the image, placing entropy crops as it goes, and
+/// resizes the crop to `width` x `height`.
+fn crop_and_resize(
+    image_path: &Path,
+    output_path: &Path,
+    region: &mut CropRegion,
+    width: u32,
+    height: u32,
+    quality: u8
+) -> Result<(), UploadError> {
+    let img = rgba_if_transparent(image::open(image_path)?);
+    let resized = crop::apply(&img, region).resize_exact(width, height, config::resize_filter());
+    save_image(&resized, output_path, quality)?;
+    Ok(())
+}
+
+/// Writes a `size` x `size` thumbnail cropped from the centre of the image.
+pub fn square_thumbnail(image_path: &Path, output_path: &Path, size: u32, quality: u8) -> Result<(), UploadError> {
+    let img = rgba_if_transparent(image::open(image_path)?);
+    let side = img.width().min(img.height());
+    let cropped = img.crop_imm((img.width() - side) / 2, (img.height() - side) / 2, side, side);
+    let thumbnail = cropped.resize_exact(size, size, config::resize_filter());
+    save_image(&thumbnail, output_path, quality)?;
+    Ok(())
+}
+
+/// Computes the hex-encoded SHA-256 digest of a file's contents, in chunks
+/// so large static files aren't read into memory
+fn content_hash(file_path: &Path) -> Result<String, UploadError> {
+    let mut sha256 = Sha256::new();
+    read_chunks(file_path, |chunk| sha256.update(chunk))?;
+    Ok(format!("{:x}", sha256.finalize()))
+}
+
+/// Returns the uid of an already-uploaded image whose metadata records the given hash
+fn find_image_by_hash(data_dir: &Path, hash: &str) -> Option<String> {
+    let entries = fs::read_dir(data_dir).ok()?;
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|s| s.to_str()) != Some("yml") {
+            continue;
+        }
+        let Ok(content) = fs::read_to_string(&path) else {
+            continue;
+        };
+        let matches = content.lines().any(|line| {
+            line.split_once(':')
+                .is_some_and(|(key, value)| key.trim() == "hash" && value.trim() == hash)
+        });
+        if matches {
+            return path.file_stem().and_then(|s| s.to_str()).map(String::from);
+        }
+    }
+
+    None
+}
+
+/// Encodes a 4x3 blurhash placeholder from a small thumbnail of the image.
+/// Templates render it as a blurred background while the real image loads.
+pub(crate) fn compute_blurhash(img: &DynamicImage) -> Option<String> {
+    let thumbnail = img.thumbnail(32, 32).to_rgba8();
+    blurhash::encode(4, 3, thumbnail.width(), thumbnail.height(), thumbnail.as_raw()).ok()
+}
+
+/// Quotes text as a YAML double-quoted scalar. JSON string syntax is valid
+/// YAML, so quotes and newlines in editor-supplied text are escaped safely.
+fn yaml_string(text: &str) -> String {
+    serde_json::to_string(text).unwrap_or_else(|_| "\"\"".to_string())
+}
+
+/// Everything recorded in an image's `data/images/{uid}.yml`.
+struct ImageRecord<'a> {
+    /// Key prefix the image's objects were uploaded under, partition included.
+    prefix: &'a str,
+    uid: &'a str,
+    width: u32,
+    height: u32,
+    format: &'a str,
+    hash: &'a str,
+    blurhash: &'a str,
+    text: &'a ImageText,
+    variants: &'a [PlannedVariant],
+    thumbnail: Option<&'a SquareThumbnail>,
+}
+
+/// The `variants` list, one entry per generated file with its breakpoint
+/// name, pixel dimensions and density, so templates can reserve layout
+/// space and build a `srcset` without recomputing anything. Cropped variants
+/// also record the source window they were cut from.
+fn variants_yaml(prefix: &str, uid: &str, format: &str, variants: &[PlannedVariant]) -> String {
+    if variants.is_empty() {
+        return String::new();
+    }
+
+    let mut yaml = String::from("\n# Resized copies for srcset; density is the 1x/2x descriptor.\nvariants :\n");
+    for variant in variants {
+        yaml.push_str(&format!(
+            "  - name       :  {}\n    key        :  {}{}{}.{}\n    breakpoint :  {}\n    width      :  {}\n    height     :  {}\n    density    :  {}x\n",
+            variant.name,
+            prefix,
+            uid,
+            variant.suffix(),
+            format,
+            variant.breakpoint,
+            variant.width,
+            variant.height,
+            variant.density
+        ));
+        if let Some(crop) = &variant.crop {
+            yaml.push_str(&format!(
+                "    crop       :  {{ aspect: \"{}\", strategy: {}, x: {}, y: {}, width: {}, height: {} }}\n",
+                crop.aspect,
+                crop.strategy.as_str(),
+                crop.x,
+                crop.y,
+                crop.width,
+                crop.height
+            ));
+        }
+    }
+    yaml
+}
+
+/// A `srcset` attribute value listing every variant URL, and the original,
+/// by its pixel width, narrowest first. Retina copies are included since a
+/// `w` descriptor already accounts for density. Cropped variants show a
+/// different picture, so they're left out.
+fn srcset(prefix: &str, uid: &str, format: &str, width: u32, variants: &[PlannedVariant]) -> String {
+    let mut candidates: Vec<(u32, String)> = variants
+        .iter()
+        .filter(|variant| variant.crop.is_none())
+        .map(|variant| (variant.width, format!("{}{}{}.{}", prefix, uid, variant.suffix(), format)))
+        .collect();
+    candidates.push((width, format!("{}{}.{}", prefix, uid, format)));
+    // A retina copy can come out as wide as another breakpoint's; keep one per width
+    candidates.sort();
+    candidates.dedup_by_key(|(width, _)| *width);
+
+    candidates
+        .iter()
+        .map(|(width, key)| format!("{} {}w", public_url(&config::s3().image_bucket, key), width))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Writes `data/images/{uid}.yml` and returns its path.
+fn write_image_metadata(record: &ImageRecord) -> Result<PathBuf, UploadError> {
+    let ImageRecord { prefix, uid, width, height, format, hash, blurhash, text, variants, thumbnail } = *record;
+    debug!("Generating metadata for image - dimensions: {}x{}", width, height);
+    let key = format!("{}{}.{}", prefix, uid, format);
+    let url = public_url(&config::s3().image_bucket, &key);
+    let mut metadata = format!(
+        r#"
+# {url}
+# Image shortcode: {{{{ img src="{uid}" }}}}
+date     :  {}
+uid      :  {}
+width    :  {}
+height   :  {}
+format   :  {}
+key      :  {}
+hash     :  {}
+blurhash :  "{}"
+
+# REQUIRED alternative text for accessibility.
+# Keep within 150 characters. https://capitalizemytitle.com/character-counter/ will count characters.
+alt      :  {}
+
+# Caption text appears below the image; usually the attribution for stock images.
+# Must be different from the alt text.
+caption  :  {}
+
+# Credit text appears after the caption text, separated by an m-dash.
+# Example https://digital.gov/2023/12/08/making-gsa-public-art-collection-more-accessible/ 
+credit   :  {}
+"#,
+        config::metadata_date(),
+        uid,
+        width,
+        height,
+        format,
+        key,
+        hash,
+        blurhash,
+        yaml_string(&text.alt),
+        yaml_string(&text.caption),
+        yaml_string(&text.credit)
+    );
+    metadata.push_str(&format!(
+        "\n# Ready-made responsive image attributes.\nsrcset   :  {}\nsizes    :  {}\n",
+        yaml_string(&srcset(prefix, uid, format, width, variants)),
+        yaml_string(&config::srcset_sizes())
+    ));
+    metadata.push_str(&variants_yaml(prefix, uid, format, variants));
+    if let Some(thumbnail) = thumbnail {
+        metadata.push_str(&format!(
+            "\n# Centre-cropped square for cards.\nthumbnail :  {}{}{}.{}\n",
+            prefix,
+            uid,
+            thumbnail.suffix(),
+            format
+        ));
+    }
+
+    fs::create_dir_all(IMAGE_DATA_DIR)?;
+    let path = Path::new(IMAGE_DATA_DIR).join(format!("{}.yml", uid));
+    fs::write(&path, metadata)?;
+    Ok(path)
+}
+
+/// Generates and writes YML metadata for a file uploaded under `prefix`,
+/// returning its path
+fn write_file_metadata(prefix: &str, uid: &str, format: &str, thumbnail: Option<&str>) -> Result<PathBuf, UploadError> {
+    let key = format!("{}{}.{}", prefix, uid, format);
+    let url = public_url(&config::s3().file_bucket, &key);
+    let mut metadata = format!(
+        r#"
+# {url}
+# File shortcode: {{{{ asset-static file="{uid}.{format}" label="{uid} ({format})" }}}}
+date     :  {}
+uid      :  {}
+format   :  {}
+key      :  {}
+"#,
+        config::metadata_date(),
+        uid,
+        format,
+        key
+    );
+
+    if let Some(thumbnail) = thumbnail {
+        metadata.push_str(&format!("\n# First-page preview.\nthumbnail :  {}\n", thumbnail));
+    }
+
+    fs::create_dir_all(FILE_DATA_DIR)?;
+    let path = Path::new(FILE_DATA_DIR).join(format!("{}.yml", uid));
+    fs::write(&path, metadata)?;
+    Ok(path)
+}
+
+/// An object written to S3 during a run.
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadedObject {
+    pub key: String,
+    pub url: String,
+    pub bytes: u64,
+    pub content_type: String,
+}
+
+/// Public URL of an object in `bucket`, on the custom endpoint when one is set.
+fn public_url(bucket: &str, key: &str) -> String {
+    let endpoint = config::s3().endpoint_url.as_deref().unwrap_or("https://s3.amazonaws.com");
+    format!("{}/{}/{}", endpoint, bucket, key)
+}
+
+/// Feeds a file to `update` in chunks, so large files aren't read into memory.
+fn read_chunks(file_path: &Path, mut update: impl FnMut(&[u8])) -> Result<(), UploadError> {
+    let mut reader = BufReader::new(File::open(file_path)?);
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        update(&buf[..n]);
+    }
+}
+
+/// Base64 MD5 and SHA-256 digests of a file, as S3 checks them on upload.
+fn file_checksums(file_path: &Path) -> Result<(String, String), UploadError> {
+    let mut md5 = Md5::new();
+    let mut sha256 = Sha256::new();
+    read_chunks(file_path, |chunk| {
+        md5.update(chunk);
+        sha256.update(chunk);
+    })?;
+    Ok((BASE64.encode(md5.finalize()), BASE64.encode(sha256.finalize())))
+}
+
+/// Hex MD5 of a file, in the form single-part ETags carry it.
+fn file_md5_hex(file_path: &Path) -> Result<String, UploadError> {
+    let mut md5 = Md5::new();
+    read_chunks(file_path, |chunk| md5.update(chunk))?;
+    Ok(format!("{:x}", md5.finalize()))
+}
+
+/// Shown when neither `.env` nor the default provider chain has credentials.
+const MISSING_CREDENTIALS: &str =
+    "AWS credentials not found; set AWS_ACCESS_KEY_ID/AWS_SECRET_ACCESS_KEY or configure a profile";
+
+/// Builds an S3 client from the `AWS_PROFILE` profile, `.env` credentials or
+/// the default provider chain, failing up front when none yields credentials
+/// rather than on the first request with an opaque SDK error.
+pub(crate) async fn s3_client() -> Result<Client, UploadError> {
+    build_s3_client(true).await
+}
+
+/// The stores a batch run uploads to. A dry run uploads nothing, so it
+/// doesn't insist on credentials before starting; the few lookups it makes
+/// fail per file instead.
+async fn stores_for_run(options: &UploadOptions) -> Result<Stores, UploadError> {
+    if options.dry_run {
+        return Ok(Stores::for_client(build_s3_client(false).await?));
+    }
+    Stores::from_env().await
+}
+
+async fn build_s3_client(check_credentials: bool) -> Result<Client, UploadError> {
+    // Load .env file
+    dotenv().ok();
+
+    let region_provider = RegionProviderChain::first_try(Region::new(config::s3().region.clone()))
+        .or_default_provider()
+        .or_else(Region::new("us-east-1"));
+
+    debug!("Loading AWS config...");
+    let mut config_loader = aws_config::from_env().region(region_provider);
+
+    // A named profile from ~/.aws/credentials wins over raw keys, so
+    // switching accounts doesn't mean editing .env
+    let profile = env::var("AWS_PROFILE").ok().filter(|profile| !profile.trim().is_empty());
+    if let Some(profile) = &profile {
+        info!("Using AWS profile {}", profile);
+        config_loader = config_loader.profile_name(profile.trim());
+    } else if let (Ok(access_key), Ok(secret_key)) = (
+        env::var("AWS_ACCESS_KEY_ID"),
+        env::var("AWS_SECRET_ACCESS_KEY"),
+    ) {
+        debug!("Using credentials from .env file");
+        let creds = Credentials::new(access_key, secret_key, None, None, "dotenv");
+        config_loader = config_loader.credentials_provider(creds);
+    } else {
+        debug!("No credentials in .env, falling back to default credential provider chain");
+    }
+
+    // Bounds connecting and each wait for data rather than a whole request,
+    // so a large PUT on a slow link isn't cut off while it's still moving
+    if let Some(timeout) = config::s3_timeout() {
+        config_loader = config_loader.timeout_config(
+            TimeoutConfig::builder().connect_timeout(timeout).read_timeout(timeout).build()
+        );
+    }
+
+    let config = config_loader.load().await;
+
+    let missing = || match &profile {
+        Some(profile) => UploadError::Other(format!("AWS profile {:?} has no usable credentials", profile)),
+        None => MISSING_CREDENTIALS.into(),
+    };
+    if check_credentials {
+        let provider = config.credentials_provider().ok_or_else(missing)?;
+        if let Err(e) = provider.provide_credentials().await {
+            warn!("Credential lookup failed: {}", e);
+            return Err(missing());
+        }
+    }
+
+    debug!("Creating S3 client...");
+    let mut s3_config = aws_sdk_s3::config::Builder::from(&config);
+    if let Some(endpoint_url) = &config::s3().endpoint_url {
+        info!("Using S3-compatible endpoint {}", endpoint_url);
+        s3_config = s3_config.endpoint_url(endpoint_url).force_path_style(true);
+    }
+    Ok(Client::from_conf(s3_config.build()))
+}
+
+/// Confirms the configured buckets can be reached with the current
+/// credentials by calling `HeadBucket` on each, so a batch can fail fast
+/// instead of file by file. Returns a short description of what was reached.
+pub async fn check_connection() -> Result<String, UploadError> {
+    let s3 = config::s3();
+    let client = s3_client().await?;
+
+    let mut buckets = vec![&s3.image_bucket, &s3.file_bucket];
+    buckets.dedup();
+    let mut reached = Vec::new();
+    for bucket in buckets {
+        match client.head_bucket().bucket(bucket).send().await {
+            Ok(_) => reached.push(bucket.as_str()),
+            Err(SdkError::ServiceError(err)) if err.err().is_not_found() => {
+                return Err(UploadError::RequestError(format!("Bucket {} does not exist", bucket)));
+            }
+            Err(SdkError::ServiceError(err)) if err.raw().http().status().as_u16() == 403 => {
+                return Err(UploadError::RequestError(format!(
+                    "Access to bucket {} was denied; check the credentials' permissions",
+                    bucket
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    match reached.as_slice() {
+        [bucket] => Ok(format!("Connected to bucket {} in {}", bucket, s3.region)),
+        buckets => Ok(format!("Connected to buckets {} in {}", buckets.join(" and "), s3.region)),
+    }
+}
+
+/// How many `{stem}-{n}` candidates are tried before a rename gives up.
+const MAX_SUFFIX_ATTEMPTS: u32 = 100;
+
+/// The numbered alternatives to `stem`: `{stem}-2`, `{stem}-3` and so on, up
+/// to `MAX_SUFFIX_ATTEMPTS` of them.
+fn suffixed(stem: &str) -> impl Iterator<Item = String> + '_ {
+    (2..2 + MAX_SUFFIX_ATTEMPTS).map(move |n| format!("{}-{}", stem, n))
+}
+
+fn no_free_suffix(what: &str) -> UploadError {
+    UploadError::Other(format!("No free name for {} after {} attempts", what, MAX_SUFFIX_ATTEMPTS))
+}
+
+/// Whether any object under `prefix` already belongs to `stem`: an original
+/// in any format, a resized variant or thumbnail, or a PDF's `-thumb.png`.
+async fn stem_in_use(store: &dyn ObjectStore, prefix: &str, stem: &str) -> Result<bool, UploadError> {
+    let pdf_thumbnail = format!("{}{}-thumb.png", prefix, stem);
+    Ok(store
+        .list(&format!("{}{}", prefix, stem))
+        .await?
+        .iter()
+        .any(|key| *key == pdf_thumbnail || mount_s3::owner_uid(key) == stem))
+}
+
+/// Picks the stem to upload `{prefix}{stem}.{extension}` under, applying the
+/// configured collision policy when that key already exists in the bucket.
+/// A renamed stem also steers clear of keys left by another upload's
+/// variants and thumbnails.
+async fn resolve_key_collision(
+    store: &dyn ObjectStore,
+    prefix: &str,
+    stem: &str,
+    extension: &str,
+    options: &UploadOptions
+) -> Result<String, UploadError> {
+    // Reprocessing deliberately replaces whatever is already under the key
+    if options.on_collision == CollisionPolicy::Overwrite || options.force_reprocess {
+        return Ok(stem.to_string());
+    }
+
+    let key_for = |stem: &str| format!("{}{}.{}", prefix, stem, extension);
+    if !store.exists(&key_for(stem)).await? {
+        return Ok(stem.to_string());
+    }
+
+    if options.on_collision == CollisionPolicy::Error {
+        return Err(UploadError::Other(format!("S3 key {} already exists", key_for(stem))));
+    }
+
+    for candidate in suffixed(stem) {
+        if !stem_in_use(store, prefix, &candidate).await? {
+            info!("S3 key {} already exists, using {}", key_for(stem), key_for(&candidate));
+            return Ok(candidate);
+        }
+    }
+    Err(no_free_suffix(&key_for(stem)))
+}
+
+/// The object key recorded in a metadata file, if it has one.
+fn recorded_key(metadata_path: &Path) -> Option<String> {
+    let content = fs::read_to_string(metadata_path).ok()?;
+    let yaml: serde_yaml::Value = serde_yaml::from_str(&content).ok()?;
+    yaml.get("key")?.as_str().map(String::from)
+}
+
+/// Keeps a file from taking over the metadata of a different object with the
+/// same uid, such as `events/photo.png` and `trips/photo.png` under
+/// `PRESERVE_INBOX_PATHS`, whose keys differ but whose metadata would both be
+/// `{data_dir}/photo.yml`. When that file records another key, the first
+/// `{stem}-{n}` that's free, or already records this key, is used instead.
+/// A chosen uid is never renamed; the upload fails instead.
+async fn avoid_shared_metadata(
+    store: &dyn ObjectStore,
+    data_dir: &Path,
+    prefix: &str,
+    stem: &str,
+    extension: &str,
+    chosen: bool
+) -> Result<String, UploadError> {
+    let metadata_for = |stem: &str| data_dir.join(format!("{}.yml", stem));
+    let key_for = |stem: &str| format!("{}{}.{}", prefix, stem, extension);
+    let Some(recorded) = recorded_key(&metadata_for(stem)).filter(|recorded| *recorded != key_for(stem)) else {
+        return Ok(stem.to_string());
+    };
+    if chosen {
+        return Err(UploadError::Other(format!("{} already describes {}", metadata_for(stem).display(), recorded)));
+    }
+
+    for candidate in suffixed(stem) {
+        let free = match recorded_key(&metadata_for(&candidate)) {
+            Some(key) => key == key_for(&candidate),
+            None => !metadata_for(&candidate).exists() && !store.exists(&key_for(&candidate)).await?,
+        };
+        if free {
+            info!("{} already describes {}, using uid {}", metadata_for(stem).display(), recorded, candidate);
+            return Ok(candidate);
+        }
+    }
+    Err(no_free_suffix(&metadata_for(stem).display().to_string()))
+}
+
+/// Applies the `ON_EXISTING` policy when `{data_dir}/{stem}.yml` is already
+/// there, so hand-edited metadata isn't replaced without anyone noticing.
+/// Returns the stem to upload under, or `None` to skip the file. A chosen
+/// uid is never renamed; the upload fails instead.
+async fn resolve_existing_metadata(
+    store: &dyn ObjectStore,
+    data_dir: &Path,
+    prefix: &str,
+    stem: &str,
+    extension: &str,
+    chosen: bool,
+    options: &UploadOptions
+) -> Result<Option<String>, UploadError> {
+    let metadata_for = |stem: &str| data_dir.join(format!("{}.yml", stem));
+    if options.force_reprocess || !metadata_for(stem).exists() {
+        return Ok(Some(stem.to_string()));
+    }
+
+    match options.on_existing {
+        ExistingPolicy::Overwrite => {
+            warn!("{} already exists and will be overwritten", metadata_for(stem).display());
+            Ok(Some(stem.to_string()))
+        }
+        ExistingPolicy::Skip => {
+            info!("Skipping {}: {} already exists", stem, metadata_for(stem).display());
+            Ok(None)
+        }
+        ExistingPolicy::Rename if chosen => {
+            Err(UploadError::Other(format!("{} already exists", metadata_for(stem).display())))
+        }
+        ExistingPolicy::Rename => {
+            for candidate in suffixed(stem) {
+                let key = format!("{}{}.{}", prefix, candidate, extension);
+                if !metadata_for(&candidate).exists() && !store.exists(&key).await? {
+                    info!("{} already exists, using uid {}", metadata_for(stem).display(), candidate);
+                    return Ok(Some(candidate));
+                }
+            }
+            Err(no_free_suffix(&metadata_for(stem).display().to_string()))
+        }
+    }
+}
+
+/// Metadata paths, `data/images/{uid}.yml` and the like, claimed by files
+/// being processed right now.
+static IN_FLIGHT_UIDS: Lazy<Mutex<HashSet<PathBuf>>> = Lazy::new(Default::default);
+
+/// A uid claimed for one file while it's processed, released on drop.
+pub(crate) struct UidReservation {
+    uid: String,
+    metadata_path: PathBuf,
+}
+
+impl UidReservation {
+    /// Claims `uid` in `data_dir`, or `None` if another file holds it.
+    fn claim(data_dir: &Path, uid: &str) -> Option<Self> {
+        let metadata_path = data_dir.join(format!("{}.yml", uid));
+        let mut in_flight = IN_FLIGHT_UIDS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        in_flight.insert(metadata_path.clone()).then(|| UidReservation { uid: uid.to_string(), metadata_path })
+    }
+
+    pub(crate) fn uid(&self) -> &str {
+        &self.uid
+    }
+}
+
+impl Drop for UidReservation {
+    fn drop(&mut self) {
+        IN_FLIGHT_UIDS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(&self.metadata_path);
+    }
+}
+
+/// Reserves `uid` for the current file so two files that resolve to the same
+/// one, such as `a.png` and `a.jpg` processed in parallel, can't race on its
+/// keys and metadata. When another file already holds it, the first
+/// `{uid}-{n}` that's free in flight, in the bucket and in `data_dir` is used
+/// instead. A chosen uid is never renamed; the upload fails instead.
+async fn reserve_uid(
+    store: &dyn ObjectStore,
+    data_dir: &Path,
+    prefix: &str,
+    uid: &str,
+    extension: &str,
+    chosen: bool
+) -> Result<UidReservation, UploadError> {
+    if let Some(reservation) = UidReservation::claim(data_dir, uid) {
+        return Ok(reservation);
+    }
+    if chosen {
+        return Err(UploadError::Other(format!("uid {} is already being uploaded by another file", uid)));
+    }
+
+    for candidate in suffixed(uid) {
+        let taken = data_dir.join(format!("{}.yml", candidate)).exists() ||
+            store.exists(&format!("{}{}.{}", prefix, candidate, extension)).await?;
+        if !taken {
+            if let Some(reservation) = UidReservation::claim(data_dir, &candidate) {
+                info!("uid {} is in use by another file in flight, using {}", uid, candidate);
+                return Ok(reservation);
+            }
+        }
+    }
+    Err(no_free_suffix(&format!("uid {}", uid)))
+}
+
+/// Whether `key` already holds exactly the bytes of `file_path`: same size
+/// and an MD5 ETag matching the file's. Objects without an MD5 ETag
+/// (multipart or SSE-KMS uploads) never count as unchanged.
+async fn is_unchanged(store: &dyn ObjectStore, file_path: &Path, key: &str, bytes: u64) -> Result<bool, UploadError> {
+    let remote_md5 = match store.head(key).await? {
+        Some(head) if head.size == bytes => head.md5,
+        _ => return Ok(false),
+    };
+    let Some(remote_md5) = remote_md5 else {
+        return Ok(false);
+    };
+
+    Ok(file_md5_hex(file_path)?.eq_ignore_ascii_case(&remote_md5))
+}
+
+/// Uploads a file to the bucket as a public object, tagged with `tagging`
+/// (see `config::object_tagging`).
+///
+/// With `IDEMPOTENT_SKIP` an object whose size and MD5 already match is left
+/// alone, so a changed ACL, tagging or storage class is not re-applied to it;
+/// pass `force` (from `FORCE_REPROCESS`) to upload it again regardless.
+pub async fn upload_to_s3(
+    store: &dyn ObjectStore,
+    file_path: &Path,
+    key: &str,
+    content_type: Option<&str>,
+    storage_class: StorageClass,
+    tagging: &str,
+    force: bool
+) -> Result<UploadedObject, UploadError> {
+    debug!("Attempting to upload file: {:?}", file_path);
+
+    let bytes = fs::metadata(file_path)?.len();
+    let content_type = content_type.unwrap_or("application/octet-stream");
+
+    // A rerun after a partial failure finds earlier uploads already in place
+    if !force && config::idempotent_skip() && is_unchanged(store, file_path, key, bytes).await? {
+        info!("{} unchanged, skipping upload", key);
+        return Ok(UploadedObject {
+            key: key.to_string(),
+            url: public_url(store.bucket(), key),
+            bytes,
+            content_type: content_type.to_string(),
+        });
+    }
+
+    let put_options = PutOptions {
+        content_type,
+        storage_class,
+        acl: config::object_acl(),
+        tagging: Some(tagging).filter(|tagging| !tagging.is_empty()),
+    };
+    store.put(key, file_path, &put_options).await?;
+
+    let url = public_url(store.bucket(), key);
+    info!("Upload completed. File should be accessible at: {}", url);
+    if put_options.acl == Some(ObjectCannedAcl::PublicRead) && verify_public_enabled() {
+        verify_public(&url).await;
+    }
+
+    Ok(UploadedObject {
+        key: key.to_string(),
+        url,
+        bytes,
+        content_type: content_type.to_string(),
+    })
+}
+
+/// Whether public-read uploads are checked anonymously, from `VERIFY_PUBLIC`.
+/// The check needs an HTTP client of its own, so without the `verify-public`
+/// feature the flag is ignored with a warning.
+fn verify_public_enabled() -> bool {
+    static ENABLED: Lazy<bool> = Lazy::new(|| {
+        let requested = config::env_flag("VERIFY_PUBLIC");
+        if requested && !cfg!(feature = "verify-public") {
+            warn!("VERIFY_PUBLIC needs a build with the verify-public feature; not checking uploads");
+        }
+        requested && cfg!(feature = "verify-public")
+    });
+    *ENABLED
+}
+
+/// Sends an anonymous `HEAD` to a public-read object's URL and warns when it
+/// can't be fetched, which catches a bucket policy or ownership setting that
+/// overrides the ACL. Only ever logs; the upload itself has succeeded.
+#[cfg(feature = "verify-public")]
+async fn verify_public(url: &str) {
+    static CLIENT: OnceCell<reqwest::Client> = OnceCell::new();
+    let client = CLIENT.get_or_try_init(|| {
+        let builder = reqwest::Client::builder();
+        let builder = match config::s3_timeout() {
+            Some(timeout) => builder.timeout(timeout),
+            None => builder,
+        };
+        builder.build()
+    });
+    let client = match client {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Skipping the public-read check of {}: {}", url, e);
+            return;
+        }
+    };
+
+    match client.head(url).send().await {
+        Ok(response) if response.status() == reqwest::StatusCode::FORBIDDEN => {
+            warn!("{} is not publicly readable (403); check the bucket policy and Object Ownership setting", url)
+        }
+        Ok(response) if !response.status().is_success() => {
+            warn!("Anonymous HEAD of {} returned {}", url, response.status())
+        }
+        Ok(_) => debug!("Verified {} is publicly readable", url),
+        Err(e) => warn!("Could not check that {} is publicly readable: {}", url, e),
+    }
+}
+
+#[cfg(not(feature = "verify-public"))]
+async fn verify_public(_url: &str) {}
+
+/// Everything produced by processing one file.
+#[derive(Debug, Default)]
+pub struct ProcessedFile {
+    pub uid: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Public URL of the uploaded original (or static file).
+    pub original_url: Option<String>,
+    /// Public URLs of the resized variants and thumbnail, in upload order.
+    pub variant_urls: Vec<String>,
+    /// The metadata file written for the upload, if any.
+    pub metadata_path: Option<String>,
+    pub objects: Vec<UploadedObject>,
+    /// Nothing was uploaded because `ON_EXISTING=skip` found metadata already
+    /// there; the source is left for someone to look at.
+    pub skipped: bool,
+}
+
+/// Uploads a file, or in dry-run mode only reports the key and size it would
+/// have been uploaded with.
+async fn upload_or_report(
+    store: &dyn ObjectStore,
+    file_path: &Path,
+    key: &str,
+    content_type: Option<&str>,
+    storage_class: StorageClass,
+    tagging: &str,
+    options: &UploadOptions
+) -> Result<UploadedObject, UploadError> {
+    if !options.dry_run {
+        return upload_to_s3(store, file_path, key, content_type, storage_class, tagging, options.force_reprocess).await;
+    }
+
+    let bytes = fs::metadata(file_path)?.len();
+    info!("[dry run] Would upload {:?} ({} bytes) to S3 key: {}", file_path, bytes, key);
+    Ok(UploadedObject {
+        key: key.to_string(),
+        url: public_url(store.bucket(), key),
+        bytes,
+        content_type: content_type.unwrap_or("application/octet-stream").to_string(),
+    })
+}
+
+/// Outcome of processing a single file in a batch run.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileResult {
+    pub path: String,
+    pub uid: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub original_url: Option<String>,
+    pub variant_urls: Vec<String>,
+    pub metadata_path: Option<String>,
+    pub objects: Vec<UploadedObject>,
+    /// Combined size of `objects`: the original plus every variant.
+    pub bytes_uploaded: u64,
+    /// Left alone by `ON_EXISTING=skip`; not counted as processed.
+    pub skipped: bool,
+    pub error: Option<String>,
+    pub error_kind: Option<&'static str>,
+}
+
+/// Summary of a batch upload run.
+#[derive(Debug, Default, Serialize)]
+pub struct UploadSummary {
+    pub processed: usize,
+    pub total: usize,
+    pub failed: usize,
+    /// Files `ON_EXISTING=skip` left in place because their metadata exists.
+    pub skipped: usize,
+    /// The inbox directory doesn't exist, as opposed to existing but empty;
+    /// usually the pipeline was run from the wrong working directory.
+    pub inbox_missing: bool,
+    /// Bytes added to the bucket across every file, for tracking storage
+    /// growth per run.
+    pub bytes_uploaded: u64,
+    /// Files `BATCH_LIMIT` left in the working directories for the next run.
+    pub remaining: usize,
+    pub files: Vec<FileResult>,
+}
+
+/// Fails a file of `size` bytes larger than `max_mb`, naming the `setting`
+/// that sets the limit.
+fn check_file_size(path: &Path, size: u64, max_mb: u64, setting: &str) -> Result<(), UploadError> {
+    if size <= max_mb.saturating_mul(1024 * 1024) {
+        return Ok(());
+    }
+    Err(
+        UploadError::Other(
+            format!(
+                "{:?} is {:.1} MB, larger than the {} MB limit ({})",
+                path,
+                size as f64 / (1024.0 * 1024.0),
+                max_mb,
+                setting
+            )
+        )
+    )
+}
+
+/// Processes a single file and returns the S3 objects it created. Images go
+/// to `stores.images` and everything else to `stores.files`.
+pub async fn process_and_upload_file(
+    stores: &Stores,
+    file_path: &Path,
+    options: &UploadOptions
+) -> Result<ProcessedFile, UploadError> {
+    let file_name = file_name_lossy(file_path)?;
+
+    // Refuse oversized inputs before anything hashes, decodes or uploads them
+    let size = fs::metadata(file_path)?.len();
+    if is_image(file_path) {
+        check_file_size(file_path, size, config::max_file_size_mb(), "MAX_FILE_SIZE_MB")?;
+    } else {
+        check_file_size(file_path, size, config::max_static_file_size_mb(), "MAX_STATIC_FILE_SIZE_MB")?;
+    }
+
+    // An empty file is usually one that hadn't finished copying
+    if size == 0 {
+        return Err(
+            UploadError::InvalidFile(format!("{:?} is empty (0 bytes); it may not have finished copying", file_path))
+        );
+    }
+
+    // Refuse files whose contents don't match their extension
+    check_file_signature(file_path)?;
+
+    // Sanitize the filename
+    let (file_name, token_alt) = split_alt_token(&file_name);
+    let sanitized_name = sanitize_filename(&file_name);
+    let chosen_uid = explicit_uid(file_path, &file_name)?;
+    // A chosen uid is never renamed on collision; the upload fails instead
+    let collision_options = match (&chosen_uid, options.on_collision) {
+        (Some(_), CollisionPolicy::Suffix) => &UploadOptions { on_collision: CollisionPolicy::Error, ..options.clone() },
+        _ => options,
+    };
+    let content_type = content_type_for(file_path);
+    let mut processed = ProcessedFile::default();
+
+    if is_image(file_path) {
+        let store = stores.images.as_ref();
+        // Accessibility text comes from sidecars or an alt= token
+        let text = read_image_text(file_path, token_alt);
+        if options.require_alt && text.alt.is_empty() {
+            return Err(
+                UploadError::Other(
+                    format!(
+                        "{:?} has no alt text (REQUIRE_ALT is set); add a {:?} sidecar or an alt= token to the file name",
+                        file_path,
+                        sidecar_path(file_path, ".alt.txt")
+                    )
+                )
+            );
+        }
+
+        // Debug: Print file size
+        let metadata = fs::metadata(file_path)?;
+        debug!("Original file size: {} bytes", metadata.len());
+
+        // Editors sometimes save a PNG as .jpg; a correctly named copy stands
+        // in so conversion, keys and content types follow the real format
+        let relabelled = match mislabelled_image_extension(file_path) {
+            Some(actual) => {
+                warn!("{:?} is really a .{} image; uploading it as one", file_path, actual);
+                Some(relabel_image(file_path, actual)?)
+            }
+            None => None,
+        };
+        let file_path = relabelled.as_ref().map_or(file_path, TempFile::path);
+
+        // Correct orientation and drop EXIF before anything is derived from the file
+        if !options.dry_run {
+            strip_exif_and_orient(file_path)?;
+        }
+
+        // Skip images whose exact bytes were already uploaded under another
+        // name, unless everything is being regenerated. Hashed after the EXIF
+        // strip rewrites the file, so a photo hashes the same on a rerun.
+        let hash = content_hash(file_path)?;
+        let existing = if options.force_reprocess {
+            None
+        } else {
+            find_image_by_hash(Path::new(IMAGE_DATA_DIR), &hash)
+        };
+        if let Some(existing_uid) = existing {
+            info!("Skipping {:?}: duplicate of existing image {}", file_path, existing_uid);
+            let metadata_path = Path::new(IMAGE_DATA_DIR).join(format!("{}.yml", existing_uid));
+            processed.metadata_path = Some(metadata_path.to_string_lossy().into_owned());
+            processed.uid = existing_uid;
+            return Ok(processed);
+        }
+
+        // TIFF and BMP always become PNG; JPG only when explicitly requested,
+        // since photos stay JPEG. A dry run leaves the source untouched but
+        // reports the PNG keys.
+        let is_jpeg = matches!(
+            file_path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase()).as_deref(),
+            Some("jpg" | "jpeg")
+        );
+        let normalized = needs_png_normalization(file_path);
+        let convert = normalized || (is_jpeg && config::env_flag("CONVERT_JPG_TO_PNG"));
+        // The converted PNG is removed when `converted` drops, on success or error
+        let converted = if convert && !options.dry_run {
+            Some(convert_to_png(file_path)?)
+        } else {
+            None
+        };
+        let source_path = file_path;
+        let file_path = converted.as_ref().map_or(file_path, TempFile::path).to_path_buf();
+
+        // Stamp the watermark on a copy; variants are resized from it, so they
+        // carry it too. Animated GIFs would be flattened, so they're left alone.
+        let watermarked = match Watermark::from_env() {
+            Some(watermark) if !options.dry_run && !is_animated_gif(&file_path) => {
+                Some(watermark_image(&file_path, &watermark)?)
+            }
+            _ => None,
+        };
+        let file_path = watermarked.as_ref().map_or(file_path.as_path(), TempFile::path).to_path_buf();
+
+        // JPEGs re-encoded above are already progressive; one uploaded as it
+        // arrived gets a progressive copy
+        let progressive = if jpeg::progressive_enabled() && !options.dry_run && jpeg::is_sequential(&file_path) {
+            Some(progressive_copy(&file_path)?)
+        } else {
+            None
+        };
+        let file_path = progressive.as_ref().map_or(file_path.as_path(), TempFile::path).to_path_buf();
+
+        // Animated GIFs go up as far smaller animated WebPs when asked for;
+        // the GIF still supplies the dimensions and blurhash
+        let animated_webp = if config::env_flag("GIF_TO_WEBP") && is_animated_gif(&file_path) {
+            match gif_to_webp(&file_path) {
+                Ok(webp) => Some(webp),
+                Err(e) => {
+                    warn!("{}; uploading {:?} unchanged", e, file_path);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let (file_path, gif_source) = match &animated_webp {
+            Some(webp) => (webp.path().to_path_buf(), Some(file_path)),
+            None => (file_path, None),
+        };
+
+        let file_stem = file_stem_lossy(Path::new(&sanitized_name))?;
+        let file_stem = file_stem.as_ref();
+        let file_extension = extension_lossy(&file_path)?;
+        let extension = if convert { "png" } else { file_extension.as_ref() };
+        let content_type = content_type_for(Path::new(&format!("{}.{}", file_stem, extension)));
+
+        // The final stem doubles as the uid, so a renamed key flows into the metadata
+        let chosen = chosen_uid.is_some();
+        let file_stem = chosen_uid.unwrap_or_else(|| options.uid_strategy.uid(file_stem, &hash));
+        let image_prefix = options.key_prefix(&config::s3().image_prefix);
+        let file_stem = resolve_key_collision(store, &image_prefix, &file_stem, extension, collision_options).await?;
+        let file_stem = avoid_shared_metadata(store, Path::new(IMAGE_DATA_DIR), &image_prefix, &file_stem, extension, chosen).await?;
+        let existing = resolve_existing_metadata(
+            store,
+            Path::new(IMAGE_DATA_DIR),
+            &image_prefix,
+            &file_stem,
+            extension,
+            chosen,
+            options
+        ).await?;
+        let Some(file_stem) = existing else {
+            let metadata_path = Path::new(IMAGE_DATA_DIR).join(format!("{}.yml", file_stem));
+            processed.metadata_path = Some(metadata_path.to_string_lossy().into_owned());
+            processed.uid = file_stem;
+            processed.skipped = true;
+            return Ok(processed);
+        };
+        // Held until the file is done, so no other file in flight takes the uid
+        let reservation = reserve_uid(store, Path::new(IMAGE_DATA_DIR), &image_prefix, &file_stem, extension, chosen).await?;
+        let file_stem = reservation.uid();
+
+        // Read and validate image dimensions
+        let img = open_image(gif_source.as_deref().unwrap_or(&file_path))?;
+        let (width, height) = (img.width(), img.height());
+        debug!("Original image dimensions: {}x{}", width, height);
+        processed.uid = file_stem.to_string();
+        processed.width = Some(width);
+        processed.height = Some(height);
+        let blurhash = compute_blurhash(&img).unwrap_or_default();
+
+        check_min_dimension(Path::new(&file_name), width, height, config::min_dimension())?;
+
+        // Recompress before upload; a dry run leaves the source untouched
+        if !options.dry_run {
+            optimize_png(&file_path);
+        }
+
+        // Upload the original file first
+        let original_s3_key = format!("{}{}.{}", image_prefix, file_stem, extension);
+        let original = upload_or_report(
+            store,
+            &file_path,
+            &original_s3_key,
+            content_type,
+            config::original_storage_class(),
+            &config::object_tagging(&[("type", "image"), ("role", "original")]),
+            options
+        ).await?;
+        info!("Uploaded original file to S3: {}", original_s3_key);
+        processed.original_url = Some(original.url.clone());
+        processed.objects.push(original);
+
+        // Optionally keep the TIFF/BMP as an archival master next to the PNG
+        if normalized && config::env_flag("ARCHIVE_ORIGINALS") {
+            let source_ext = extension_lossy(source_path)?.to_lowercase();
+            let master_key = format!("{}{}.{}", image_prefix, file_stem, source_ext);
+            let master = upload_or_report(
+                store,
+                source_path,
+                &master_key,
+                content_type_for(source_path),
+                config::original_storage_class(),
+                &config::object_tagging(&[("type", "image"), ("role", "master")]),
+                options
+            ).await?;
+            info!("Uploaded archival master to S3: {}", master_key);
+            processed.objects.push(master);
+        }
+
+        // Resizing would flatten an animation to its first frame, so animated
+        // GIFs (and the WebPs made from them) are served as uploaded
+        let animated = gif_source.is_some() || is_animated_gif(&file_path);
+        let (mut variants, mut thumbnail) = if animated {
+            (Vec::new(), None)
+        } else {
+            (
+                variants::plan_variants(width, height, config::env_flag("EMIT_RETINA")),
+                variants::plan_thumbnail(width, height, config::thumbnail_size()),
+            )
+        };
+        // Versioned keys let a CDN cache variants forever; the metadata
+        // written below points at the new ones
+        if config::env_flag("HASHED_FILENAMES") {
+            let short_hash = &hash[..FILENAME_HASH_LEN];
+            for variant in &mut variants {
+                variant.hash = Some(short_hash.to_string());
+            }
+            if let Some(thumbnail) = &mut thumbnail {
+                thumbnail.hash = Some(short_hash.to_string());
+            }
+        }
+
+        if animated {
+            info!("Skipping variants for animated GIF {:?}; uploaded unmodified", file_path);
+        }
+
+        // Then process and upload resized versions, recording the dimensions
+        // each one actually came out at
+        let working_dir = Path::new(WORKING_IMAGES_DIR);
+        fs::create_dir_all(working_dir)?;
+        let mut generated = Vec::new();
+        for (variant, object) in upload_variants(
+            store,
+            &file_path,
+            working_dir,
+            file_stem,
+            extension,
+            &variants,
+            options
+        ).await? {
+            processed.variant_urls.push(object.url.clone());
+            processed.objects.push(object);
+            generated.push(variant);
+        }
+
+        if let Some(thumbnail) = &thumbnail {
+            let output_filename = format!("{}{}.{}", file_stem, thumbnail.suffix(), extension);
+            let output = TempFile::new(working_dir.join(&output_filename));
+            square_thumbnail(&file_path, output.path(), thumbnail.size, config::variant_quality())?;
+            optimize_png(output.path());
+
+            let s3_key = format!("{}{}", image_prefix, output_filename);
+            let uploaded = upload_or_report(
+                store,
+                output.path(),
+                &s3_key,
+                content_type,
+                config::variant_storage_class(),
+                &config::object_tagging(&[("type", "image"), ("role", "variant")]),
+                options
+            ).await?;
+            info!("Uploaded thumbnail to S3: {}", s3_key);
+            processed.variant_urls.push(uploaded.url.clone());
+            processed.objects.push(uploaded);
+            finish_intermediate(output, options);
+        }
+
+        // Metadata goes last so it describes what was actually uploaded
+        if options.dry_run {
+            info!("[dry run] Would write metadata to {}/{}.yml", IMAGE_DATA_DIR, file_stem);
+        } else if options.skip_metadata {
+            debug!("SKIP_METADATA is set; not writing {}/{}.yml", IMAGE_DATA_DIR, file_stem);
+        } else {
+            debug!("Generating metadata for image - dimensions: {}x{}", width, height);
+            let metadata_path = write_image_metadata(&ImageRecord {
+                prefix: &image_prefix,
+                uid: file_stem,
+                width,
+                height,
+                format: extension,
+                hash: &hash,
+                blurhash: &blurhash,
+                text: &text,
+                variants: &generated,
+                thumbnail: thumbnail.as_ref(),
+            })?;
+            processed.metadata_path = Some(metadata_path.to_string_lossy().into_owned());
+        }
+
+        for intermediate in [relabelled, converted, watermarked, progressive, animated_webp].into_iter().flatten() {
+            finish_intermediate(intermediate, options);
+        }
+    } else {
+        // For non-image files, upload directly under the static prefix
+        let store = stores.files.as_ref();
+        let file_stem = file_stem_lossy(Path::new(&sanitized_name))?;
+        let file_stem = file_stem.as_ref();
+        let extension = extension_lossy(Path::new(&sanitized_name))?;
+        let extension = extension.as_ref();
+        // Static files can be large, so they're only hashed when the uid needs it
+        let chosen = chosen_uid.is_some();
+        let file_stem = match (chosen_uid, options.uid_strategy) {
+            (Some(uid), _) => uid,
+            (None, UidStrategy::Name) => file_stem.to_string(),
+            (None, strategy) => strategy.uid(file_stem, &content_hash(file_path)?),
+        };
+        let static_prefix = options.key_prefix(&config::s3().static_prefix);
+        let file_stem = resolve_key_collision(store, &static_prefix, &file_stem, extension, collision_options).await?;
+        let file_stem = avoid_shared_metadata(store, Path::new(FILE_DATA_DIR), &static_prefix, &file_stem, extension, chosen).await?;
+        let reservation = reserve_uid(store, Path::new(FILE_DATA_DIR), &static_prefix, &file_stem, extension, chosen).await?;
+        let file_stem = reservation.uid();
+
+        let s3_key = format!("{}{}.{}", static_prefix, file_stem, extension);
+        info!("Uploading non-image file to S3: {}", s3_key);
+        let tagging = config::object_tagging(&[("type", "file"), ("role", "original")]);
+        let uploaded = upload_or_report(store, file_path, &s3_key, content_type, StorageClass::Standard, &tagging, options).await?;
+        processed.original_url = Some(uploaded.url.clone());
+        processed.objects.push(uploaded);
+
+        let thumbnail = if extension.eq_ignore_ascii_case("pdf") && config::env_flag("PDF_THUMBNAILS") {
+            upload_pdf_thumbnail(store, file_path, file_stem, options).await
+        } else {
+            None
+        };
+        if let Some(thumbnail) = &thumbnail {
+            processed.variant_urls.push(thumbnail.url.clone());
+        }
+        let thumbnail_key = thumbnail.as_ref().map(|thumbnail| thumbnail.key.clone());
+        processed.objects.extend(thumbnail);
+
+        // Generate metadata for the file
+        if options.dry_run {
+            info!("[dry run] Would write metadata to data/files/{}.yml", file_stem);
+        } else if options.skip_metadata {
+            debug!("SKIP_METADATA is set; not writing data/files/{}.yml", file_stem);
+        } else {
+            let metadata_path = write_file_metadata(&static_prefix, file_stem, extension, thumbnail_key.as_deref())?;
+            processed.metadata_path = Some(metadata_path.to_string_lossy().into_owned());
+        }
+        processed.uid = file_stem.to_string();
+    }
+
+    Ok(processed)
+}
+
+/// Renders the PDF's first page and uploads it as `{static_prefix}{uid}-thumb.png`,
+/// partitioned like the PDF itself.
+/// The preview is optional, so a failure is logged and the upload goes on
+/// without it. A dry run reports the key without rendering.
+async fn upload_pdf_thumbnail(
+    store: &dyn ObjectStore,
+    file_path: &Path,
+    file_stem: &str,
+    options: &UploadOptions
+) -> Option<UploadedObject> {
+    let output_filename = format!("{}-thumb.png", file_stem);
+    let s3_key = format!("{}{}", options.key_prefix(&config::s3().static_prefix), output_filename);
+    if options.dry_run {
+        info!("[dry run] Would render and upload a PDF thumbnail to S3 key: {}", s3_key);
+        return None;
+    }
+
+    let result = async {
+        // Named per process so concurrent runs rendering the same file don't collide
+        let output = TempFile::new(env::temp_dir().join(format!("file-upload-{}-{}", std::process::id(), output_filename)));
+        pdf::render_first_page(file_path, output.path(), pdf::PDF_THUMBNAIL_WIDTH)?;
+        optimize_png(output.path());
+        let tagging = config::object_tagging(&[("type", "file"), ("role", "variant")]);
+        let uploaded = upload_to_s3(store, output.path(), &s3_key, Some("image/png"), StorageClass::Standard, &tagging, options.force_reprocess).await?;
+        finish_intermediate(output, options);
+        Ok::<_, UploadError>(uploaded)
+    }.await;
+
+    match result {
+        Ok(uploaded) => {
+            info!("Uploaded PDF thumbnail to S3: {}", s3_key);
+            Some(uploaded)
+        }
+        Err(e) => {
+            warn!("Skipping thumbnail for {:?}: {}", file_path, e);
+            None
+        }
+    }
+}
+
+/// Resizes `file_path` to each planned variant under `working_dir` and
+/// uploads it as `{image_prefix}{partition}{stem}{suffix}.{extension}`, returning each
+/// variant with the dimensions its file actually has. Each resized file is
+/// removed once its upload finishes or fails.
+async fn upload_variants(
+    store: &dyn ObjectStore,
+    file_path: &Path,
+    working_dir: &Path,
+    file_stem: &str,
+    extension: &str,
+    variants: &[PlannedVariant],
+    options: &UploadOptions
+) -> Result<Vec<(PlannedVariant, UploadedObject)>, UploadError> {
+    let content_type = content_type_for(Path::new(&format!("{}.{}", file_stem, extension)));
+    let quality = config::variant_quality();
+    let storage_class = config::variant_storage_class();
+    let tagging = config::object_tagging(&[("type", "image"), ("role", "variant")]);
+    let image_prefix = options.key_prefix(&config::s3().image_prefix);
+    let mut uploaded = Vec::new();
+    for variant in variants {
+        let output_filename = format!("{}{}.{}", file_stem, variant.suffix(), extension);
+        // Removed when this iteration ends, including when resizing or the upload fails
+        let output = TempFile::new(working_dir.join(&output_filename));
+        let output_path = output.path();
+
+        let mut variant = variant.clone();
+        match variant.crop.as_mut() {
+            Some(region) => {
+                crop_and_resize(file_path, output_path, region, variant.width, variant.height, quality)?;
+                debug!("Cropped {} variant to {}x{} at ({}, {})", variant.name, region.width, region.height, region.x, region.y);
+            }
+            // The plan already sized the variant, so fit it to that box
+            None => resize_image(file_path, output_path, ResizeMode::Fit, variant.width, Some(variant.height), quality)?,
+        }
+        optimize_png(output_path);
+
+        if let Ok((width, height)) = image::image_dimensions(output_path) {
+            debug!("Resized image dimensions for {} variant at {}x: {}x{}", variant.name, variant.density, width, height);
+            variant.width = width;
+            variant.height = height;
+        }
+
+        let s3_key = format!("{}{}", image_prefix, output_filename);
+        let object = upload_or_report(store, output_path, &s3_key, content_type, storage_class.clone(), &tagging, options).await?;
+        info!("Uploaded resized file to S3: {}", s3_key);
+        uploaded.push((variant, object));
+        finish_intermediate(output, options);
+    }
+    Ok(uploaded)
+}
+
+/// Writes the run summary to `upload-manifest.json` for the deploy pipeline.
+fn write_manifest(summary: &UploadSummary) -> Result<(), UploadError> {
+    let json = serde_json::to_string_pretty(summary)
+        .map_err(|e| UploadError::Other(e.to_string()))?;
+    fs::write(MANIFEST_FILE, json)?;
+    info!("Wrote upload manifest to {}", MANIFEST_FILE);
+    Ok(())
+}
+
+/// The files in `dir` the pipeline would upload, skipping placeholders,
+/// sidecars and unsupported types.
+fn uploadable_files(dir: &Path) -> Result<Vec<PathBuf>, UploadError> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && !is_placeholder(path) && !is_sidecar(path))
+        .filter(|path| {
+            let valid = is_valid_file_type(path);
+            if !valid {
+                warn!("Skipping {:?}: its file type isn't allowed (ALLOWED_EXTENSIONS/BLOCKED_EXTENSIONS)", path);
+            }
+            valid
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Processes each file, adding its outcome to `summary` and passing it to
+/// `on_progress`. With `remove_sources`, a file and its sidecars are deleted
+/// once it has been uploaded.
+async fn process_files(
+    stores: &Stores,
+    files: &[PathBuf],
+    options: &UploadOptions,
+    remove_sources: bool,
+    on_progress: &(impl Fn(&FileResult) + Sync),
+    summary: &mut UploadSummary
+) {
+    summary.total += files.len();
+
+    for path in files {
+        let result = match process_and_upload_file(stores, path, options).await {
+            Ok(file) => {
+                if file.skipped {
+                    summary.skipped += 1;
+                    info!("Skipped {:?}; it stays where it is", path);
+                } else {
+                    summary.processed += 1;
+                    info!("Successfully processed and uploaded: {:?}", path);
+                }
+                // Inbox files are removed after a successful upload; explicit
+                // paths belong to the caller and are left in place, as are
+                // skipped files so the clash with existing metadata can be sorted out
+                let remove_sources = remove_sources && !file.skipped;
+                if remove_sources && options.dry_run {
+                    info!("[dry run] Would remove {:?}", path);
+                } else if remove_sources && options.keep_working {
+                    info!("Keeping {:?}", path);
+                } else if remove_sources {
+                    if let Err(e) = fs::remove_file(path) {
+                        warn!("Error removing file {:?}: {}", path, e);
+                    }
+                    for suffix in SIDECAR_SUFFIXES {
+                        let _ = fs::remove_file(sidecar_path(path, suffix));
+                    }
+                }
+                FileResult {
+                    path: path.to_string_lossy().into_owned(),
+                    uid: Some(file.uid),
+                    width: file.width,
+                    height: file.height,
+                    original_url: file.original_url,
+                    variant_urls: file.variant_urls,
+                    metadata_path: file.metadata_path,
+                    bytes_uploaded: file.objects.iter().map(|object| object.bytes).sum(),
+                    objects: file.objects,
+                    skipped: file.skipped,
+                    error: None,
+                    error_kind: None,
+                }
+            }
+            Err(e) => {
+                summary.failed += 1;
+                error!("Error processing file {:?}: {}", path, e);
+                // Continue with the next file
+                FileResult {
+                    path: path.to_string_lossy().into_owned(),
+                    uid: None,
+                    width: None,
+                    height: None,
+                    original_url: None,
+                    variant_urls: Vec::new(),
+                    metadata_path: None,
+                    objects: Vec::new(),
+                    bytes_uploaded: 0,
+                    skipped: false,
+                    error: Some(e.to_string()),
+                    error_kind: Some(e.kind()),
+                }
+            }
+        };
+        on_progress(&result);
+        summary.bytes_uploaded += result.bytes_uploaded;
+        summary.files.push(result);
+    }
+}
+
+/// Processes one file, or every uploadable file directly inside a directory,
+/// without the inbox convention: nothing is moved into working directories
+/// and the given files are left in place afterwards.
+pub async fn process_and_upload_path(
+    path: &Path,
+    options: &UploadOptions,
+    on_progress: impl Fn(&FileResult) + Sync
+) -> Result<UploadSummary, UploadError> {
+    let files = if path.is_dir() {
+        uploadable_files(path)?
+    } else if path.is_file() {
+        if !is_valid_file_type(path) {
+            return Err(UploadError::InvalidFile(format!("{:?} is not a supported file type", path)));
+        }
+        vec![path.to_path_buf()]
+    } else {
+        return Err(UploadError::Other(format!("{:?} does not exist", path)));
+    };
+    info!("Found {} valid files in {:?}.", files.len(), path);
+    if options.dry_run {
+        info!("Dry run: nothing will be uploaded.");
+    }
+
+    let stores = stores_for_run(options).await?;
+    let mut summary = UploadSummary::default();
+    process_files(&stores, &files, options, false, &on_progress, &mut summary).await;
+    Ok(summary)
+}
+
+/// Runs the whole inbox pipeline, calling `on_progress` as each file finishes.
+pub async fn process_and_upload_all(
+    options: &UploadOptions,
+    on_progress: impl Fn(&FileResult) + Sync
+) -> Result<UploadSummary, UploadError> {
+    info!("Starting file upload process...");
+    if options.dry_run {
+        info!("Dry run: nothing will be uploaded or deleted.");
+    }
+
+    // First, move files from inbox to working directories
+    let inbox_found = prepare_working_directories(options)?;
+
+    let image_dir = Path::new(WORKING_IMAGES_DIR);
+    let file_dir = Path::new(WORKING_FILES_DIR);
+    let stores = stores_for_run(options).await?;
+
+    let mut summary = UploadSummary { inbox_missing: !inbox_found, ..UploadSummary::default() };
+
+    for dir in &[image_dir, file_dir] {
+        if !dir.exists() {
+            warn!("Working directory not found at {:?}", dir);
+            continue;
+        }
+
+        // Subfolders only exist when PRESERVE_INBOX_PATHS kept them
+        for (subfolder, files) in working_files(dir)? {
+            if files.is_empty() {
+                continue;
+            }
+            info!("Found {} valid files in {:?}.", files.len(), dir.join(&subfolder));
+            // Whatever doesn't fit in the batch waits in the working directory
+            let room = options.batch_limit.map_or(files.len(), |limit| limit.saturating_sub(summary.total));
+            let (batch, rest) = files.split_at(room.min(files.len()));
+            summary.remaining += rest.len();
+            if batch.is_empty() {
+                continue;
+            }
+            process_files(&stores, batch, &options.in_subfolder(&subfolder), true, &on_progress, &mut summary).await;
+        }
+    }
+
+    if summary.remaining > 0 {
+        info!(
+            "Batch limit of {} reached; {} files left for the next run",
+            options.batch_limit.unwrap_or_default(),
+            summary.remaining
+        );
+    }
+
+    // Cleanup: remove working directories
+    let directories_to_remove = [
+        Path::new("content/uploads/_working-images"),
+        Path::new("content/uploads/_working-files"),
+    ];
+
+    for dir in &directories_to_remove {
+        if dir.exists() {
+            if summary.remaining > 0 {
+                debug!("Keeping directory {:?} for the rest of the batch", dir);
+            } else if options.dry_run {
+                info!("[dry run] Would remove directory {:?}", dir);
+            } else if options.keep_working {
+                info!("Keeping working directory {:?}", dir);
+            } else if let Err(e) = fs::remove_dir_all(dir) {
+                warn!("Error removing directory {:?}: {}", dir, e);
+            }
+        }
+    }
+
+    if options.dry_run {
+        info!("[dry run] Skipping upload manifest");
+    } else if let Err(e) = write_manifest(&summary) {
+        error!("Error writing upload manifest: {}", e);
+    }
+
+    info!("Upload process completed successfully.");
+    if summary.total == 0 {
+        info!("No valid files to process.");
+    } else {
+        info!(
+            "Successfully processed and uploaded {} out of {} files.",
+            summary.processed,
+            summary.total
+        );
+    }
+
+    Ok(summary)
+}
+
+/// Outcome of `rebuild_metadata`. One unreadable object doesn't stop the others.
+#[derive(Debug, Default, Serialize)]
+pub struct RebuildSummary {
+    /// Metadata files written.
+    pub written: Vec<String>,
+    /// uids whose `data/images/{uid}.yml` already existed and was left alone.
+    pub skipped: Vec<String>,
+    /// `key: error` for each original that couldn't be read.
+    pub errors: Vec<String>,
+}
+
+/// Splits an original's key into its prefix (partition included), uid and
+/// format, or `None` for variants, thumbnails and anything that isn't an image.
+fn original_key_parts(key: &str) -> Option<(&str, &str, &str)> {
+    let file_name = key.rsplit_once('/').map_or(key, |(_, file_name)| file_name);
+    let (uid, format) = file_name.rsplit_once('.')?;
+    if uid.is_empty() || !is_image(Path::new(file_name)) || mount_s3::owner_uid(file_name) != uid {
+        return None;
+    }
+    Some((&key[..key.len() - file_name.len()], uid, format))
+}
+
+/// Reads an object only as far as its header, returning the pixel dimensions
+/// once the decoder can tell them.
+async fn object_dimensions(store: &dyn ObjectStore, key: &str) -> Result<(u32, u32), UploadError> {
+    use futures::TryStreamExt;
+
+    let mut body = store.get(key).await?.body;
+    let mut header = Vec::new();
+    while let Some(chunk) = body.try_next().await.map_err(|e| UploadError::Other(e.to_string()))? {
+        header.extend_from_slice(&chunk);
+        let reader = image::io::Reader::new(Cursor::new(&header)).with_guessed_format()?;
+        if let Ok(dimensions) = reader.into_dimensions() {
+            return Ok(dimensions);
+        }
+    }
+    let reader = image::io::Reader::new(Cursor::new(&header)).with_guessed_format()?;
+    Ok(reader.into_dimensions()?)
+}
+
+/// Recreates missing `data/images/{uid}.yml` files from the originals in
+/// `store`, treating the bucket as the source of truth. Existing metadata is
+/// never overwritten. Only each original's header is downloaded, so `hash` and
+/// `blurhash` are left blank, as are alt, caption and credit for editors to
+/// refill; variants and thumbnails are listed when their objects exist.
+async fn rebuild_metadata_from(store: &dyn ObjectStore) -> Result<RebuildSummary, UploadError> {
+    let s3 = config::s3();
+    let keys: Vec<String> = store
+        .list(&s3.image_prefix)
+        .await?
+        .into_iter()
+        // An empty image prefix lists the whole bucket, static files included
+        .filter(|key| s3.static_prefix.is_empty() || !key.starts_with(&s3.static_prefix))
+        .collect();
+    let existing: std::collections::HashSet<&str> = keys.iter().map(String::as_str).collect();
+
+    let mut summary = RebuildSummary::default();
+    for key in &keys {
+        let Some((prefix, uid, format)) = original_key_parts(key) else {
+            continue;
+        };
+        if Path::new(IMAGE_DATA_DIR).join(format!("{}.yml", uid)).exists() {
+            debug!("Keeping existing metadata for {}", uid);
+            summary.skipped.push(uid.to_string());
+            continue;
+        }
+
+        let (width, height) = match object_dimensions(store, key).await {
+            Ok(dimensions) => dimensions,
+            Err(e) => {
+                error!("Error reading {}: {}", key, e);
+                summary.errors.push(format!("{}: {}", key, e));
+                continue;
+            }
+        };
+        let exists = |suffix: String| existing.contains(format!("{}{}{}.{}", prefix, uid, suffix, format).as_str());
+        let variants: Vec<PlannedVariant> = variants::plan_variants(width, height, true)
+            .into_iter()
+            .filter(|variant| exists(variant.suffix()))
+            .collect();
+        let thumbnail = variants::plan_thumbnail(width, height, config::thumbnail_size())
+            .filter(|thumbnail| exists(thumbnail.suffix()));
+
+        let path = write_image_metadata(&ImageRecord {
+            prefix,
+            uid,
+            width,
+            height,
+            format,
+            hash: "",
+            blurhash: "",
+            text: &ImageText::default(),
+            variants: &variants,
+            thumbnail: thumbnail.as_ref(),
+        })?;
+        info!("Rebuilt {} from {}", path.display(), key);
+        summary.written.push(path.to_string_lossy().into_owned());
+    }
+
+    info!(
+        "Rebuilt {} metadata files, kept {}, {} failed",
+        summary.written.len(),
+        summary.skipped.len(),
+        summary.errors.len()
+    );
+    Ok(summary)
+}
+
+/// Writes `data/files/{uid}.yml` for a static file uploaded outside the
+/// pipeline, such as through a presigned PUT, once it's in the file bucket.
+pub async fn record_file_upload(key: &str) -> Result<PathBuf, UploadError> {
+    if is_image(Path::new(key)) {
+        return Err(UploadError::Other(format!("{} is an image; run rebuild_metadata() for images", key)));
+    }
+    let stores = Stores::from_env().await?;
+    if stores.files.head(key).await?.is_none() {
+        return Err(UploadError::Other(format!("{} isn't in the file bucket", key)));
+    }
+
+    let (prefix, name) = match key.rsplit_once('/') {
+        Some((folder, name)) => (&key[..=folder.len()], name),
+        None => ("", key),
+    };
+    let Some((uid, format)) = name.rsplit_once('.') else {
+        return Err(UploadError::InvalidFile(format!("{} has no file extension", key)));
+    };
+    write_file_metadata(prefix, uid, format, None)
+}
+
+/// Regenerates lost `data/images` metadata from the image bucket.
+pub async fn rebuild_metadata() -> Result<RebuildSummary, UploadError> {
+    let stores = Stores::from_env().await?;
+    rebuild_metadata_from(stores.images.as_ref()).await
+}
+
+/// Converts a single file result into a JS object.
+fn file_result_to_js<'a, C: Context<'a>>(cx: &mut C, file: &FileResult) -> JsResult<'a, JsObject> {
+    let file_obj = cx.empty_object();
+
+    let path = cx.string(&file.path);
+    file_obj.set(cx, "path", path)?;
+
+    let status = cx.string(match (&file.error, file.skipped) {
+        (Some(_), _) => "error",
+        (None, true) => "skipped",
+        (None, false) => "success",
+    });
+    file_obj.set(cx, "status", status)?;
+
+    let keys = cx.empty_array();
+    for (j, object) in file.objects.iter().enumerate() {
+        let key = cx.string(&object.key);
+        keys.set(cx, j as u32, key)?;
+    }
+    file_obj.set(cx, "keys", keys)?;
+
+    let url: Handle<JsValue> = match &file.original_url {
+        Some(url) => cx.string(url).upcast(),
+        None => cx.null().upcast(),
+    };
+    file_obj.set(cx, "url", url)?;
+
+    let variant_urls = strings_to_js(cx, &file.variant_urls)?;
+    file_obj.set(cx, "variant_urls", variant_urls)?;
+
+    let metadata_path: Handle<JsValue> = match &file.metadata_path {
+        Some(path) => cx.string(path).upcast(),
+        None => cx.null().upcast(),
+    };
+    file_obj.set(cx, "metadata_path", metadata_path)?;
+
+    let bytes_uploaded = cx.number(file.bytes_uploaded as f64);
+    file_obj.set(cx, "bytes_uploaded", bytes_uploaded)?;
+
+    let error: Handle<JsValue> = match &file.error {
+        Some(e) => cx.string(e).upcast(),
+        None => cx.null().upcast(),
+    };
+    file_obj.set(cx, "error", error)?;
+
+    let error_kind: Handle<JsValue> = match file.error_kind {
+        Some(kind) => cx.string(kind).upcast(),
+        None => cx.null().upcast(),
+    };
+    file_obj.set(cx, "error_kind", error_kind)?;
+
+    Ok(file_obj)
+}
+
+/// Converts an upload summary into the plain JS object returned to Node.
+fn summary_to_js<'a, C: Context<'a>>(cx: &mut C, summary: &UploadSummary) -> JsResult<'a, JsObject> {
+    let obj = cx.empty_object();
+
+    let processed = cx.number(summary.processed as f64);
+    obj.set(cx, "processed", processed)?;
+    let total = cx.number(summary.total as f64);
+    obj.set(cx, "total", total)?;
+    let failed = cx.number(summary.failed as f64);
+    obj.set(cx, "failed", failed)?;
+    let skipped = cx.number(summary.skipped as f64);
+    obj.set(cx, "skipped", skipped)?;
+    let inbox_missing = cx.boolean(summary.inbox_missing);
+    obj.set(cx, "inbox_missing", inbox_missing)?;
+    let bytes_uploaded = cx.number(summary.bytes_uploaded as f64);
+    obj.set(cx, "bytes_uploaded", bytes_uploaded)?;
+    let remaining = cx.number(summary.remaining as f64);
+    obj.set(cx, "remaining", remaining)?;
+
+    let files = cx.empty_array();
+    for (i, file) in summary.files.iter().enumerate() {
+        let file_obj = file_result_to_js(cx, file)?;
+        files.set(cx, i as u32, file_obj)?;
+    }
+    obj.set(cx, "files", files)?;
+
+    Ok(obj)
+}
+
+/// Throws a JS `Error` carrying the upload error's category as `kind`.
+fn throw_upload_error<'a, C: Context<'a>, T>(cx: &mut C, err: &UploadError) -> NeonResult<T> {
+    let js_err = cx.error(err.to_string())?;
+    let kind = cx.string(err.kind());
+    js_err.set(cx, "kind", kind)?;
+    cx.throw(js_err)
+}
+
+/// The argument at `index`, with `undefined` and `null` treated as left out,
+/// so JS callers can skip an optional argument explicitly.
+fn optional_arg<'a>(cx: &mut FunctionContext<'a>, index: usize) -> Option<Handle<'a, JsValue>> {
+    let arg = cx.argument_opt(index)?;
+    if arg.is_a::<JsUndefined, _>(cx) || arg.is_a::<JsNull, _>(cx) {
+        return None;
+    }
+    Some(arg)
+}
+
+/// Runs the inbox pipeline, or just `path` when given, in the background and
+/// resolves with the `{processed, total, failed, skipped, inbox_missing,
+/// bytes_uploaded, remaining, files}` summary, where `remaining` counts files
+/// `BATCH_LIMIT` left for another run. The optional callback at
+/// `callback_index` receives each file's result as it finishes.
+fn spawn_upload<'a>(
+    cx: &mut FunctionContext<'a>,
+    options: UploadOptions,
+    path: Option<PathBuf>,
+    callback_index: usize
+) -> JsResult<'a, JsPromise> {
+    let callback = match optional_arg(cx, callback_index) {
+        Some(arg) => Some(Arc::new(arg.downcast_or_throw::<JsFunction, _>(cx)?.root(cx))),
+        None => None,
+    };
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+
+    runtime().spawn(async move {
+        let progress_channel = channel.clone();
+        let on_progress = move |file: &FileResult| {
+            let Some(callback) = callback.clone() else {
+                return;
+            };
+            let file = file.clone();
+            progress_channel.send(move |mut cx| {
+                let callback = callback.to_inner(&mut cx);
+                let this = cx.undefined();
+                let arg = file_result_to_js(&mut cx, &file)?;
+                callback.call(&mut cx, this, [arg.upcast()])?;
+                Ok(())
+            });
+        };
+
+        let result = match path {
+            Some(path) => process_and_upload_path(&path, &options, on_progress).await,
+            None => process_and_upload_all(&options, on_progress).await,
+        };
+        deferred.settle_with(&channel, move |mut cx| match result {
+            Ok(summary) => summary_to_js(&mut cx, &summary),
+            Err(e) => throw_upload_error(&mut cx, &e),
+        });
+    });
+
+    Ok(promise)
+}
+
+fn process_and_upload_js(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let options = UploadOptions::from_env();
+    spawn_upload(&mut cx, options, None, 0)
+}
+
+fn upload_dry_run_js(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let mut options = UploadOptions::from_env();
+    options.dry_run = true;
+    spawn_upload(&mut cx, options, None, 0)
+}
+
+/// `upload_path(path, onProgress?)`: uploads one file or the files in one
+/// directory, bypassing the inbox.
+fn upload_path_js(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let path = PathBuf::from(cx.argument::<JsString>(0)?.value(&mut cx));
+    let options = UploadOptions::from_env();
+    spawn_upload(&mut cx, options, Some(path), 1)
+}
+
+fn download_summary_to_js<'a, C: Context<'a>>(cx: &mut C, summary: &DownloadSummary) -> JsResult<'a, JsObject> {
+    let summary_obj = cx.empty_object();
+    let succeeded = cx.number(summary.succeeded as f64);
+    summary_obj.set(cx, "succeeded", succeeded)?;
+    let failed = cx.number(summary.failed as f64);
+    summary_obj.set(cx, "failed", failed)?;
+    let bytes = cx.number(summary.bytes as f64);
+    summary_obj.set(cx, "bytes", bytes)?;
+    let errors = strings_to_js(cx, &summary.errors)?;
+    summary_obj.set(cx, "errors", errors)?;
+    Ok(summary_obj)
+}
+
+/// Downloads every image named in `data/images` into `LOCAL_IMAGE_DIR`.
+pub async fn download_all_images() -> Result<DownloadSummary, MountError> {
+    info!("Creating S3 mount...");
+    let mount = S3Mount::new().await?;
+    mount.create_local_dir(LOCAL_IMAGE_DIR)?;
+
+    let downloads = S3Mount::get_image_metadata()
+        .into_iter()
+        .map(|image| {
+            let local_path = format!("{}/{}", LOCAL_IMAGE_DIR, image.key);
+            (image.key, local_path)
+        })
+        .collect();
+
+    Ok(mount.download_all(downloads).await)
+}
+
+/// `mkdir_and_download_files()`: downloads every image named in `data/images`
+/// and returns whether the mount could be set up, as it always has; failed
+/// downloads are only logged. `download_images()` reports them.
+fn mkdir_and_download_all_images_from_s3(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let result = runtime().block_on(download_all_images());
+    if let Err(e) = &result {
+        error!("Error downloading images: {}", e);
+    }
+    Ok(cx.boolean(result.is_ok()))
+}
+
+/// `download_images()`: downloads every image named in `data/images` and
+/// returns `{succeeded, failed, bytes, errors}`.
+fn download_images_js(mut cx: FunctionContext) -> JsResult<JsObject> {
+    match runtime().block_on(download_all_images()) {
+        Ok(summary) => download_summary_to_js(&mut cx, &summary),
+        Err(e) => cx.throw_error(e.to_string()),
+    }
+}
+
+fn delete_by_uid_js(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let uid = cx.argument::<JsString>(0)?.value(&mut cx);
+
+    let result = runtime().block_on(async {
+        dotenv().ok();
+        let upload = S3Upload::new().await?;
+        upload.delete(&uid).await
+    });
+
+    match result {
+        Ok(count) => Ok(cx.number(count as f64)),
+        Err(e) => throw_upload_error(&mut cx, &e),
+    }
+}
+
+fn strings_to_js<'a, C: Context<'a>>(cx: &mut C, values: &[String]) -> JsResult<'a, JsArray> {
+    let array = cx.empty_array();
+    for (i, value) in values.iter().enumerate() {
+        let value = cx.string(value);
+        array.set(cx, i as u32, value)?;
+    }
+    Ok(array)
+}
+
+/// `reconcile()`: compares `data/images` metadata with the bucket and returns
+/// `{missing, orphaned, matched}` arrays of keys.
+fn reconcile_js(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let result = runtime().block_on(async {
+        let mount = S3Mount::new().await?;
+        mount.reconcile().await
+    });
+
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+
+    let report_obj = cx.empty_object();
+    let missing = strings_to_js(&mut cx, &report.missing)?;
+    report_obj.set(&mut cx, "missing", missing)?;
+    let orphaned = strings_to_js(&mut cx, &report.orphaned)?;
+    report_obj.set(&mut cx, "orphaned", orphaned)?;
+    let matched = strings_to_js(&mut cx, &report.matched)?;
+    report_obj.set(&mut cx, "matched", matched)?;
+    Ok(report_obj)
+}
+
+/// `rebuild_metadata()`: recreates missing `data/images` files from the
+/// bucket and returns `{written, skipped, errors}` arrays.
+fn rebuild_metadata_js(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let summary = match runtime().block_on(rebuild_metadata()) {
+        Ok(summary) => summary,
+        Err(e) => return throw_upload_error(&mut cx, &e),
+    };
+
+    let summary_obj = cx.empty_object();
+    let written = strings_to_js(&mut cx, &summary.written)?;
+    summary_obj.set(&mut cx, "written", written)?;
+    let skipped = strings_to_js(&mut cx, &summary.skipped)?;
+    summary_obj.set(&mut cx, "skipped", skipped)?;
+    let errors = strings_to_js(&mut cx, &summary.errors)?;
+    summary_obj.set(&mut cx, "errors", errors)?;
+    Ok(summary_obj)
+}
+
+/// Mirrors everything under `prefix` into `local_dir` for the
+/// `download_prefix` exports.
+fn download_prefix_blocking(cx: &mut FunctionContext) -> NeonResult<Result<DownloadSummary, MountError>> {
+    let prefix = cx.argument::<JsString>(0)?.value(cx);
+    let local_dir = cx.argument::<JsString>(1)?.value(cx);
+
+    Ok(runtime().block_on(async {
+        let mount = S3Mount::for_prefix(&prefix).await?;
+        mount.create_local_dir(&local_dir)?;
+        mount.download_prefix(&prefix, &local_dir).await
+    }))
+}
+
+/// `download_prefix(prefix, localDir)`: mirrors everything under an S3 prefix
+/// into a local directory and returns the number of objects downloaded.
+fn download_prefix_js(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    match download_prefix_blocking(&mut cx)? {
+        Ok(summary) => Ok(cx.number(summary.succeeded as f64)),
+        Err(e) => cx.throw_error(e.to_string()),
+    }
+}
+
+/// `download_prefix_summary(prefix, localDir)`: like `download_prefix`, but
+/// returns `{succeeded, failed, bytes, errors}`.
+fn download_prefix_summary_js(mut cx: FunctionContext) -> JsResult<JsObject> {
+    match download_prefix_blocking(&mut cx)? {
+        Ok(summary) => download_summary_to_js(&mut cx, &summary),
+        Err(e) => cx.throw_error(e.to_string()),
+    }
+}
+
+/// How long a presigned URL stays valid when the caller doesn't say.
+const DEFAULT_PRESIGN_SECS: f64 = 3600.0;
+
+/// The optional `expiresInSecs` argument at `index`, defaulting to
+/// `DEFAULT_PRESIGN_SECS`.
+fn expires_in_arg(cx: &mut FunctionContext, index: usize) -> NeonResult<std::time::Duration> {
+    let secs = match optional_arg(cx, index) {
+        Some(arg) => arg.downcast_or_throw::<JsNumber, _>(cx)?.value(cx),
+        None => DEFAULT_PRESIGN_SECS,
+    };
+    if !(secs.is_finite() && secs > 0.0) {
+        return cx.throw_range_error("expiresInSecs must be a positive number");
+    }
+    Ok(std::time::Duration::from_secs_f64(secs))
+}
+
+/// `presign(key, expiresInSecs?)`: a time-limited GET URL for a private object.
+fn presign_js(mut cx: FunctionContext) -> JsResult<JsString> {
+    let key = cx.argument::<JsString>(0)?.value(&mut cx);
+    let expires_in = expires_in_arg(&mut cx, 1)?;
+
+    let result = runtime().block_on(async {
+        dotenv().ok();
+        let upload = S3Upload::new().await?;
+        upload.presign_get(&key, expires_in).await
+    });
+
+    match result {
+        Ok(url) => Ok(cx.string(url)),
+        Err(e) => throw_upload_error(&mut cx, &e),
+    }
+}
+
+/// `presign_put(key, contentType, expiresInSecs?)`: a time-limited PUT URL
+/// the browser can upload to directly, as `{url, headers}`; every header must
+/// be sent with the upload. Metadata isn't written; once the client confirms
+/// the upload, run `record_file_upload(key)` for a file or
+/// `rebuild_metadata()` for an image.
+fn presign_put_js(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let key = cx.argument::<JsString>(0)?.value(&mut cx);
+    let content_type = cx.argument::<JsString>(1)?.value(&mut cx);
+    let expires_in = expires_in_arg(&mut cx, 2)?;
+
+    let result = runtime().block_on(async {
+        dotenv().ok();
+        let upload = S3Upload::new().await?;
+        upload.presign_put(&key, &content_type, expires_in).await
+    });
+
+    let presigned = match result {
+        Ok(presigned) => presigned,
+        Err(e) => return throw_upload_error(&mut cx, &e),
+    };
+    let presigned_obj = cx.empty_object();
+    let url = cx.string(&presigned.url);
+    presigned_obj.set(&mut cx, "url", url)?;
+    let headers = cx.empty_object();
+    for (name, value) in &presigned.headers {
+        let value = cx.string(value);
+        headers.set(&mut cx, name.as_str(), value)?;
+    }
+    presigned_obj.set(&mut cx, "headers", headers)?;
+    Ok(presigned_obj)
+}
+
+/// `record_file_upload(key)`: writes `data/files` metadata for a static file
+/// uploaded through `presign_put` and returns the metadata path.
+fn record_file_upload_js(mut cx: FunctionContext) -> JsResult<JsString> {
+    let key = cx.argument::<JsString>(0)?.value(&mut cx);
+    match runtime().block_on(record_file_upload(&key)) {
+        Ok(path) => Ok(cx.string(path.to_string_lossy())),
+        Err(e) => throw_upload_error(&mut cx, &e),
+    }
+}
+
+/// `check_connection()`: checks that the bucket is reachable before a batch
+/// run. Returns `{ok, message}` rather than throwing, so the message can be
+/// shown as-is.
+fn check_connection_js(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let (ok, message) = match runtime().block_on(check_connection()) {
+        Ok(message) => (true, message),
+        Err(e) => (false, e.to_string()),
+    };
+
+    let result = cx.empty_object();
+    let ok = cx.boolean(ok);
+    result.set(&mut cx, "ok", ok)?;
+    let message = cx.string(message);
+    result.set(&mut cx, "message", message)?;
+    Ok(result)
+}
+
+#[neon::main]
+fn main(mut cx: ModuleContext) -> NeonResult<()> {
+    init_logging();
+    cx.export_function("mkdir_and_download_files", mkdir_and_download_all_images_from_s3)?;
+    cx.export_function("download_images", download_images_js)?;
+    cx.export_function("upload", process_and_upload_js)?;
+    cx.export_function("upload_dry_run", upload_dry_run_js)?;
+    cx.export_function("upload_path", upload_path_js)?;
+    cx.export_function("delete", delete_by_uid_js)?;
+    cx.export_function("presign", presign_js)?;
+    cx.export_function("presign_put", presign_put_js)?;
+    cx.export_function("record_file_upload", record_file_upload_js)?;
+    cx.export_function("reconcile", reconcile_js)?;
+    cx.export_function("rebuild_metadata", rebuild_metadata_js)?;
+    cx.export_function("download_prefix", download_prefix_js)?;
+    cx.export_function("download_prefix_summary", download_prefix_summary_js)?;
+    cx.export_function("check_connection", check_connection_js)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::Frame;
+
+    #[test]
+    fn blurhash_uses_four_by_three_components() {
+        let img = DynamicImage::new_rgb8(640, 480);
+        let hash = compute_blurhash(&img).unwrap();
+        // 1 size flag + 1 max AC + 4 DC + 2 per AC component
+        assert_eq!(hash.len(), 6 + 2 * (4 * 3 - 1));
+    }
+
+    #[test]
+    fn detects_animated_gifs() {
+        let dir = tempfile::tempdir().unwrap();
+        let write_gif = |name: &str, frames: usize| {
+            let path = dir.path().join(name);
+            let mut encoder = GifEncoder::new(File::create(&path).unwrap());
+            for _ in 0..frames {
+                encoder.encode_frame(Frame::new(image::RgbaImage::new(4, 4))).unwrap();
+            }
+            path
+        };
+
+        let animated = write_gif("file-upload-animated-test.gif", 3);
+        let still = write_gif("file-upload-still-test.gif", 1);
+        assert!(is_animated_gif(&animated));
+        assert!(!is_animated_gif(&still));
+    }
+
+    #[test]
+    fn alt_token_is_split_from_file_name() {
+        assert_eq!(
+            split_alt_token("sunset alt=Sun over the lake.jpg"),
+            ("sunset.jpg".to_string(), Some("Sun over the lake".to_string()))
+        );
+        assert_eq!(split_alt_token("sunset.jpg"), ("sunset.jpg".to_string(), None));
+    }
+
+    #[test]
+    fn sidecars_are_named_after_the_full_file_name() {
+        let path = Path::new("inbox/photo.jpg");
+        assert_eq!(sidecar_path(path, ".alt.txt"), Path::new("inbox/photo.jpg.alt.txt"));
+        assert!(!is_sidecar(path));
+    }
+
+    #[test]
+    fn yml_sidecar_fills_image_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file-upload-sidecar-test.jpg");
+        let sidecar = sidecar_path(&path, ".yml");
+        fs::write(&sidecar, "caption: \"Lake at dusk\"\ncredit: Jane Doe\n").unwrap();
+        let text = read_image_text(&path, Some("Sun over the lake".to_string()));
+
+        assert_eq!(text.alt, "Sun over the lake");
+        assert_eq!(text.caption, "Lake at dusk");
+        assert_eq!(text.credit, "Jane Doe");
+    }
+
+    #[test]
+    fn batch_csv_fills_blank_sidecar_fields() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let csv = dir.join(BATCH_METADATA_FILE);
+        fs::write(
+            &csv,
+            "filename,alt,caption,credit\nIMG_001.JPG,A heron,\"Lake, at dusk\",Stock Co\nbroken.jpg\n"
+        ).unwrap();
+        let rows = read_batch_metadata(&csv).unwrap();
+        assert_eq!(rows["img_001.jpg"].caption, "Lake, at dusk");
+        assert_eq!(rows["broken.jpg"], ImageText::default());
+
+        let path = dir.join("IMG_001.JPG");
+        fs::write(sidecar_path(&path, ".yml"), "uid: heron\ncaption: Our own caption\n").unwrap();
+        apply_batch_metadata(&path, &rows["img_001.jpg"]).unwrap();
+        let text = read_image_text(&path, None);
+        let sidecar = fs::read_to_string(sidecar_path(&path, ".yml")).unwrap();
+
+        assert_eq!(text.alt, "A heron");
+        assert_eq!(text.caption, "Our own caption");
+        assert_eq!(text.credit, "Stock Co");
+        assert!(sidecar.contains("uid: heron"));
+    }
+
+    #[test]
+    fn batch_csv_leaves_invalid_sidecars_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file-upload-batch-invalid-test.jpg");
+        let sidecar = sidecar_path(&path, ".yml");
+        fs::write(&sidecar, "caption: [unclosed\n").unwrap();
+        let text = ImageText { alt: "A heron".to_string(), ..ImageText::default() };
+        let result = apply_batch_metadata(&path, &text);
+        let content = fs::read_to_string(&sidecar).unwrap();
+
+        assert!(matches!(result, Err(UploadError::InvalidFile(_))));
+        assert_eq!(content, "caption: [unclosed\n");
+    }
+
+    #[tokio::test]
+    async fn failed_upload_after_conversion_keeps_source_and_removes_png() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let bmp = dir.join("scan.bmp");
+        DynamicImage::new_rgb8(8, 8).save(&bmp).unwrap();
+
+        let stores = Stores::single(Arc::new(store::MemoryStore::rejecting_puts()));
+        let result = process_and_upload_file(&stores, &bmp, &UploadOptions::default()).await;
+        let source_kept = bmp.exists();
+        let png_left = dir.join(".scan.converting.png").exists();
+
+        match result {
+            Err(UploadError::Other(message)) => assert!(message.contains("rejected"), "{}", message),
+            other => panic!("expected the upload to fail, got {:?}", other.map(|p| p.uid)),
+        }
+        assert!(source_kept);
+        assert!(!png_left);
+    }
+
+    #[test]
+    fn tiff_and_bmp_are_normalized_to_png() {
+        assert!(needs_png_normalization(Path::new("scan.TIFF")));
+        assert!(needs_png_normalization(Path::new("scan.tif")));
+        assert!(needs_png_normalization(Path::new("icon.bmp")));
+        assert!(!needs_png_normalization(Path::new("photo.jpg")));
+        assert!(!needs_png_normalization(Path::new("logo.png")));
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let bmp = dir.join("icon.bmp");
+        DynamicImage::new_rgb8(8, 8).save(&bmp).unwrap();
+
+        let converted = convert_to_png(&bmp).unwrap();
+        let format = image::ImageFormat::from_path(converted.path()).unwrap();
+        let decoded = image::open(converted.path()).map(|img| (img.width(), img.height()));
+        drop(converted);
+
+        assert_eq!(format, ImageFormat::Png);
+        assert_eq!(decoded.unwrap(), (8, 8));
+    }
+
+    #[test]
+    fn placeholders_are_recognised() {
+        assert!(is_placeholder(Path::new("inbox/.gitkeep")));
+        assert!(is_placeholder(Path::new("inbox/.DS_Store")));
+        assert!(is_placeholder(Path::new("inbox/__add image or static files to this folder__")));
+        assert!(is_placeholder(Path::new("inbox/__README.md")));
+        assert!(!is_placeholder(Path::new("inbox/snake_case__name.png")));
+        assert!(!is_placeholder(Path::new("inbox/photo.jpg")));
+    }
+
+    #[test]
+    fn signature_accepts_extension_aliases() {
+        assert!(signature_matches("JPEG", "jpg"));
+        assert!(signature_matches("png", "png"));
+        assert!(signature_matches("xlsx", "zip"));
+    }
+
+    #[test]
+    fn signature_rejects_mismatched_contents() {
+        assert!(!signature_matches("png", "exe"));
+        assert!(!signature_matches("jpg", "png"));
+    }
+
+    #[test]
+    fn check_rejects_renamed_executable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file-upload-signature-test.png");
+        fs::write(&path, b"MZ\x90\x00\x03\x00\x00\x00\x04\x00\x00\x00\xff\xff").unwrap();
+        let result = check_file_signature(&path);
+        assert!(matches!(result, Err(UploadError::InvalidFile(_))));
+    }
+
+    #[test]
+    fn sanitize_transliterates_accents() {
+        assert_eq!(sanitize_filename("Café Señor.png"), "cafe-senor.png");
+    }
+
+    #[test]
+    fn sanitize_transliterates_cyrillic() {
+        assert_eq!(sanitize_filename("Привет мир.jpg"), "privet-mir.jpg");
+    }
+
+    #[test]
+    fn sanitize_hashes_cjk_only_names() {
+        let name = sanitize_filename("中文.png");
+        let (stem, extension) = name.rsplit_once('.').unwrap();
+        assert_eq!(extension, "png");
+        assert_eq!(stem.len(), 12);
+        assert!(stem.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(name, sanitize_filename("日本.png"));
+    }
+
+    #[test]
+    fn sanitize_hashes_symbol_only_names() {
+        for filename in ["!!!.png", "---.png", "«»%.png", "....png"] {
+            let name = sanitize_filename(filename);
+            let stem = Path::new(&name).file_stem().and_then(OsStr::to_str).unwrap();
+            assert_eq!(stem.len(), 12, "{} became {}", filename, name);
+            assert!(stem.chars().all(|c| c.is_ascii_hexdigit()), "{} became {}", filename, name);
+            assert_eq!(Path::new(&name).extension().and_then(OsStr::to_str), Some("png"));
+        }
+        assert_ne!(sanitize_filename("!!!.png"), sanitize_filename("???.png"));
+    }
+
+    #[test]
+    fn sanitize_never_leaves_an_empty_stem() {
+        for filename in ["中文.png", "!!!.png", "中文", "@@", "photo.!!"] {
+            let name = sanitize_filename(filename);
+            assert!(!name.is_empty());
+            assert!(!name.starts_with('.'), "{} became {}", filename, name);
+            assert!(!name.ends_with('.'), "{} became {}", filename, name);
+        }
+        assert_eq!(sanitize_filename("photo.!!"), "photo");
+    }
+
+    #[test]
+    fn sanitize_handles_emoji() {
+        let name = sanitize_filename("party 🎉 time.png");
+        assert!(name.is_ascii());
+        assert!(name.starts_with("party-"));
+        assert!(name.ends_with("-time.png"));
+    }
+
+    #[test]
+    fn sanitize_collapses_and_trims_dashes() {
+        assert_eq!(sanitize_filename("  --My   File--.PDF"), "my-file.pdf");
+    }
+
+    fn collision_options(on_collision: CollisionPolicy) -> UploadOptions {
+        UploadOptions { on_collision, ..UploadOptions::default() }
+    }
+
+    #[tokio::test]
+    async fn suffix_policy_picks_the_first_free_key() {
+        let store = store::MemoryStore::with_keys(&["photo.png", "photo-2.png"]);
+        let options = collision_options(CollisionPolicy::Suffix);
+        let stem = resolve_key_collision(&store, "", "photo", "png", &options).await.unwrap();
+        assert_eq!(stem, "photo-3");
+
+        let stem = resolve_key_collision(&store, "", "other", "png", &options).await.unwrap();
+        assert_eq!(stem, "other");
+    }
+
+    #[tokio::test]
+    async fn suffix_policy_skips_leftover_variants_and_gives_up_eventually() {
+        let store = store::MemoryStore::with_keys(&["photo.png", "photo-2_w800.webp", "photo-3-thumb.png", "photo-40.png"]);
+        let options = collision_options(CollisionPolicy::Suffix);
+        let stem = resolve_key_collision(&store, "", "photo", "png", &options).await.unwrap();
+        assert_eq!(stem, "photo-4");
+
+        let mut keys = vec!["full.png".to_string()];
+        keys.extend(suffixed("full").map(|candidate| format!("{}.png", candidate)));
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let store = store::MemoryStore::with_keys(&keys);
+        assert!(resolve_key_collision(&store, "", "full", "png", &options).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn existing_metadata_is_skipped_or_renamed_around() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("photo.yml"), "uid      :  photo\n").unwrap();
+        fs::write(dir.join("photo-2.yml"), "uid      :  photo-2\n").unwrap();
+        let store = store::MemoryStore::with_keys(&["photo-3.png"]);
+        let with_policy = |on_existing| UploadOptions { on_existing, ..UploadOptions::default() };
+
+        let overwrite = resolve_existing_metadata(&store, dir, "", "photo", "png", false, &with_policy(ExistingPolicy::Overwrite)).await.unwrap();
+        let skip = resolve_existing_metadata(&store, dir, "", "photo", "png", false, &with_policy(ExistingPolicy::Skip)).await.unwrap();
+        let rename = resolve_existing_metadata(&store, dir, "", "photo", "png", false, &with_policy(ExistingPolicy::Rename)).await.unwrap();
+        let chosen = resolve_existing_metadata(&store, dir, "", "photo", "png", true, &with_policy(ExistingPolicy::Rename)).await;
+        let fresh = resolve_existing_metadata(&store, dir, "", "sunset", "png", false, &with_policy(ExistingPolicy::Skip)).await.unwrap();
+
+        assert_eq!(overwrite.as_deref(), Some("photo"));
+        assert_eq!(skip, None);
+        assert_eq!(rename.as_deref(), Some("photo-4"));
+        assert!(chosen.is_err());
+        assert_eq!(fresh.as_deref(), Some("sunset"));
+    }
+
+    #[tokio::test]
+    async fn same_named_files_from_other_folders_get_their_own_metadata() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("photo.yml"), "uid: photo\nkey: events/photo.png\n").unwrap();
+        let store = store::MemoryStore::with_keys(&["events/photo.png", "trips/photo-2.png"]);
+
+        let reupload = avoid_shared_metadata(&store, dir, "events/", "photo", "png", false).await;
+        let other = avoid_shared_metadata(&store, dir, "trips/", "photo", "png", false).await;
+        let chosen = avoid_shared_metadata(&store, dir, "trips/", "photo", "png", true).await;
+        fs::write(dir.join("photo-2.yml"), "uid: photo-2\nkey: trips/photo-2.png\n").unwrap();
+        let again = avoid_shared_metadata(&store, dir, "trips/", "photo", "png", false).await;
+
+        assert_eq!(reupload.unwrap(), "photo");
+        assert_eq!(other.unwrap(), "photo-3");
+        assert!(chosen.is_err());
+        assert_eq!(again.unwrap(), "photo-2");
+    }
+
+    #[tokio::test]
+    async fn uids_in_flight_are_renamed_until_released() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let store = store::MemoryStore::with_keys(&["shared-2.png"]);
+
+        let first = reserve_uid(&store, dir, "", "shared", "png", false).await.unwrap();
+        let second = reserve_uid(&store, dir, "", "shared", "jpg", false).await.unwrap();
+        let third = reserve_uid(&store, dir, "", "shared", "png", false).await.unwrap();
+        let chosen = reserve_uid(&store, dir, "", "shared", "png", true).await;
+        assert_eq!((first.uid(), second.uid(), third.uid()), ("shared", "shared-2", "shared-3"));
+        assert!(chosen.is_err());
+
+        drop(first);
+        let again = reserve_uid(&store, dir, "", "shared", "png", false).await.unwrap();
+        assert_eq!(again.uid(), "shared");
+    }
+
+    #[tokio::test]
+    async fn error_policy_rejects_existing_keys() {
+        let store = store::MemoryStore::with_keys(&["static/report.pdf"]);
+        let options = collision_options(CollisionPolicy::Error);
+        assert!(resolve_key_collision(&store, "static/", "report", "pdf", &options).await.is_err());
+
+        let options = collision_options(CollisionPolicy::Overwrite);
+        let stem = resolve_key_collision(&store, "static/", "report", "pdf", &options).await.unwrap();
+        assert_eq!(stem, "report");
+
+        let options = UploadOptions { force_reprocess: true, ..collision_options(CollisionPolicy::Error) };
+        let stem = resolve_key_collision(&store, "static/", "report", "pdf", &options).await.unwrap();
+        assert_eq!(stem, "report");
+    }
+
+    #[tokio::test]
+    async fn variants_are_uploaded_under_suffixed_keys() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let source = dir.join("photo.png");
+        DynamicImage::new_rgb8(1200, 600).save(&source).unwrap();
+
+        let store = store::MemoryStore::default();
+        let planned = variants::plan_variants(1200, 600, false);
+        let uploaded = upload_variants(&store, &source, dir, "photo", "png", &planned, &UploadOptions::default())
+            .await
+            .unwrap();
+        let leftovers = fs::read_dir(dir).unwrap().count();
+
+        let prefix = &config::s3().image_prefix;
+        let mut expected: Vec<String> = planned
+            .iter()
+            .map(|v| format!("{}photo{}.png", prefix, v.suffix()))
+            .collect();
+        expected.sort();
+        assert!(!expected.is_empty());
+        assert_eq!(store.keys(), expected);
+        assert_eq!(uploaded.len(), planned.len());
+        for (variant, _) in &uploaded {
+            assert_eq!((variant.width, variant.height), (variant.breakpoint, variant.breakpoint / 2));
+        }
+        // Only the source is left; every resized file was cleaned up
+        assert_eq!(leftovers, 1);
+    }
+
+    #[test]
+    fn srcset_lists_each_width_once_narrowest_first() {
+        let planned = variants::plan_variants(1200, 600, true);
+        let prefix = &config::s3().image_prefix;
+        let value = srcset(prefix, "photo", "png", 1200, &planned);
+        let entries: Vec<&str> = value.split(", ").collect();
+
+        let widths: Vec<u32> = entries
+            .iter()
+            .map(|entry| entry.rsplit_once(' ').unwrap().1.trim_end_matches('w').parse().unwrap())
+            .collect();
+        let mut sorted = widths.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(widths, sorted);
+        assert_eq!(widths.last(), Some(&1200));
+
+        assert!(entries.last().unwrap().ends_with(&format!("/{}photo.png 1200w", prefix)));
+        let first = planned.iter().min_by_key(|v| v.width).unwrap();
+        assert!(entries[0].ends_with(&format!("photo{}.png {}w", first.suffix(), first.width)));
+    }
+
+    #[tokio::test]
+    async fn keep_working_leaves_variants_on_disk() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let source = dir.join("photo.png");
+        DynamicImage::new_rgb8(1200, 600).save(&source).unwrap();
+
+        let store = store::MemoryStore::default();
+        let planned = variants::plan_variants(1200, 600, false);
+        let options = UploadOptions { keep_working: true, ..UploadOptions::default() };
+        upload_variants(&store, &source, dir, "photo", "png", &planned, &options).await.unwrap();
+        let kept: Vec<bool> = planned
+            .iter()
+            .map(|v| dir.join(format!("photo{}.png", v.suffix())).exists())
+            .collect();
+
+        assert!(!kept.is_empty());
+        assert!(kept.iter().all(|&exists| exists));
+    }
+
+    #[tokio::test]
+    async fn pdf_thumbnail_failures_do_not_fail_the_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file-upload-pdf-thumbnail-test.pdf");
+        fs::write(&path, "%PDF-1.4 not really a document").unwrap();
+
+        let store = store::MemoryStore::default();
+        let thumbnail = upload_pdf_thumbnail(&store, &path, "report", &UploadOptions::default()).await;
+
+        assert!(thumbnail.is_none());
+        assert!(store.keys().is_empty());
+    }
+
+    #[test]
+    fn variants_yaml_records_name_and_dimensions() {
+        let variant = PlannedVariant {
+            name: "mobile".to_string(),
+            breakpoint: 400,
+            density: 1,
+            width: 400,
+            height: 267,
+            crop: None,
+            hash: None,
+        };
+        let yaml: serde_yaml::Value = serde_yaml::from_str(&variants_yaml("images/2024/06/", "photo", "jpg", &[variant])).unwrap();
+        let entry = &yaml["variants"][0];
+
+        assert_eq!(entry["name"].as_str(), Some("mobile"));
+        assert_eq!(entry["width"].as_u64(), Some(400));
+        assert_eq!(entry["height"].as_u64(), Some(267));
+        assert_eq!(entry["key"].as_str(), Some("images/2024/06/photo_w400.jpg"));
+        assert!(entry["crop"].is_null());
+    }
+
+    #[tokio::test]
+    async fn cropped_variants_record_where_they_were_cut() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let source = dir.join("photo.png");
+        DynamicImage::new_rgb8(1600, 1200).save(&source).unwrap();
+
+        let crop = variants::CropRegion {
+            aspect: "16:9".to_string(),
+            strategy: variants::CropStrategy::Entropy,
+            x: 0,
+            y: 150,
+            width: 1600,
+            height: 900,
+        };
+        let hero = PlannedVariant {
+            name: "hero".to_string(),
+            breakpoint: 800,
+            density: 1,
+            width: 800,
+            height: 450,
+            crop: Some(crop),
+            hash: None,
+        };
+        let store = store::MemoryStore::default();
+        let generated = upload_variants(&store, &source, dir, "photo", "png", &[hero], &UploadOptions::default())
+            .await
+            .unwrap();
+
+        let (hero, object) = &generated[0];
+        assert!(object.key.ends_with("photo_hero.png"));
+        assert_eq!((hero.width, hero.height), (800, 450));
+
+        let generated = [hero.clone()];
+        let yaml: serde_yaml::Value = serde_yaml::from_str(&variants_yaml("", "photo", "png", &generated)).unwrap();
+        let crop = &yaml["variants"][0]["crop"];
+        assert_eq!(crop["aspect"].as_str(), Some("16:9"));
+        assert_eq!(crop["strategy"].as_str(), Some("entropy"));
+        assert_eq!((crop["y"].as_u64(), crop["height"].as_u64()), (Some(150), Some(900)));
+        assert_eq!(srcset("", "photo", "png", 1600, &generated), format!("{} 1600w", public_url(&config::s3().image_bucket, "photo.png")));
+    }
+
+    #[tokio::test]
+    async fn zero_byte_files_are_rejected_up_front() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file-upload-empty-test.png");
+        fs::write(&path, b"").unwrap();
+
+        let store = Arc::new(store::MemoryStore::default());
+        let result = process_and_upload_file(&Stores::single(store.clone()), &path, &UploadOptions::default()).await;
+
+        match result {
+            Err(UploadError::InvalidFile(message)) => assert!(message.contains("0 bytes")),
+            other => panic!("expected an invalid file error, got {:?}", other.map(|p| p.uid)),
+        }
+        assert!(store.keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn summary_totals_bytes_uploaded() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let small = dir.join("small.csv");
+        let large = dir.join("large.csv");
+        let missing = dir.join("missing.csv");
+        fs::write(&small, "a,b\n").unwrap();
+        fs::write(&large, "a,b\n".repeat(100)).unwrap();
+
+        let stores = Stores::single(Arc::new(store::MemoryStore::default()));
+        let options = UploadOptions { dry_run: true, ..UploadOptions::default() };
+        let mut summary = UploadSummary::default();
+        process_files(&stores, &[small, large, missing], &options, false, &|_: &FileResult| {}, &mut summary).await;
+
+        let per_file: Vec<u64> = summary.files.iter().map(|file| file.bytes_uploaded).collect();
+        assert_eq!(per_file, [4, 400, 0]);
+        assert_eq!(summary.bytes_uploaded, 404);
+    }
+
+    #[test]
+    fn chosen_uids_must_be_slug_safe() {
+        assert_eq!(uid_token("uid__agency-seal-2024.png"), Some("agency-seal-2024"));
+        assert_eq!(uid_token("agency-seal.png"), None);
+        assert!(is_valid_uid("agency-seal-2024"));
+        assert!(is_valid_uid("seal_v2"));
+        for uid in ["", "Agency-Seal", "agency seal", "-seal", "seal-", "seal/2024", "café"] {
+            assert!(!is_valid_uid(uid), "{:?}", uid);
+        }
+        assert!(!is_valid_uid(&"a".repeat(MAX_UID_LEN + 1)));
+
+        assert_eq!(working_name("uid__Agency Seal.PNG"), "uid__Agency Seal.png");
+        assert_eq!(working_name("Agency Seal.PNG"), "agency-seal.png");
+    }
+
+    #[test]
+    fn chosen_uid_can_come_from_a_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file-upload-uid-sidecar-test.png");
+        fs::write(sidecar_path(&path, ".yml"), "alt: Seal\nuid: agency-seal-2024\n").unwrap();
+        let from_sidecar = explicit_uid(&path, "seal.png");
+        let from_name = explicit_uid(&path, "uid__other-seal.png");
+        let invalid = explicit_uid(&path, "uid__Other Seal.png");
+
+        assert_eq!(from_sidecar.unwrap().as_deref(), Some("agency-seal-2024"));
+        assert_eq!(from_name.unwrap().as_deref(), Some("other-seal"));
+        assert!(matches!(invalid, Err(UploadError::InvalidFile(_))));
+        assert_eq!(explicit_uid(&path, "seal.png").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn chosen_uid_names_the_upload() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("uid__budget-2024.csv");
+        fs::write(&path, "year,total\n").unwrap();
+
+        let stores = Stores::single(Arc::new(store::MemoryStore::default()));
+        let options = UploadOptions { dry_run: true, ..UploadOptions::default() };
+        let processed = process_and_upload_file(&stores, &path, &options).await;
+
+        let processed = processed.unwrap();
+        assert_eq!(processed.uid, "budget-2024");
+        assert!(processed.original_url.unwrap().ends_with("/budget-2024.csv"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn non_utf8_file_names_are_sanitized_not_fatal() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join(OsStr::from_bytes(b"report-\xff\xfe.csv"));
+        fs::write(&path, "a,b\n").unwrap();
+
+        let stores = Stores::single(Arc::new(store::MemoryStore::default()));
+        let options = UploadOptions { dry_run: true, ..UploadOptions::default() };
+        let processed = process_and_upload_file(&stores, &path, &options).await;
+
+        assert_eq!(processed.unwrap().uid, "report");
+        assert!(matches!(file_name_lossy(Path::new("..")), Err(UploadError::InvalidFile(_))));
+    }
+
+    #[tokio::test]
+    async fn static_files_go_to_the_file_bucket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file-upload-routing-test.csv");
+        fs::write(&path, "year,count\n2024,3\n").unwrap();
+
+        let stores = Stores {
+            images: Arc::new(store::MemoryStore::in_bucket("media")),
+            files: Arc::new(store::MemoryStore::in_bucket("datasets")),
+        };
+        let options = UploadOptions { dry_run: true, ..UploadOptions::default() };
+        let processed = process_and_upload_file(&stores, &path, &options).await;
+
+        let url = processed.unwrap().original_url.unwrap();
+        assert!(url.contains("/datasets/"), "{}", url);
+    }
+
+    #[tokio::test]
+    async fn skip_metadata_still_reports_the_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file-upload-skip-metadata-test.csv");
+        fs::write(&path, "year,count\n2024,3\n").unwrap();
+
+        let store = Arc::new(store::MemoryStore::default());
+        let stores = Stores::single(store.clone());
+        let options = UploadOptions { skip_metadata: true, ..UploadOptions::default() };
+        let processed = process_and_upload_file(&stores, &path, &options).await;
+
+        let processed = processed.unwrap();
+        assert_eq!(processed.metadata_path, None);
+        assert!(!Path::new("data/files/file-upload-skip-metadata-test.yml").exists());
+        assert_eq!(processed.objects.len(), 1);
+        assert_eq!(store.keys(), [processed.objects[0].key.clone()]);
+    }
+
+    #[test]
+    fn oversized_static_files_are_refused() {
+        let path = Path::new("database-dump.sql");
+        let limit = config::max_static_file_size_mb();
+        let over = limit * 1024 * 1024 + 1;
+        match check_file_size(path, over, limit, "MAX_STATIC_FILE_SIZE_MB") {
+            Err(UploadError::Other(message)) => assert!(message.contains("MAX_STATIC_FILE_SIZE_MB"), "{}", message),
+            other => panic!("expected a size error, got {:?}", other),
+        }
+        assert!(check_file_size(path, over - 1, limit, "MAX_STATIC_FILE_SIZE_MB").is_ok());
+        assert!(check_file_size(path, u64::MAX, u64::MAX, "MAX_STATIC_FILE_SIZE_MB").is_ok());
+    }
+
+    #[test]
+    fn min_dimension_rejects_small_images() {
+        let path = Path::new("icon.png");
+        assert!(matches!(check_min_dimension(path, 64, 640, Some(100)), Err(UploadError::InvalidFile(_))));
+        assert!(check_min_dimension(path, 100, 100, Some(100)).is_ok());
+        assert!(check_min_dimension(path, 64, 64, None).is_ok());
+    }
+
+    #[test]
+    fn truncated_images_name_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file-upload-truncated-test.png");
+        let mut bytes = Vec::new();
+        DynamicImage::new_rgb8(64, 64)
+            .write_to(&mut std::io::Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+
+        let result = open_image(&path);
+
+        match result {
+            Err(e @ UploadError::ImageError(_)) => assert!(e.to_string().contains("file-upload-truncated-test.png")),
+            other => panic!("expected an image error, got {:?}", other.map(|img| (img.width(), img.height()))),
+        }
+    }
+
+    #[tokio::test]
+    async fn identical_reuploads_are_recognised() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file-upload-idempotent-test.txt");
+        fs::write(&path, b"same bytes").unwrap();
+
+        let store = store::MemoryStore::default();
+        let before = is_unchanged(&store, &path, "static/notes.txt", 10).await.unwrap();
+        upload_to_s3(&store, &path, "static/notes.txt", None, StorageClass::Standard, "", false).await.unwrap();
+        let after = is_unchanged(&store, &path, "static/notes.txt", 10).await.unwrap();
+        fs::write(&path, b"same size!").unwrap();
+        let edited = is_unchanged(&store, &path, "static/notes.txt", 10).await.unwrap();
+
+        assert!(!before);
+        assert!(after);
+        assert!(!edited);
+    }
+
+    #[test]
+    fn duplicate_images_are_found_by_hash() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("sunset.yml"), "uid      :  sunset\nhash     :  abc123\n").unwrap();
+        fs::write(dir.join("notes.txt"), "hash: abc123\n").unwrap();
+
+        let found = find_image_by_hash(dir, "abc123");
+        let missing = find_image_by_hash(dir, "def456");
+
+        assert_eq!(found.as_deref(), Some("sunset"));
+        assert_eq!(missing, None);
+    }
+
+    #[test]
+    fn panorama_variants_resize_to_at_least_one_pixel() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let source = dir.join("panorama.png");
+        let output = dir.join("panorama_w200.png");
+        DynamicImage::new_rgb8(10000, 1).save(&source).unwrap();
+
+        let result = resize_image(&source, &output, ResizeMode::Width, 200, None, 75);
+        let dimensions = image::image_dimensions(&output);
+
+        result.unwrap();
+        assert_eq!(dimensions.unwrap(), (200, 1));
+    }
+
+    #[test]
+    fn resize_image_bytes_stays_in_memory() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(400, 200).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+
+        let jpeg = resize_image_bytes(&png, 100, ImageFormat::Jpeg).unwrap();
+        assert_eq!(image::guess_format(&jpeg).unwrap(), ImageFormat::Jpeg);
+        let resized = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!((resized.width(), resized.height()), (100, 50));
+
+        assert!(resize_image_bytes(b"not an image", 100, ImageFormat::Png).is_err());
+    }
+
+    #[test]
+    fn only_original_images_are_rebuilt() {
+        assert_eq!(original_key_parts("2024/06/photo.png"), Some(("2024/06/", "photo", "png")));
+        assert_eq!(original_key_parts("report.v2.jpg"), Some(("", "report.v2", "jpg")));
+        assert_eq!(original_key_parts("2024/06/photo_w800.png"), None);
+        assert_eq!(original_key_parts("photo_w800@2x.png"), None);
+        assert_eq!(original_key_parts("photo_thumb150.png"), None);
+        assert_eq!(original_key_parts("brochure.pdf"), None);
+        assert_eq!(original_key_parts("2024/06/"), None);
+    }
+
+    #[tokio::test]
+    async fn object_dimensions_come_from_the_header() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let source = dir.join("photo.png");
+        DynamicImage::new_rgb8(300, 120).save(&source).unwrap();
+
+        let store = store::MemoryStore::default();
+        let options = PutOptions {
+            content_type: "image/png",
+            storage_class: StorageClass::Standard,
+            acl: None,
+            tagging: None,
+        };
+        store.put("photo.png", &source, &options).await.unwrap();
+
+        assert_eq!(object_dimensions(&store, "photo.png").await.unwrap(), (300, 120));
+        assert!(object_dimensions(&store, "missing.png").await.is_err());
+    }
+
+    #[test]
+    fn resized_pngs_keep_their_transparency() {
+        // Left half transparent, right half opaque red; oxipng stores the
+        // two colours as a palette with a tRNS chunk
+        let img = image::RgbaImage::from_fn(40, 40, |x, _| {
+            if x < 20 { image::Rgba([0, 0, 0, 0]) } else { image::Rgba([255, 0, 0, 255]) }
+        });
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(img).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let palette = oxipng::optimize_from_memory(&png, &oxipng::Options::from_preset(OXIPNG_PRESET)).unwrap();
+        assert_eq!(palette[25], 3, "expected an indexed-colour PNG");
+
+        let resized = image::load_from_memory(&resize_image_bytes(&palette, 20, ImageFormat::Png).unwrap()).unwrap();
+        assert!(resized.color().has_alpha());
+        let resized = resized.to_rgba8();
+        assert_eq!(resized.dimensions(), (20, 20));
+        assert_eq!(resized.get_pixel(2, 10)[3], 0);
+        assert_eq!(resized.get_pixel(17, 10).0, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn transparent_images_flatten_onto_the_background_for_jpeg() {
+        let img = image::RgbaImage::from_fn(16, 16, |x, _| {
+            if x < 8 { image::Rgba([0, 0, 0, 0]) } else { image::Rgba([0, 0, 255, 128]) }
+        });
+        let flat = flatten(&DynamicImage::ImageRgba8(img.clone()), [255, 255, 255]).to_rgb8();
+        assert_eq!(flat.get_pixel(2, 2).0, [255, 255, 255]);
+        assert_eq!(flat.get_pixel(12, 2).0, [127, 127, 255]);
+
+        let jpeg = encode_image(&DynamicImage::ImageRgba8(img), ImageFormat::Jpeg, 90).unwrap();
+        let decoded = image::load_from_memory(&jpeg).unwrap().to_rgb8();
+        // Nowhere near the black transparency used to come out as
+        assert!(decoded.get_pixel(2, 8).0.iter().all(|&c| c > 240));
+    }
+
+    #[test]
+    fn mislabelled_images_are_relabelled_not_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file-upload-mislabelled-test.jpg");
+        DynamicImage::new_rgb8(8, 8).save_with_format(&path, ImageFormat::Png).unwrap();
+        let actual = mislabelled_image_extension(&path);
+        let signature = check_file_signature(&path);
+        let relabelled = relabel_image(&path, "png").unwrap();
+        let decoded = image::open(relabelled.path());
+        let relabelled_path = relabelled.path().to_path_buf();
+        drop(relabelled);
+
+        assert_eq!(actual, Some("png"));
+        signature.unwrap();
+        assert!(decoded.is_ok());
+        assert!(!relabelled_path.exists());
+    }
+
+    #[test]
+    fn svgs_are_static_uploads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file-upload-svg-test.svg");
+        fs::write(&path, "<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\"/>\n").unwrap();
+        let signature = check_file_signature(&path);
+
+        signature.unwrap();
+        assert!(is_valid_file_type(&path));
+        assert!(!is_image(&path));
+        assert_eq!(content_type_for(&path), Some("image/svg+xml"));
+    }
+
+    #[test]
+    fn overrides_take_precedence_over_mime_guess() {
+        assert_eq!(content_type_for(Path::new("data.CSV")), Some("text/csv"));
+        assert_eq!(content_type_for(Path::new("photo.png")), Some("image/png"));
+    }
+
+    #[test]
+    fn square_thumbnails_crop_the_centre() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let source = dir.join("wide.png");
+        let output = dir.join("wide_thumb150.png");
+        // Red edges around a green centre square
+        let mut img = image::RgbImage::from_pixel(600, 300, image::Rgb([255, 0, 0]));
+        for x in 150..450 {
+            for y in 0..300 {
+                img.put_pixel(x, y, image::Rgb([0, 255, 0]));
+            }
+        }
+        img.save(&source).unwrap();
+
+        let result = square_thumbnail(&source, &output, 150, 75);
+        let thumbnail = image::open(&output).map(|t| t.to_rgb8());
+
+        result.unwrap();
+        let thumbnail = thumbnail.unwrap();
+        assert_eq!(thumbnail.dimensions(), (150, 150));
+        assert_eq!(thumbnail.get_pixel(0, 75), &image::Rgb([0, 255, 0]));
+        assert_eq!(thumbnail.get_pixel(149, 75), &image::Rgb([0, 255, 0]));
+    }
+
+    #[test]
+    fn watermark_is_scaled_and_placed_in_the_corner() {
+        let mut img = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1000, 500, image::Rgba([0, 0, 0, 255])));
+        let mark = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(50, 50, image::Rgba([255, 255, 255, 255])));
+        let watermark = Watermark {
+            path: PathBuf::new(),
+            position: WatermarkPosition::BottomRight,
+            opacity: 1.0,
+            scale: 0.1,
+        };
+        apply_watermark(&mut img, &mark, &watermark);
+        let img = img.to_rgba8();
+
+        // 100px wide, inset by 10px (1/50 of the short side)
+        assert_eq!(img.get_pixel(950, 450), &image::Rgba([255, 255, 255, 255]));
+        assert_eq!(img.get_pixel(885, 450), &image::Rgba([0, 0, 0, 255]));
+        assert_eq!(img.get_pixel(950, 495), &image::Rgba([0, 0, 0, 255]));
+        assert_eq!(img.get_pixel(10, 10), &image::Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn inbox_subfolders_are_searched_to_a_bounded_depth() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("Events/2024/deeper")).unwrap();
+        fs::create_dir_all(dir.join(".hidden")).unwrap();
+        for name in ["top.png", "Events/a.pdf", "Events/2024/b.png", "Events/2024/deeper/c.png", ".hidden/d.png"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        let within_two = inbox_files(dir, 2);
+        let top_only = inbox_files(dir, 0);
+
+        assert_eq!(
+            within_two.unwrap(),
+            vec![dir.join("top.png"), dir.join("Events/a.pdf"), dir.join("Events/2024/b.png")]
+        );
+        assert_eq!(top_only.unwrap(), vec![dir.join("top.png")]);
+    }
+
+    #[test]
+    fn nested_inbox_files_are_flattened_or_keep_their_folders() {
+        let tmp = tempfile::tempdir().unwrap();
+        let working = tmp.path();
+        let inbox = Path::new("inbox");
+        let nested = inbox.join("Field Trips/2024/Photo.png");
+
+        let preserved = working_path(working, inbox, &nested, "photo.png", true);
+        let flattened = working_path(working, inbox, &nested, "photo.png", false);
+        fs::write(working.join("photo.png"), b"").unwrap();
+        let renamed = working_path(working, inbox, &nested, "photo.png", false);
+        let top_level = working_path(working, inbox, &inbox.join("photo.png"), "photo.png", false);
+        fs::write(working.join("field-trips-2024-photo.png"), b"").unwrap();
+        let numbered = working_path(working, inbox, &nested, "photo.png", false);
+
+        assert_eq!(preserved, working.join("field-trips/2024/photo.png"));
+        assert_eq!(flattened, working.join("photo.png"));
+        assert_eq!(renamed, working.join("field-trips-2024-photo.png"));
+        assert_eq!(top_level, working.join("photo-2.png"));
+        assert_eq!(numbered, working.join("field-trips-2024-photo-2.png"));
+    }
+
+    #[test]
+    fn working_files_are_grouped_by_subfolder() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("events/2024")).unwrap();
+        for name in ["top.png", "events/2024/b.png"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        let groups = working_files(dir);
+
+        assert_eq!(
+            groups.unwrap(),
+            vec![
+                (String::new(), vec![dir.join("top.png")]),
+                ("events/".to_string(), vec![]),
+                ("events/2024/".to_string(), vec![dir.join("events/2024/b.png")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn uploadable_files_skip_placeholders_sidecars_and_unknown_types() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        for name in ["b.png", "a.pdf", ".gitkeep", "b.png.alt.txt", "notes.exe"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        fs::create_dir_all(dir.join("nested.png")).unwrap();
+
+        let files = uploadable_files(dir);
+
+        assert_eq!(files.unwrap(), vec![dir.join("a.pdf"), dir.join("b.png")]);
+    }
+
+    #[test]
+    fn orphaned_sidecars_are_ordinary_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        for name in ["b.png", "b.png.yml", "notes.yml", "gone.pdf.alt.txt"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        let sidecars: Vec<bool> = ["b.png.yml", "notes.yml", "gone.pdf.alt.txt"]
+            .iter()
+            .map(|name| is_sidecar(&dir.join(name)))
+            .collect();
+        let files = uploadable_files(dir);
+
+        assert_eq!(sidecars, [true, false, false]);
+        assert_eq!(files.unwrap(), vec![dir.join("b.png"), dir.join("gone.pdf.alt.txt")]);
+    }
 }
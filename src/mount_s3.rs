@@ -1,15 +1,19 @@
-use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_s3::config::Credentials;
-use aws_sdk_s3::{ Client, Error as S3Error, error::SdkError, config::Region };
+use aws_sdk_s3::{ Error as S3Error, error::SdkError };
+use md5::{Digest, Md5};
 use derive_more::Display;
-use std::{fmt, fs};
-use std::path::Path;
-use std::env;
-use dotenv::dotenv;
-use regex::Regex;
-use colored::*;
-
-use crate::{ BUCKET_NAME, REGION };
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashSet;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::fs::{self, File};
+use std::fmt;
+use std::io::{BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use log::{debug, error, info, warn};
+
+use crate::config;
+use crate::error::UploadError;
+use crate::store::{ObjectStore, Stores};
 const IMAGE_DATA_DIR: &str = "./data/images";
 
 
@@ -19,11 +23,14 @@ pub struct ImageMetadata {
    pub key: String,
    pub uid: String,
    pub format: String,
+   pub width: Option<u32>,
+   pub height: Option<u32>,
+   pub alt: String,
 }
 
 #[derive(Debug)]
 pub enum MountError {
-    S3Error(S3Error),
+    S3Error(Box<S3Error>),
     IoError(std::io::Error),
     Other(String),
 }
@@ -40,7 +47,7 @@ impl fmt::Display for MountError {
 
 impl From<S3Error> for MountError {
     fn from(err: S3Error) -> Self {
-        MountError::S3Error(err)
+        MountError::S3Error(Box::new(err))
     }
 }
 
@@ -56,70 +63,144 @@ impl From<String> for MountError {
     }
 }
 
+impl From<UploadError> for MountError {
+    fn from(err: UploadError) -> Self {
+        match err {
+            UploadError::IoError(err) => MountError::IoError(err),
+            other => MountError::Other(other.to_string()),
+        }
+    }
+}
+
 impl<E> From<SdkError<E>> for MountError {
     fn from(err: SdkError<E>) -> Self {
         MountError::Other(err.to_string())
     }
 }
 
-// Simple YAML parser for our specific case
-fn parse_image_yaml(content: &str) -> Option<(String, String)> {
-    let uid_re = Regex::new(r"uid\s*:\s*([^\n]+)").unwrap();
-    let format_re = Regex::new(r"format\s*:\s*([^\n]+)").unwrap();
+/// The fields of a `data/images/{uid}.yml` file that the mount cares about.
+#[derive(Debug, Deserialize)]
+struct ImageYaml {
+    #[serde(deserialize_with = "scalar_string")]
+    uid: String,
+    #[serde(deserialize_with = "scalar_string")]
+    format: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    alt: Option<String>,
+    /// The original's object key; older metadata predates it.
+    key: Option<String>,
+}
+
+/// Accepts any YAML scalar as a string, so an all-digit uid like `2024`
+/// isn't rejected for parsing as a number.
+fn scalar_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    match serde_yaml::Value::deserialize(deserializer)? {
+        serde_yaml::Value::String(s) => Ok(s),
+        serde_yaml::Value::Number(n) => Ok(n.to_string()),
+        serde_yaml::Value::Bool(b) => Ok(b.to_string()),
+        other => Err(serde::de::Error::custom(format!("expected a scalar, found {:?}", other))),
+    }
+}
+
+fn parse_image_yaml(content: &str) -> Result<ImageYaml, serde_yaml::Error> {
+    serde_yaml::from_str(content)
+}
+
+/// Where `key` is mirrored under `local_dir`, or `None` for a key that
+/// would land outside it: an absolute path or one climbing out with `..`.
+fn local_path_for(local_dir: &Path, key: &str) -> Option<PathBuf> {
+    let relative = Path::new(key);
+    relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        .then(|| local_dir.join(relative))
+}
+
+/// The uid an object key belongs to: the file name without any `YYYY/MM/`
+/// partition, its extension or a variant suffix (`_w{width}`, optionally
+/// `@{n}x`, or `_thumb{size}`, then a `HASHED_FILENAMES` hash), e.g.
+/// `2024/06/photo_w800@2x.webp` -> `photo`.
+pub(crate) fn owner_uid(name: &str) -> &str {
+    let name = name.rsplit_once('/').map_or(name, |(_, file_name)| file_name);
+    let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+    let all_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    // Only variants are hashed, so an original's dotted uid is left alone below
+    let unhashed = match stem.rsplit_once('.') {
+        Some((base, hash)) if hash.len() == crate::FILENAME_HASH_LEN && hash.bytes().all(|b| b.is_ascii_hexdigit()) => base,
+        _ => stem,
+    };
+    let base = match unhashed.rsplit_once('@') {
+        Some((base, density)) if density.strip_suffix('x').is_some_and(all_digits) => base,
+        _ => unhashed,
+    };
+    for marker in ["_w", "_thumb"] {
+        if let Some((uid, size)) = base.rsplit_once(marker) {
+            if all_digits(size) {
+                return uid;
+            }
+        }
+    }
+    stem
+}
+
+/// How often a single download reports progress.
+const PROGRESS_STEP_BYTES: u64 = 8 * 1024 * 1024;
 
-    let uid = uid_re
-        .captures(content)
-        .and_then(|cap| cap.get(1).map(|m| m.as_str().trim().to_string()));
-    let format = format_re
-        .captures(content)
-        .and_then(|cap| cap.get(1).map(|m| m.as_str().trim().to_string()));
+fn megabytes(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+}
 
-    match (uid, format) {
-        (Some(uid), Some(format)) => Some((uid, format)),
-        _ => None,
+/// "12.0 MB of 48.0 MB (25%)", or just the running total when the size is unknown.
+fn progress_text(written: u64, total: u64) -> String {
+    if total == 0 {
+        return megabytes(written);
     }
+    format!("{} of {} ({}%)", megabytes(written), megabytes(total), written * 100 / total)
+}
+
+/// Outcome of a bulk download. A failed object doesn't stop the others.
+#[derive(Debug, Default)]
+pub struct DownloadSummary {
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Bytes written to disk; files that already existed count as 0.
+    pub bytes: u64,
+    /// `key: error` for each failed object.
+    pub errors: Vec<String>,
 }
 
+/// Result of comparing `data/images` metadata against the bucket.
+#[derive(Debug, Default, Serialize)]
+pub struct ReconcileReport {
+    /// Original keys named by metadata that don't exist in the bucket.
+    pub missing: Vec<String>,
+    /// Bucket objects that no metadata file accounts for.
+    pub orphaned: Vec<String>,
+    /// Original keys present in both.
+    pub matched: Vec<String>,
+}
 
 pub struct S3Mount {
-    client: Client,
+    store: Arc<dyn ObjectStore>,
 }
 
 impl S3Mount {
+    /// A mount on the image bucket.
     pub async fn new() -> Result<Self, MountError> {
-        // Load .env file
-        dotenv().ok();
-
-        let region_provider = RegionProviderChain::first_try(Region::new(REGION.to_string()))
-            .or_default_provider()
-            .or_else(Region::new("us-east-1"));
-
-        println!("Loading AWS config...");
-        let mut config_loader = aws_config::from_env().region(region_provider);
-
-        // Check for credentials in .env
-        if
-            let (Ok(access_key), Ok(secret_key)) = (
-                env::var("AWS_ACCESS_KEY_ID"),
-                env::var("AWS_SECRET_ACCESS_KEY"),
-            )
-        {
-            println!("Using credentials from .env file");
-            let creds = Credentials::new(access_key, secret_key, None, None, "dotenv");
-            config_loader = config_loader.credentials_provider(creds);
-        } else {
-            println!("No credentials in .env, falling back to default credential provider chain");
-        }
-
-        let config = config_loader.load().await;
-        // Create client and config separately
+        Ok(Self::with_store(Stores::from_env().await?.images))
+    }
 
-        println!("Creating S3 client...");
-        let client = Client::new(&config);
+    /// A mount on the bucket objects under `prefix` live in: the file bucket
+    /// for the static prefix, the image bucket otherwise.
+    pub async fn for_prefix(prefix: &str) -> Result<Self, MountError> {
+        let stores = Stores::from_env().await?;
+        Ok(Self::with_store(stores.for_key(prefix).clone()))
+    }
 
-        Ok(S3Mount {
-            client,
-        })
+    /// A mount reading from any object store, e.g. an in-memory one in tests.
+    pub fn with_store(store: Arc<dyn ObjectStore>) -> Self {
+        S3Mount { store }
     }
 
     pub fn create_local_dir(&self, dir_path: &str) -> Result<(), MountError> {
@@ -127,35 +208,203 @@ impl S3Mount {
         Ok(())
     }
 
-    pub async fn download_file(&self, key: &str, local_path: &str) -> Result<(), MountError> {
+    /// Downloads one object, streaming it to disk and printing progress every
+    /// `PROGRESS_STEP_BYTES`. An existing local file is only trusted when its
+    /// size matches the object's; otherwise it's treated as an interrupted
+    /// download and fetched again. Data is written to a `.part` file and
+    /// checked against the object's size and MD5 ETag before it's moved into
+    /// place. Returns the bytes written, or 0 when the file was kept.
+    pub async fn download_file(&self, key: &str, local_path: &str) -> Result<u64, MountError> {
 
-        println!("{}", format!("Downloading {} to {}", key, local_path).yellow().bold());
+        info!("Downloading {} to {}", key, local_path);
 
         // Check if file already exists
-        if Path::new(local_path).exists() {
-            println!("{}", "File already exists, skipping".bright_cyan().italic());
-            return Ok(());
+        if let Ok(local) = fs::metadata(local_path) {
+            let remote_size = self.store.head(key).await?.map(|head| head.size);
+
+            if remote_size == Some(local.len()) {
+                debug!("{} already exists, skipping", local_path);
+                return Ok(0);
+            }
+            warn!(
+                "Local copy is {} bytes but the object is {}; downloading again",
+                local.len(),
+                remote_size.map_or_else(|| "missing".to_string(), |size| size.to_string())
+            );
         }
 
         // Ensure the directory exists
         if let Some(parent) = Path::new(local_path).parent() {
             fs::create_dir_all(parent)?;
         }
-        
 
+        let object = self.store.get(key).await?;
+
+        let total = object.size;
+        let expected_md5 = object.md5;
+        let part_path = format!("{}.part", local_path);
+        let mut body = object.body;
+        let mut file = BufWriter::new(File::create(&part_path)?);
+        let mut md5 = Md5::new();
+        let mut written: u64 = 0;
+        let mut next_report = PROGRESS_STEP_BYTES;
+
+        let stall_limit = config::s3_timeout();
+        loop {
+            let next = match stall_limit {
+                Some(limit) => tokio::time::timeout(limit, body.try_next()).await.map_err(|_| {
+                    MountError::Other(format!("Download of {} stalled: no data for {:?}", key, limit))
+                })?,
+                None => body.try_next().await,
+            };
+            let Some(chunk) = next.map_err(|e| MountError::Other(e.to_string()))? else {
+                break;
+            };
+            file.write_all(&chunk)?;
+            md5.update(&chunk);
+            written += chunk.len() as u64;
+            if written >= next_report {
+                debug!("  {}: {}", key, progress_text(written, total));
+                next_report += PROGRESS_STEP_BYTES;
+            }
+        }
+        file.flush()?;
+        drop(file);
 
+        let actual_md5 = format!("{:x}", md5.finalize());
+        let problem = if total != 0 && written != total {
+            Some(format!("received {} of {} bytes", written, total))
+        } else {
+            expected_md5
+                .filter(|expected| *expected != actual_md5)
+                .map(|expected| format!("MD5 {} doesn't match ETag {}", actual_md5, expected))
+        };
+        if let Some(problem) = problem {
+            fs::remove_file(&part_path)?;
+            return Err(MountError::Other(format!("Download of {} failed verification: {}", key, problem)));
+        }
 
-        let get_object = self.client
-            .get_object()
-            .bucket(BUCKET_NAME.to_string())
-            .key(key)
-            .send().await?;
+        fs::rename(&part_path, local_path)?;
+        Ok(written)
+    }
 
-        let data = get_object.body.collect().await.map_err(|e| MountError::Other(e.to_string()))?;
+    /// Lists every key under `prefix`, however many pages the bucket returns.
+    pub async fn list_keys(&self, prefix: &str) -> Result<Vec<String>, MountError> {
+        Ok(self.store.list(prefix).await?)
+    }
 
-        fs::write(local_path, data.into_bytes())?;
+    /// Downloads each `(key, local_path)` pair, running up to
+    /// `DOWNLOAD_CONCURRENCY` at a time and collecting failures rather than
+    /// stopping at the first one.
+    pub async fn download_all(&self, downloads: Vec<(String, String)>) -> DownloadSummary {
+        let total = downloads.len();
+        let mut results = stream::iter(downloads)
+            .map(|(key, local_path)| async move {
+                let result = self.download_file(&key, &local_path).await;
+                (key, result)
+            })
+            .buffer_unordered(config::download_concurrency());
+
+        let mut summary = DownloadSummary::default();
+        while let Some((key, result)) = results.next().await {
+            match result {
+                Ok(bytes) => {
+                    summary.succeeded += 1;
+                    summary.bytes += bytes;
+                    info!(
+                        "[{}/{}] {} done, {} downloaded so far",
+                        summary.succeeded + summary.failed,
+                        total,
+                        key,
+                        megabytes(summary.bytes)
+                    );
+                }
+                Err(e) => {
+                    error!("[{}/{}] Error downloading {}: {}", summary.succeeded + summary.failed + 1, total, key, e);
+                    summary.failed += 1;
+                    summary.errors.push(format!("{}: {}", key, e));
+                }
+            }
+        }
 
-        Ok(())
+        info!(
+            "Downloads finished: {} succeeded, {} failed, {} transferred",
+            summary.succeeded,
+            summary.failed,
+            megabytes(summary.bytes)
+        );
+        summary
+    }
+
+    /// Mirrors every object under `prefix` into `local_dir`, keeping the key's
+    /// path structure. Existing files are skipped by `download_file`.
+    pub async fn download_prefix(&self, prefix: &str, local_dir: &str) -> Result<DownloadSummary, MountError> {
+        let keys: Vec<String> = self.list_keys(prefix).await?
+            .into_iter()
+            // Skip folder markers
+            .filter(|key| !key.ends_with('/'))
+            .collect();
+        info!("Found {} objects under {:?}", keys.len(), prefix);
+
+        let downloads = keys
+            .into_iter()
+            .filter_map(|key| {
+                let Some(local_path) = local_path_for(Path::new(local_dir), &key) else {
+                    warn!("Skipping {:?}: the key would be written outside {:?}", key, local_dir);
+                    return None;
+                };
+                Some((key, local_path.to_string_lossy().into_owned()))
+            })
+            .collect();
+
+        Ok(self.download_all(downloads).await)
+    }
+
+    /// Audits the asset catalog: which metadata points at missing objects,
+    /// which image objects have no metadata, and which line up.
+    pub async fn reconcile(&self) -> Result<ReconcileReport, MountError> {
+        let s3 = config::s3();
+        let metadata = Self::get_image_metadata();
+        let uids: HashSet<&str> = metadata.iter().map(|m| m.uid.as_str()).collect();
+
+        let mut objects: HashSet<String> = HashSet::new();
+        for key in self.list_keys(&s3.image_prefix).await? {
+            // An empty image prefix lists the whole bucket, which holds the
+            // static files too unless FILE_BUCKET moved them elsewhere
+            let shared_bucket = s3.image_bucket == s3.file_bucket;
+            if shared_bucket && !s3.static_prefix.is_empty() && key.starts_with(&s3.static_prefix) {
+                continue;
+            }
+            objects.insert(key);
+        }
+
+        let mut report = ReconcileReport::default();
+        for image in &metadata {
+            if objects.contains(&image.key) {
+                report.matched.push(image.key.clone());
+            } else {
+                report.missing.push(image.key.clone());
+            }
+        }
+        for key in &objects {
+            let name = key.strip_prefix(s3.image_prefix.as_str()).unwrap_or(key);
+            if !uids.contains(owner_uid(name)) {
+                report.orphaned.push(key.clone());
+            }
+        }
+
+        report.missing.sort();
+        report.orphaned.sort();
+        report.matched.sort();
+        info!(
+            "Reconciled {} metadata files: {} matched, {} missing, {} orphaned objects",
+            metadata.len(),
+            report.matched.len(),
+            report.missing.len(),
+            report.orphaned.len()
+        );
+
+        Ok(report)
     }
 
     // Function to read image metadata files
@@ -164,29 +413,113 @@ impl S3Mount {
 
         match fs::read_dir(IMAGE_DATA_DIR) {
             Ok(entries) => {
-                for entry in entries {
-                    if let Ok(entry) = entry {
-                        let path = entry.path();
-                        if path.extension().and_then(|s| s.to_str()) == Some("yml") {
-                            match fs::read_to_string(&path) {
-                                Ok(content) => {
-                                    if let Some((uid, format)) = parse_image_yaml(&content) {
-                                        images.push(ImageMetadata {
-                                            key: format!("{}.{}", uid, format),
-                                            uid,
-                                            format,
-                                        });
-                                    }
+                for entry in entries.flatten() {
+                    let path = entry.path();
+                    if path.extension().and_then(|s| s.to_str()) == Some("yml") {
+                        match fs::read_to_string(&path) {
+                            Ok(content) => {
+                                match parse_image_yaml(&content) {
+                                    Ok(yaml) => images.push(ImageMetadata {
+                                        key: yaml.key.unwrap_or_else(|| {
+                                            format!("{}{}.{}", config::s3().image_prefix, yaml.uid, yaml.format)
+                                        }),
+                                        uid: yaml.uid,
+                                        format: yaml.format,
+                                        width: yaml.width,
+                                        height: yaml.height,
+                                        alt: yaml.alt.unwrap_or_default(),
+                                    }),
+                                    Err(err) => warn!("Error parsing {}: {}", path.display(), err),
                                 }
-                                Err(err) => eprintln!("Error reading {}: {}", path.display(), err),
                             }
+                            Err(err) => warn!("Error reading {}: {}", path.display(), err),
                         }
                     }
                 }
             }
-            Err(err) => eprintln!("Error reading metadata directory: {}", err),
+            Err(err) => warn!("Error reading metadata directory: {}", err),
         }
 
         images
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_generated_metadata() {
+        let yaml = parse_image_yaml(r#"
+# https://s3.amazonaws.com/digitalgov/2024.png
+date     :  2024-01-02 03:04:05 -0400
+uid      :  2024
+width    :  1200
+height   :  800
+format   :  png
+alt      :  "Caption: with a colon"
+"#).unwrap();
+
+        assert_eq!(yaml.uid, "2024");
+        assert_eq!(yaml.format, "png");
+        assert_eq!((yaml.width, yaml.height), (Some(1200), Some(800)));
+        assert_eq!(yaml.alt.as_deref(), Some("Caption: with a colon"));
+    }
+
+    #[test]
+    fn downloaded_keys_stay_inside_the_local_dir() {
+        let dir = Path::new("assets");
+        assert_eq!(local_path_for(dir, "static/report.pdf"), Some(dir.join("static/report.pdf")));
+        assert_eq!(local_path_for(dir, "/etc/passwd"), None);
+        assert_eq!(local_path_for(dir, "static/../../secrets.txt"), None);
+        assert_eq!(local_path_for(dir, ".."), None);
+    }
+
+    #[test]
+    fn progress_text_shows_percentage_when_size_is_known() {
+        assert_eq!(progress_text(12 * 1024 * 1024, 48 * 1024 * 1024), "12.0 MB of 48.0 MB (25%)");
+        assert_eq!(progress_text(1024 * 1024, 0), "1.0 MB");
+    }
+
+    #[test]
+    fn owner_uid_strips_extension_and_width_suffix() {
+        assert_eq!(owner_uid("photo.png"), "photo");
+        assert_eq!(owner_uid("photo_w800.webp"), "photo");
+        assert_eq!(owner_uid("old_west.jpg"), "old_west");
+        assert_eq!(owner_uid("report.v2.png"), "report.v2");
+        assert_eq!(owner_uid("photo_w800@2x.png"), "photo");
+        assert_eq!(owner_uid("photo_thumb150.png"), "photo");
+        assert_eq!(owner_uid("photo@home.png"), "photo@home");
+        assert_eq!(owner_uid("2024/06/photo_w800.png"), "photo");
+        assert_eq!(owner_uid("photo_w800@2x.abc12345.png"), "photo");
+        assert_eq!(owner_uid("photo_thumb150.abc12345.png"), "photo");
+        assert_eq!(owner_uid("notes.deadbeef.png"), "notes.deadbeef");
+    }
+
+    #[tokio::test]
+    async fn download_file_verifies_and_keeps_complete_copies() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let source = dir.join("source.png");
+        fs::write(&source, b"not really a png").unwrap();
+
+        let store = crate::store::MemoryStore::default();
+        let options = crate::store::PutOptions {
+            content_type: "image/png",
+            storage_class: aws_sdk_s3::types::StorageClass::Standard,
+            acl: None,
+            tagging: None,
+        };
+        store.put("photo.png", &source, &options).await.unwrap();
+        let mount = S3Mount::with_store(Arc::new(store));
+
+        let target = dir.join("photo.png").to_string_lossy().into_owned();
+        let first = mount.download_file("photo.png", &target).await.unwrap();
+        let second = mount.download_file("photo.png", &target).await.unwrap();
+        let content = fs::read(&target).unwrap();
+
+        assert_eq!(first, 16);
+        assert_eq!(second, 0);
+        assert_eq!(content, b"not really a png");
+    }
+}
@@ -0,0 +1,158 @@
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{
+    CompletedMultipartUpload, CompletedPart, ObjectCannedAcl, StorageClass,
+};
+use aws_sdk_s3::Client;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use log::{error, info, warn};
+use md5::{Digest, Md5};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::config::Encryption;
+use crate::error::UploadError;
+use crate::retry;
+
+/// Size of each uploaded part. S3 requires at least 5 MB for all but the last.
+const PART_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Parts in flight at once; bounds memory to roughly this many `PART_SIZE` buffers.
+const PART_CONCURRENCY: usize = 4;
+
+/// Where and how a multipart upload is stored; mirrors the `put_object`
+/// settings used for small files.
+pub struct UploadTarget<'a> {
+    pub bucket: &'a str,
+    pub key: &'a str,
+    pub content_type: &'a str,
+    pub storage_class: StorageClass,
+    pub encryption: &'a Encryption,
+    pub acl: Option<ObjectCannedAcl>,
+    pub tagging: Option<&'a str>,
+}
+
+/// Reads one part of the file into memory.
+fn read_part(path: &Path, offset: u64, len: u64) -> std::io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0; len as usize];
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Uploads `path` in `PART_SIZE` parts, aborting the multipart upload if any
+/// part fails so no orphaned parts are left billing in the bucket.
+pub async fn upload(client: &Client, target: &UploadTarget<'_>, path: &Path) -> Result<(), UploadError> {
+    let size = std::fs::metadata(path)?.len();
+
+    let created = client
+        .create_multipart_upload()
+        .bucket(target.bucket)
+        .key(target.key)
+        .content_type(target.content_type)
+        .storage_class(target.storage_class.clone())
+        .set_acl(target.acl.clone())
+        .set_tagging(target.tagging.map(String::from))
+        .set_server_side_encryption(target.encryption.server_side_encryption())
+        .set_ssekms_key_id(target.encryption.kms_key_id())
+        .send()
+        .await?;
+    let upload_id = created
+        .upload_id()
+        .ok_or("create_multipart_upload returned no upload id")?
+        .to_string();
+
+    info!("Started multipart upload of {:?} ({} bytes) to {}", path, size, target.key);
+
+    match upload_parts(client, target, path, size, &upload_id).await {
+        Ok(parts) => {
+            client
+                .complete_multipart_upload()
+                .bucket(target.bucket)
+                .key(target.key)
+                .upload_id(&upload_id)
+                .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+                .send()
+                .await?;
+            info!("Completed multipart upload to {}", target.key);
+            Ok(())
+        }
+        Err(err) => {
+            warn!("Aborting multipart upload to {}: {}", target.key, err);
+            if let Err(abort_err) = client
+                .abort_multipart_upload()
+                .bucket(target.bucket)
+                .key(target.key)
+                .upload_id(&upload_id)
+                .send()
+                .await
+            {
+                error!("Failed to abort multipart upload {}: {}", upload_id, abort_err);
+            }
+            Err(err)
+        }
+    }
+}
+
+async fn upload_part(
+    client: &Client,
+    target: &UploadTarget<'_>,
+    path: &Path,
+    upload_id: &str,
+    part_number: i32,
+    offset: u64,
+    len: u64
+) -> Result<CompletedPart, UploadError> {
+    let body = bytes::Bytes::from(read_part(path, offset, len)?);
+    let content_md5 = BASE64.encode(Md5::digest(&body));
+
+    let label = format!("{} part {}", target.key, part_number);
+    let output = retry::with_retry(&label, || {
+        client
+            .upload_part()
+            .bucket(target.bucket)
+            .key(target.key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .content_md5(&content_md5)
+            .body(ByteStream::from(body.clone()))
+            .send()
+    })
+    .await?;
+
+    Ok(
+        CompletedPart::builder()
+            .set_e_tag(output.e_tag().map(str::to_string))
+            .part_number(part_number)
+            .build()
+    )
+}
+
+/// Uploads every part with at most `PART_CONCURRENCY` in flight, returning
+/// them in part-number order as `complete_multipart_upload` requires.
+async fn upload_parts(
+    client: &Client,
+    target: &UploadTarget<'_>,
+    path: &Path,
+    size: u64,
+    upload_id: &str
+) -> Result<Vec<CompletedPart>, UploadError> {
+    let ranges = (0..size)
+        .step_by(PART_SIZE as usize)
+        .enumerate()
+        .map(|(index, offset)| ((index + 1) as i32, offset, PART_SIZE.min(size - offset)));
+
+    let mut parts = stream::iter(ranges)
+        .map(|(part_number, offset, len)| {
+            upload_part(client, target, path, upload_id, part_number, offset, len)
+        })
+        .buffer_unordered(PART_CONCURRENCY)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    parts.sort_by_key(|part| part.part_number());
+    Ok(parts)
+}
@@ -0,0 +1,37 @@
+use std::path::Path;
+
+use crate::error::UploadError;
+
+/// Width of the first-page preview rendered for PDFs.
+pub const PDF_THUMBNAIL_WIDTH: u32 = 400;
+
+/// Renders the first page of `pdf` to a PNG at `output`, `width` pixels wide.
+/// PDFium is loaded at runtime from `PDFIUM_LIBRARY_DIR` when set, otherwise
+/// from the system library path.
+#[cfg(feature = "pdf-thumbnails")]
+pub fn render_first_page(pdf: &Path, output: &Path, width: u32) -> Result<(), UploadError> {
+    use image::ImageFormat;
+    use pdfium_render::prelude::*;
+
+    let to_error = |e: PdfiumError| UploadError::Other(format!("PDF render failed: {}", e));
+
+    let bindings = match std::env::var("PDFIUM_LIBRARY_DIR") {
+        Ok(dir) => Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path(&dir)),
+        Err(_) => Pdfium::bind_to_system_library(),
+    }
+    .map_err(to_error)?;
+    let pdfium = Pdfium::new(bindings);
+
+    let document = pdfium.load_pdf_from_file(pdf, None).map_err(to_error)?;
+    let page = document.pages().first().map_err(to_error)?;
+    let rendered = page
+        .render_with_config(&PdfRenderConfig::new().set_target_width(width as i32))
+        .map_err(to_error)?;
+    rendered.as_image().save_with_format(output, ImageFormat::Png)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "pdf-thumbnails"))]
+pub fn render_first_page(_pdf: &Path, _output: &Path, _width: u32) -> Result<(), UploadError> {
+    Err(UploadError::Other("PDF thumbnails need a build with the pdf-thumbnails feature".to_string()))
+}
@@ -0,0 +1,60 @@
+use aws_sdk_s3::error::SdkError;
+use log::warn;
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAX_RETRIES: u32 = 3;
+const BASE_DELAY_MS: u64 = 200;
+const MAX_JITTER_MS: u64 = 100;
+
+/// Whether an S3 error is worth retrying: timeouts, dropped connections,
+/// throttling and 5xx responses. Client errors such as AccessDenied are not.
+pub fn is_retryable<E>(err: &SdkError<E>) -> bool {
+    match err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => true,
+        SdkError::ServiceError(context) => {
+            let status = context.raw().http().status();
+            status.is_server_error() || status.as_u16() == 429
+        }
+        _ => false,
+    }
+}
+
+/// Exponential backoff (200ms, 400ms, 800ms, ...) plus a little jitter.
+fn backoff_delay(attempt: u32) -> Duration {
+    let jitter = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| u64::from(d.subsec_nanos()) % MAX_JITTER_MS)
+        .unwrap_or(0);
+    Duration::from_millis(BASE_DELAY_MS * 2u64.pow(attempt) + jitter)
+}
+
+/// Runs an S3 operation, retrying retryable failures up to `MAX_RETRIES`
+/// times. Timeouts from the client's `S3_TIMEOUT_SECS` connect and read
+/// limits count as retryable. Non-retryable errors are returned immediately.
+pub async fn with_retry<T, E, F, Fut>(label: &str, mut operation: F) -> Result<T, SdkError<E>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, SdkError<E>>>,
+{
+    let mut attempt = 0;
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < MAX_RETRIES && is_retryable(&err) => {
+                let delay = backoff_delay(attempt);
+                attempt += 1;
+                warn!(
+                    "Retrying {} (attempt {}/{}) in {:?} after error: {}",
+                    label,
+                    attempt,
+                    MAX_RETRIES,
+                    delay,
+                    err
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
@@ -0,0 +1,414 @@
+use async_trait::async_trait;
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{ObjectCannedAcl, ServerSideEncryption, StorageClass};
+use aws_sdk_s3::Client;
+use log::debug;
+use std::fs;
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::config::{self, Encryption};
+use crate::error::UploadError;
+use crate::{file_checksums, multipart, retry};
+
+/// How an object is stored by `ObjectStore::put`.
+#[derive(Debug, Clone)]
+pub struct PutOptions<'a> {
+    pub content_type: &'a str,
+    pub storage_class: StorageClass,
+    pub acl: Option<ObjectCannedAcl>,
+    /// URL-encoded `key=value&...` object tags.
+    pub tagging: Option<&'a str>,
+}
+
+/// An object's body along with what's needed to verify it once read.
+pub struct StoredObject {
+    /// Size in bytes, or 0 when the store didn't say.
+    pub size: u64,
+    /// Hex MD5 of the body, when the store's ETag is one.
+    pub md5: Option<String>,
+    pub body: ByteStream,
+}
+
+/// What `ObjectStore::head` reports about an existing object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectHead {
+    /// Size in bytes, or 0 when the store didn't say.
+    pub size: u64,
+    /// Hex MD5 of the body, when the store's ETag is one.
+    pub md5: Option<String>,
+}
+
+/// The object storage operations the pipeline relies on. `S3Store` talks to
+/// the bucket; tests substitute an in-memory fake.
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    /// Uploads the file at `path` to `key`.
+    async fn put(&self, key: &str, path: &Path, options: &PutOptions<'_>) -> Result<(), UploadError>;
+
+    /// Opens the object at `key` for reading.
+    async fn get(&self, key: &str) -> Result<StoredObject, UploadError>;
+
+    /// Size and checksum of the object at `key`, or `None` if there isn't one.
+    async fn head(&self, key: &str) -> Result<Option<ObjectHead>, UploadError>;
+
+    async fn delete(&self, key: &str) -> Result<(), UploadError>;
+
+    /// Every key under `prefix`.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, UploadError>;
+
+    async fn exists(&self, key: &str) -> Result<bool, UploadError> {
+        Ok(self.head(key).await?.is_some())
+    }
+
+    /// The bucket public URLs for this store's objects point at.
+    fn bucket(&self) -> &str {
+        &config::s3().bucket
+    }
+}
+
+/// Where each kind of upload goes. Images and static files share
+/// `AWS_BUCKET_NAME` unless `IMAGE_BUCKET` and `FILE_BUCKET` split them.
+#[derive(Clone)]
+pub struct Stores {
+    pub images: Arc<dyn ObjectStore>,
+    pub files: Arc<dyn ObjectStore>,
+}
+
+impl Stores {
+    /// Sends images and files alike to one store.
+    pub fn single(store: Arc<dyn ObjectStore>) -> Self {
+        Stores { images: store.clone(), files: store }
+    }
+
+    /// S3 stores for the configured image and file buckets.
+    pub async fn from_env() -> Result<Self, UploadError> {
+        Ok(Self::for_client(crate::s3_client().await?))
+    }
+
+    /// S3 stores for the configured image and file buckets on `client`.
+    pub fn for_client(client: Client) -> Self {
+        let s3 = config::s3();
+        Stores {
+            images: Arc::new(S3Store::for_bucket(client.clone(), &s3.image_bucket)),
+            files: Arc::new(S3Store::for_bucket(client, &s3.file_bucket)),
+        }
+    }
+
+    /// The store `key`, or a prefix, belongs in: the file store under the
+    /// static prefix, the image store otherwise.
+    pub fn for_key(&self, key: &str) -> &Arc<dyn ObjectStore> {
+        let static_prefix = &config::s3().static_prefix;
+        if !static_prefix.is_empty() && key.starts_with(static_prefix.as_str()) {
+            &self.files
+        } else {
+            &self.images
+        }
+    }
+}
+
+/// The MD5 hex digest an ETag stands for, when it stands for one. Multipart
+/// ETags (containing `-`) and SSE-KMS objects use other schemes.
+fn md5_etag<'a>(e_tag: Option<&'a str>, encryption: Option<&ServerSideEncryption>) -> Option<&'a str> {
+    if encryption == Some(&ServerSideEncryption::AwsKms) {
+        return None;
+    }
+    let e_tag = e_tag?.trim_matches('"');
+    (e_tag.len() == 32 && e_tag.bytes().all(|b| b.is_ascii_hexdigit())).then_some(e_tag)
+}
+
+/// Drives a `list_objects_v2` listing to completion: `fetch_page` is called
+/// with each continuation token until a page comes back untruncated. S3
+/// returns at most 1000 keys per page, so stopping early gives wrong answers.
+async fn collect_keys<F, Fut, E>(mut fetch_page: F) -> Result<Vec<String>, UploadError>
+where
+    F: FnMut(Option<String>) -> Fut,
+    Fut: Future<Output = Result<ListObjectsV2Output, E>>,
+    UploadError: From<E>,
+{
+    let mut keys = Vec::new();
+    let mut continuation_token = None;
+
+    loop {
+        let page = fetch_page(continuation_token).await?;
+        keys.extend(page.contents().unwrap_or_default().iter().filter_map(|o| o.key().map(String::from)));
+
+        continuation_token = page.next_continuation_token().map(String::from);
+        if !page.is_truncated() || continuation_token.is_none() {
+            break;
+        }
+    }
+
+    Ok(keys)
+}
+
+/// An S3 bucket, by default the configured `AWS_BUCKET_NAME`.
+pub struct S3Store {
+    client: Client,
+    bucket: String,
+    encryption: Encryption,
+}
+
+impl S3Store {
+    pub fn new(client: Client) -> Self {
+        Self::for_bucket(client, &config::s3().bucket)
+    }
+
+    pub fn for_bucket(client: Client, bucket: &str) -> Self {
+        S3Store {
+            client,
+            bucket: bucket.to_string(),
+            encryption: Encryption::from_env(),
+        }
+    }
+
+    /// Builds a store on a client from `.env` credentials or the default chain.
+    pub async fn from_env() -> Result<Self, UploadError> {
+        Ok(Self::new(crate::s3_client().await?))
+    }
+}
+
+/// Whether a file of `size` bytes goes up through the multipart API.
+pub(crate) fn uses_multipart(size: u64) -> bool {
+    size > config::multipart_threshold_bytes()
+}
+
+#[async_trait]
+impl ObjectStore for S3Store {
+    async fn put(&self, key: &str, path: &Path, options: &PutOptions<'_>) -> Result<(), UploadError> {
+        if uses_multipart(fs::metadata(path)?.len()) {
+            let target = multipart::UploadTarget {
+                bucket: &self.bucket,
+                key,
+                content_type: options.content_type,
+                storage_class: options.storage_class.clone(),
+                encryption: &self.encryption,
+                acl: options.acl.clone(),
+                tagging: options.tagging,
+            };
+            return multipart::upload(&self.client, &target, path).await;
+        }
+
+        // S3 rejects the request if the received body doesn't match these
+        let (content_md5, checksum_sha256) = file_checksums(path)?;
+        debug!("Checksums for {}: md5={} sha256={}", key, content_md5, checksum_sha256);
+
+        debug!("Uploading file: {:?} to S3 key: {}", path, key);
+        retry::with_retry(key, || async {
+            // The body is streamed from disk, so reopen it for every attempt
+            let body = ByteStream::from_path(path).await.map_err(SdkError::construction_failure)?;
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .body(body)
+                .content_type(options.content_type)
+                .content_md5(&content_md5)
+                .checksum_sha256(&checksum_sha256)
+                .set_acl(options.acl.clone())
+                .set_tagging(options.tagging.map(String::from))
+                .storage_class(options.storage_class.clone())
+                .set_server_side_encryption(self.encryption.server_side_encryption())
+                .set_ssekms_key_id(self.encryption.kms_key_id())
+                .send()
+                .await
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<StoredObject, UploadError> {
+        let output = retry::with_retry(key, || {
+            self.client.get_object().bucket(&self.bucket).key(key).send()
+        })
+        .await?;
+
+        Ok(StoredObject {
+            size: u64::try_from(output.content_length()).unwrap_or(0),
+            md5: md5_etag(output.e_tag(), output.server_side_encryption()).map(String::from),
+            body: output.body,
+        })
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectHead>, UploadError> {
+        let result = retry::with_retry(key, || {
+            self.client.head_object().bucket(&self.bucket).key(key).send()
+        })
+        .await;
+
+        match result {
+            Ok(head) => Ok(Some(ObjectHead {
+                size: u64::try_from(head.content_length()).unwrap_or(0),
+                md5: md5_etag(head.e_tag(), head.server_side_encryption()).map(String::from),
+            })),
+            Err(SdkError::ServiceError(err)) if err.err().is_not_found() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), UploadError> {
+        retry::with_retry(key, || {
+            self.client.delete_object().bucket(&self.bucket).key(key).send()
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, UploadError> {
+        collect_keys(|continuation_token| {
+            retry::with_retry(prefix, move || {
+                self.client
+                    .list_objects_v2()
+                    .bucket(&self.bucket)
+                    .prefix(prefix)
+                    .set_continuation_token(continuation_token.clone())
+                    .send()
+            })
+        })
+        .await
+    }
+
+    fn bucket(&self) -> &str {
+        &self.bucket
+    }
+}
+
+/// An `ObjectStore` held in memory, for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MemoryStore {
+    objects: std::sync::Mutex<std::collections::BTreeMap<String, Vec<u8>>>,
+    bucket: Option<String>,
+    reject_puts: bool,
+}
+
+#[cfg(test)]
+impl MemoryStore {
+    pub(crate) fn with_keys(keys: &[&str]) -> Self {
+        let store = Self::default();
+        store.objects.lock().unwrap().extend(keys.iter().map(|key| (key.to_string(), Vec::new())));
+        store
+    }
+
+    /// A store standing in for `bucket` in public URLs.
+    pub(crate) fn in_bucket(bucket: &str) -> Self {
+        MemoryStore { bucket: Some(bucket.to_string()), ..Self::default() }
+    }
+
+    /// A store whose every upload fails, as when the bucket is unreachable.
+    pub(crate) fn rejecting_puts() -> Self {
+        MemoryStore { reject_puts: true, ..Self::default() }
+    }
+
+    pub(crate) fn keys(&self) -> Vec<String> {
+        self.objects.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl ObjectStore for MemoryStore {
+    async fn put(&self, key: &str, path: &Path, _options: &PutOptions<'_>) -> Result<(), UploadError> {
+        if self.reject_puts {
+            return Err(UploadError::Other(format!("Upload of {} rejected", key)));
+        }
+        let body = fs::read(path)?;
+        self.objects.lock().unwrap().insert(key.to_string(), body);
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<StoredObject, UploadError> {
+        use md5::{Digest, Md5};
+
+        let body = self.objects.lock().unwrap().get(key).cloned()
+            .ok_or_else(|| UploadError::Other(format!("No such key: {}", key)))?;
+        Ok(StoredObject {
+            size: body.len() as u64,
+            md5: Some(format!("{:x}", Md5::digest(&body))),
+            body: ByteStream::from(body),
+        })
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectHead>, UploadError> {
+        use md5::{Digest, Md5};
+
+        Ok(self.objects.lock().unwrap().get(key).map(|body| ObjectHead {
+            size: body.len() as u64,
+            md5: Some(format!("{:x}", Md5::digest(body))),
+        }))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), UploadError> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, UploadError> {
+        Ok(self.keys().into_iter().filter(|key| key.starts_with(prefix)).collect())
+    }
+
+    fn bucket(&self) -> &str {
+        self.bucket.as_deref().unwrap_or(&config::s3().bucket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use aws_sdk_s3::types::Object;
+
+    fn page(keys: &[&str], next_token: Option<&str>) -> ListObjectsV2Output {
+        let mut builder = ListObjectsV2Output::builder()
+            .is_truncated(next_token.is_some())
+            .set_next_continuation_token(next_token.map(String::from));
+        for key in keys {
+            builder = builder.contents(Object::builder().key(*key).build());
+        }
+        builder.build()
+    }
+
+    #[tokio::test]
+    async fn collect_keys_follows_continuation_tokens() {
+        let mut requested_tokens = Vec::new();
+        let keys = collect_keys(|token: Option<String>| {
+            requested_tokens.push(token.clone());
+            let response = match token.as_deref() {
+                None => page(&["a.png", "a_w200.png"], Some("page-2")),
+                Some("page-2") => page(&["b.png"], Some("page-3")),
+                Some("page-3") => page(&["c.png"], None),
+                Some(other) => panic!("unexpected token {}", other),
+            };
+            async move { Ok::<_, UploadError>(response) }
+        }).await.unwrap();
+
+        assert_eq!(keys, vec!["a.png", "a_w200.png", "b.png", "c.png"]);
+        assert_eq!(
+            requested_tokens,
+            vec![None, Some("page-2".to_string()), Some("page-3".to_string())]
+        );
+    }
+
+    #[test]
+    fn static_keys_belong_in_the_file_store() {
+        let stores = Stores {
+            images: Arc::new(MemoryStore::in_bucket("media")),
+            files: Arc::new(MemoryStore::in_bucket("datasets")),
+        };
+        let static_key = format!("{}report.pdf", config::s3().static_prefix);
+        assert_eq!(stores.for_key(&static_key).bucket(), "datasets");
+        assert_eq!(stores.for_key(&format!("{}photo.png", config::s3().image_prefix)).bucket(), "media");
+    }
+
+    #[test]
+    fn only_single_part_etags_are_md5s() {
+        let md5 = "\"9e107d9d372bb6826bd81d3542a419d6\"";
+        assert_eq!(md5_etag(Some(md5), None), Some("9e107d9d372bb6826bd81d3542a419d6"));
+        assert_eq!(md5_etag(Some("\"9e107d9d372bb6826bd81d3542a419d6-3\""), None), None);
+        assert_eq!(md5_etag(Some(md5), Some(&ServerSideEncryption::AwsKms)), None);
+    }
+}
@@ -1,69 +1,23 @@
-use aws_sdk_s3::{Client, Error as S3Error, primitives::ByteStream, error::SdkError};
-use std::fmt;
+use aws_sdk_s3::{Client, presigning::PresigningConfig, types::StorageClass};
 use std::fs;
 use std::path::Path;
-use mime_guess::from_path;
+use std::time::Duration;
 use image::{ImageFormat, DynamicImage};
-use chrono::Local;
+use log::{debug, info, warn};
 use serde_yaml;
-use std::collections::HashMap;
-use image::imageops::FilterType;
-
-const VARIANT_SETTINGS: &[(&str, u32)] = &[
-    ("mobile", 200),
-    ("tablet", 400),
-    ("desktop_md", 800),
-    ("desktop_lg", 1200),
-];
-
-#[derive(Debug)]
-pub enum UploadError {
-    S3Error(S3Error),
-    IoError(std::io::Error),
-    ImageError(image::ImageError),
-    Other(String),
-}
-
-impl fmt::Display for UploadError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            UploadError::S3Error(err) => write!(f, "S3 Error: {}", err),
-            UploadError::IoError(err) => write!(f, "IO Error: {}", err),
-            UploadError::ImageError(err) => write!(f, "Image Error: {}", err),
-            UploadError::Other(err) => write!(f, "Error: {}", err),
-        }
-    }
-}
-
-impl From<S3Error> for UploadError {
-    fn from(err: S3Error) -> Self {
-        UploadError::S3Error(err)
-    }
-}
-
-impl From<std::io::Error> for UploadError {
-    fn from(err: std::io::Error) -> Self {
-        UploadError::IoError(err)
-    }
-}
-
-impl From<image::ImageError> for UploadError {
-    fn from(err: image::ImageError) -> Self {
-        UploadError::ImageError(err)
-    }
-}
-
-impl From<String> for UploadError {
-    fn from(err: String) -> Self {
-        UploadError::Other(err)
-    }
-}
-
-impl<E> From<SdkError<E>> for UploadError {
-    fn from(err: SdkError<E>) -> Self {
-        UploadError::Other(err.to_string())
-    }
-}
+use ravif::{Encoder, Img, RGBA8};
+
+use crate::config::{
+    env_flag, metadata_date, object_acl, object_tagging, resize_filter, s3, variant_quality, variant_storage_class,
+    Encryption,
+};
+use crate::error::UploadError;
+use crate::store::{ObjectStore, PutOptions, Stores};
+use crate::{
+    crop, compute_blurhash, content_type_for, extension_lossy, file_name_lossy, file_stem_lossy, is_image,
+    needs_png_normalization, optimize_png, rgba_if_transparent, s3_client, save_image, TempFile,
+};
+use crate::variants::VARIANT_SETTINGS;
 
 #[derive(Debug, serde::Serialize)]
 struct ImageMetadata {
@@ -72,9 +26,11 @@ struct ImageMetadata {
     width: u32,
     height: u32,
     format: String,
+    blurhash: String,
     alt: String,
     caption: String,
     credit: String,
+    keys: Vec<String>,
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -85,36 +41,80 @@ struct FileMetadata {
 }
 
 pub struct S3Config {
-    bucket: String,
+    webp: bool,
+    avif: bool,
+    convert_jpg_to_png: bool,
+    quality: u8,
 }
 
 impl S3Config {
     pub fn new() -> Self {
-        let bucket = std::env::var("AWS_BUCKET_NAME")
-            .expect("AWS_BUCKET_NAME must be set");
-        
-        S3Config { bucket }
+        let webp = env_flag("EMIT_WEBP");
+        let avif = env_flag("EMIT_AVIF");
+        let convert_jpg_to_png = env_flag("CONVERT_JPG_TO_PNG");
+        let quality = variant_quality();
+
+        S3Config { webp, avif, convert_jpg_to_png, quality }
     }
 }
 
+impl Default for S3Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// AVIF encoder speed (1-10); 8 keeps batch runs reasonable at a small size cost.
+const AVIF_SPEED: u8 = 8;
+
+/// Encodes an image as AVIF with ravif at the given quality (1-100).
+fn save_avif(img: &DynamicImage, path: &Path, quality: u8) -> Result<(), UploadError> {
+    let rgba = img.to_rgba8();
+    let pixels: Vec<RGBA8> = rgba
+        .pixels()
+        .map(|p| RGBA8::new(p[0], p[1], p[2], p[3]))
+        .collect();
+
+    let encoded = Encoder::new()
+        .with_quality(f32::from(quality.clamp(1, 100)))
+        .with_speed(AVIF_SPEED)
+        .encode_rgba(Img::new(&pixels[..], rgba.width() as usize, rgba.height() as usize))
+        .map_err(|e| UploadError::Other(format!("AVIF encode failed: {}", e)))?;
+
+    fs::write(path, encoded.avif_file)?;
+    Ok(())
+}
+
+/// A presigned PUT and the headers its upload has to send.
+#[derive(Debug)]
+pub struct PresignedPut {
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+}
+
 pub struct S3Upload {
     config: S3Config,
     client: Client,
+    stores: Stores,
 }
 
 impl S3Upload {
     pub async fn new() -> Result<Self, UploadError> {
-        let config = aws_config::from_env().load().await;
-        let client = Client::new(&config);
-        let s3_config = S3Config::new();
-        
-        Ok(S3Upload {
-            config: s3_config,
+        let client = s3_client().await?;
+        Ok(Self::with_stores(client.clone(), Stores::for_client(client)))
+    }
+
+    /// An uploader writing to any object stores; `client` is only used to
+    /// presign URLs, against the bucket of the store each key belongs in.
+    pub fn with_stores(client: Client, stores: Stores) -> Self {
+        S3Upload {
+            config: S3Config::new(),
             client,
-        })
+            stores,
+        }
     }
 
-    async fn convert_jpg_to_png(&self, image_path: &Path) -> Result<String, UploadError> {
+    async fn convert_to_png(&self, image_path: &Path) -> Result<String, UploadError> {
         let img = image::open(image_path)?;
         let new_path = image_path.with_extension("png");
         img.save_with_format(&new_path, ImageFormat::Png)?;
@@ -126,42 +126,78 @@ impl S3Upload {
         Ok(new_path.to_string_lossy().into_owned())
     }
 
-    async fn create_image_variants(&self, image_path: &Path, processed_dir: &Path) -> Result<Vec<String>, UploadError> {
-        let img = image::open(image_path)?;
+    /// Writes every variant into `processed_dir`. The files are deleted when
+    /// the returned guards drop, and any already written are removed if a
+    /// later variant fails.
+    async fn create_image_variants(&self, image_path: &Path, processed_dir: &Path) -> Result<Vec<TempFile>, UploadError> {
+        let img = rgba_if_transparent(image::open(image_path)?);
+        let filter = resize_filter();
         let mut variant_paths = Vec::new();
 
-        for (variant_name, width) in VARIANT_SETTINGS {
-            let filename = image_path.file_stem().unwrap().to_string_lossy();
-            let extension = image_path.extension().unwrap().to_string_lossy();
-            let variant_filename = format!("{}_w{}.{}", filename, width, extension);
-            let variant_path = processed_dir.join(&variant_filename);
-
-            let height = (img.height() as f32 * (*width as f32 / img.width() as f32)) as u32;
-            let resized = img.resize_exact(*width, height, FilterType::Lanczos3);
-            resized.save(&variant_path)?;
+        for (variant_name, variant) in VARIANT_SETTINGS.iter() {
+            let cropped = variant.crop_region(img.width(), img.height()).map(|mut region| crop::apply(&img, &mut region));
+            let source = cropped.as_ref().unwrap_or(&img);
+            if !variant.downscales(source.width(), source.height()) {
+                debug!(
+                    "Skipping {} variant: {}px is not smaller than the {}x{} source",
+                    variant_name,
+                    variant.width,
+                    source.width(),
+                    source.height()
+                );
+                continue;
+            }
 
-            variant_paths.push(variant_path.to_string_lossy().into_owned());
+            let filename = file_stem_lossy(image_path)?;
+            let extension = extension_lossy(image_path)?;
+            let variant_filename = format!("{}{}.{}", filename, variant.suffix(variant_name), extension);
+            let variant_file = TempFile::new(processed_dir.join(&variant_filename));
+
+            let (width, height) = variant.dimensions(source.width(), source.height());
+            let resized = source.resize_exact(width, height, filter);
+            save_image(&resized, variant_file.path(), self.config.quality)?;
+            optimize_png(variant_file.path());
+            let variant_path = variant_file.path().to_path_buf();
+            variant_paths.push(variant_file);
+
+            // Emit WebP/AVIF copies alongside; a failed encode only loses that one file
+            if self.config.webp {
+                let webp_file = TempFile::new(variant_path.with_extension("webp"));
+                match resized.save_with_format(webp_file.path(), ImageFormat::WebP) {
+                    Ok(()) => variant_paths.push(webp_file),
+                    Err(e) => warn!("Failed to encode WebP for {} variant: {}", variant_name, e),
+                }
+            }
+            if self.config.avif {
+                let avif_file = TempFile::new(variant_path.with_extension("avif"));
+                match save_avif(&resized, avif_file.path(), self.config.quality) {
+                    Ok(()) => variant_paths.push(avif_file),
+                    Err(e) => warn!("Failed to encode AVIF for {} variant: {}", variant_name, e),
+                }
+            }
         }
 
         Ok(variant_paths)
     }
 
-    fn generate_image_metadata(&self, image: &DynamicImage, uid: &str, format: &str) -> ImageMetadata {
+    fn generate_image_metadata(&self, image: &DynamicImage, uid: &str, format: &str, keys: Vec<String>) -> ImageMetadata {
         ImageMetadata {
-            date: Local::now().format("%Y-%m-%d %H:%M:%S -0400").to_string(),
+            date: metadata_date(),
             uid: uid.to_string(),
             width: image.width(),
             height: image.height(),
             format: format.to_string(),
+            blurhash: compute_blurhash(image).unwrap_or_default(),
             alt: String::new(),
             caption: String::new(),
             credit: String::new(),
+            keys,
         }
     }
 
     fn generate_file_metadata(&self, uid: &str, format: &str) -> FileMetadata {
         FileMetadata {
-            date: Local::now().format("%Y-%m-%d %H:%M:%S -0400").to_string(),
+            date: metadata_date(),
             uid: uid.to_string(),
             format: format.to_string(),
         }
@@ -181,78 +217,234 @@ impl S3Upload {
             .unwrap_or("")
             .to_lowercase();
 
-        // Convert JPG to PNG if needed
-        let final_path = if extension == "jpg" || extension == "jpeg" {
-            self.convert_jpg_to_png(path).await?
+        // TIFF and BMP always become PNG; JPG only when explicitly requested,
+        // since photos stay JPEG
+        let is_jpeg = extension == "jpg" || extension == "jpeg";
+        let final_path = if needs_png_normalization(path) || (is_jpeg && self.config.convert_jpg_to_png) {
+            self.convert_to_png(path).await?
         } else {
             local_path.to_string()
         };
 
         let path = Path::new(&final_path);
-        let is_image = matches!(extension.as_str(), "png" | "jpg" | "jpeg");
+        let extension = path.extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_lowercase();
 
-        if is_image {
+        if is_image(path) {
             // Process image and variants
-            let processed_dir = path.parent().unwrap().join("processed");
+            let processed_dir = path.parent().unwrap_or(Path::new("")).join("processed");
             fs::create_dir_all(&processed_dir)?;
 
             let img = image::open(path)?;
-            let uid = path.file_stem().unwrap().to_string_lossy();
-            
-            // Generate and write metadata
-            let metadata = self.generate_image_metadata(&img, &uid, &extension);
-            let metadata_path = Path::new("data/images").join(format!("{}.yml", uid));
-            fs::create_dir_all(metadata_path.parent().unwrap())?;
-            self.write_metadata(&metadata, &metadata_path).await?;
+            let uid = file_stem_lossy(path)?;
 
             // Create and upload variants
             let variants = self.create_image_variants(path, &processed_dir).await?;
-            for variant_path in variants {
-                self.upload_file(&variant_path, &Path::new(&variant_path).file_name().unwrap().to_string_lossy()).await?;
+            let storage_class = variant_storage_class();
+            let tagging = object_tagging(&[("type", "image"), ("role", "variant")]);
+            let mut keys = Vec::new();
+            for variant in &variants {
+                let file_name = file_name_lossy(variant.path())?;
+                let key = format!("{}{}", s3().image_prefix, file_name);
+                self.upload_file(&variant.path().to_string_lossy(), &key, storage_class.clone(), &tagging).await?;
+                keys.push(key);
             }
+
+            // Generate and write metadata
+            let metadata = self.generate_image_metadata(&img, &uid, &extension, keys);
+            let metadata_path = Path::new("data/images").join(format!("{}.yml", uid));
+            fs::create_dir_all(metadata_path.parent().unwrap())?;
+            self.write_metadata(&metadata, &metadata_path).await?;
         } else {
             // Handle regular files
-            let uid = path.file_stem().unwrap().to_string_lossy();
+            let uid = file_stem_lossy(path)?;
             let metadata = self.generate_file_metadata(&uid, &extension);
             let metadata_path = Path::new("data/files").join(format!("{}.yml", uid));
             fs::create_dir_all(metadata_path.parent().unwrap())?;
             self.write_metadata(&metadata, &metadata_path).await?;
 
-            self.upload_file(local_path, &format!("static/{}", path.file_name().unwrap().to_string_lossy())).await?;
+            let key = format!("{}{}", s3().static_prefix, file_name_lossy(path)?);
+            let tagging = object_tagging(&[("type", "file"), ("role", "original")]);
+            self.upload_file(local_path, &key, StorageClass::Standard, &tagging).await?;
         }
 
         Ok(())
     }
 
-    pub async fn upload_file(&self, local_path: &str, key: &str) -> Result<(), UploadError> {
-        let body = ByteStream::from_path(Path::new(local_path))
-            .await
+    pub async fn upload_file(
+        &self,
+        local_path: &str,
+        key: &str,
+        storage_class: StorageClass,
+        tagging: &str
+    ) -> Result<(), UploadError> {
+        let content_type = content_type_for(Path::new(local_path)).unwrap_or("application/octet-stream");
+
+        let options = PutOptions {
+            content_type,
+            storage_class,
+            acl: None,
+            tagging: Some(tagging).filter(|tagging| !tagging.is_empty()),
+        };
+        self.stores.for_key(key).put(key, Path::new(local_path), &options).await
+    }
+
+    pub async fn delete_file(&self, key: &str) -> Result<(), UploadError> {
+        self.stores.for_key(key).delete(key).await
+    }
+
+    /// Returns a time-limited GET URL for `key`, so objects in a private
+    /// bucket can still be previewed.
+    pub async fn presign_get(&self, key: &str, expires_in: Duration) -> Result<String, UploadError> {
+        let presigning = PresigningConfig::expires_in(expires_in)
             .map_err(|e| UploadError::Other(e.to_string()))?;
 
-        let content_type = from_path(local_path)
-            .first_or_octet_stream()
-            .to_string();
+        let request = self.client
+            .get_object()
+            .bucket(self.stores.for_key(key).bucket())
+            .key(key)
+            .presigned(presigning)
+            .await?;
 
-        self.client
+        Ok(request.uri().to_string())
+    }
+
+    /// Returns a time-limited PUT URL for `key`, so a browser can upload a
+    /// large file straight to the bucket: the image bucket for images, the
+    /// file bucket for anything else. The URL is signed with the run's
+    /// `OBJECT_ACL`, encryption and tagging, so the upload must send the
+    /// returned headers, `Content-Type` included, unchanged.
+    pub async fn presign_put(&self, key: &str, content_type: &str, expires_in: Duration) -> Result<PresignedPut, UploadError> {
+        let presigning = PresigningConfig::expires_in(expires_in)
+            .map_err(|e| UploadError::Other(e.to_string()))?;
+
+        let (store, kind) = if is_image(Path::new(key)) {
+            (&self.stores.images, "image")
+        } else {
+            (&self.stores.files, "file")
+        };
+        let tagging = object_tagging(&[("type", kind), ("role", "original")]);
+        let encryption = Encryption::from_env();
+        let request = self.client
             .put_object()
-            .bucket(&self.config.bucket)
+            .bucket(store.bucket())
             .key(key)
-            .body(body)
             .content_type(content_type)
-            .send()
+            .set_acl(object_acl())
+            .set_tagging(Some(tagging).filter(|tagging| !tagging.is_empty()))
+            .set_server_side_encryption(encryption.server_side_encryption())
+            .set_ssekms_key_id(encryption.kms_key_id())
+            .presigned(presigning)
             .await?;
 
-        Ok(())
+        let headers = request
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        Ok(PresignedPut { url: request.uri().to_string(), headers })
     }
 
-    pub async fn delete_file(&self, key: &str) -> Result<(), UploadError> {
-        self.client
-            .delete_object()
-            .bucket(&self.config.bucket)
-            .key(key)
-            .send()
-            .await?;
+    /// Deletes every S3 object recorded for an image uid (the original plus
+    /// all variants), then removes its local metadata file. Returns the
+    /// number of objects deleted.
+    pub async fn delete_image(&self, uid: &str) -> Result<usize, UploadError> {
+        let metadata_path = Path::new("data/images").join(format!("{}.yml", uid));
+        let content = fs::read_to_string(&metadata_path)?;
+        let metadata: serde_yaml::Value = serde_yaml::from_str(&content)
+            .map_err(|e| UploadError::Other(format!("Invalid metadata {}: {}", metadata_path.display(), e)))?;
+
+        let format = metadata
+            .get("format")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| UploadError::Other(format!("No format in {}", metadata_path.display())))?;
+
+        let mut keys = Vec::new();
+        // Partitioned uploads record where the original actually went
+        if let Some(key) = metadata.get("key").and_then(|v| v.as_str()) {
+            keys.push(key.to_string());
+        }
+        if let Some(listed) = metadata.get("keys").and_then(|v| v.as_sequence()) {
+            keys.extend(listed.iter().filter_map(|k| k.as_str()).map(String::from));
+        }
+        if let Some(thumbnail) = metadata.get("thumbnail").and_then(|v| v.as_str()) {
+            keys.push(thumbnail.to_string());
+        }
+        if let Some(variants) = metadata.get("variants").and_then(|v| v.as_sequence()) {
+            keys.extend(variants.iter().filter_map(|v| v.get("key")?.as_str()).map(String::from));
+        }
 
-        Ok(())
+        // Older metadata doesn't record its keys, so fall back to the names
+        // the current settings would give, but only those actually in the bucket
+        let prefix = &s3().image_prefix;
+        let mut guessed = vec![format!("{}{}.{}", prefix, uid, format)];
+        for (name, variant) in VARIANT_SETTINGS.iter() {
+            guessed.push(format!("{}{}{}.{}", prefix, uid, variant.suffix(name), format));
+        }
+        for key in guessed {
+            if !keys.contains(&key) && self.stores.images.head(&key).await?.is_some() {
+                keys.push(key);
+            }
+        }
+        keys.sort();
+        keys.dedup();
+
+        for key in &keys {
+            self.stores.images.delete(key).await?;
+            info!("Deleted S3 object: {}", key);
+        }
+
+        fs::remove_file(&metadata_path)?;
+
+        Ok(keys.len())
+    }
+
+    /// Deletes the objects recorded for a static file uid (the file and any
+    /// PDF preview) from the file bucket, then removes its local metadata
+    /// file. Returns the number of objects deleted.
+    pub async fn delete_static_file(&self, uid: &str) -> Result<usize, UploadError> {
+        let metadata_path = Path::new("data/files").join(format!("{}.yml", uid));
+        let content = fs::read_to_string(&metadata_path)?;
+        let metadata: serde_yaml::Value = serde_yaml::from_str(&content)
+            .map_err(|e| UploadError::Other(format!("Invalid metadata {}: {}", metadata_path.display(), e)))?;
+
+        let mut keys = Vec::new();
+        for field in ["key", "thumbnail"] {
+            if let Some(key) = metadata.get(field).and_then(|v| v.as_str()) {
+                keys.push(key.to_string());
+            }
+        }
+        // Metadata from before keys were recorded names the file by uid and format
+        if keys.is_empty() {
+            if let Some(format) = metadata.get("format").and_then(|v| v.as_str()) {
+                let key = format!("{}{}.{}", s3().static_prefix, uid, format);
+                if self.stores.files.head(&key).await?.is_some() {
+                    keys.push(key);
+                }
+            }
+        }
+
+        for key in &keys {
+            self.stores.files.delete(key).await?;
+            info!("Deleted S3 object: {}", key);
+        }
+
+        fs::remove_file(&metadata_path)?;
+
+        Ok(keys.len())
+    }
+
+    /// Deletes an image uid, or a static file uid when only `data/files`
+    /// has metadata for it. Returns the number of objects deleted.
+    pub async fn delete(&self, uid: &str) -> Result<usize, UploadError> {
+        let is_file = !Path::new("data/images").join(format!("{}.yml", uid)).exists() &&
+            Path::new("data/files").join(format!("{}.yml", uid)).exists();
+        if is_file {
+            self.delete_static_file(uid).await
+        } else {
+            self.delete_image(uid).await
+        }
     }
 }
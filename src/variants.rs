@@ -0,0 +1,442 @@
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+
+const VARIANTS_FILE: &str = "variants.toml";
+
+/// How a variant's `width` is applied to the source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeMode {
+    /// Scale so the image is `width` wide (the default).
+    #[default]
+    Width,
+    /// Scale so the longer side is `width`, whatever the orientation, giving
+    /// portrait and landscape images the same footprint.
+    LongestEdge,
+    /// Scale to fit inside a `width` x `max_height` box, square when
+    /// `max_height` is unset.
+    Fit,
+}
+
+impl ResizeMode {
+    /// Scale factor taking a `src_width` x `src_height` source to `size`,
+    /// further limited by `max_height` when set.
+    fn scale(self, src_width: u32, src_height: u32, size: u32, max_height: Option<u32>) -> f32 {
+        let height_scale = max_height.map(|max_height| max_height as f32 / src_height as f32);
+        let scale = match self {
+            ResizeMode::Width => size as f32 / src_width as f32,
+            ResizeMode::LongestEdge => size as f32 / src_width.max(src_height) as f32,
+            ResizeMode::Fit => {
+                let box_height = max_height.unwrap_or(size);
+                (size as f32 / src_width as f32).min(box_height as f32 / src_height as f32)
+            }
+        };
+        height_scale.map_or(scale, |height_scale| scale.min(height_scale))
+    }
+
+    /// Output size for a `src_width` x `src_height` source, keeping its
+    /// aspect ratio.
+    pub fn dimensions(self, src_width: u32, src_height: u32, size: u32, max_height: Option<u32>) -> (u32, u32) {
+        scaled(src_width, src_height, self.scale(src_width, src_height, size, max_height))
+    }
+}
+
+/// Where an aspect-ratio crop places its window on the source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CropStrategy {
+    /// The middle of the image (the default).
+    #[default]
+    Center,
+    /// The busiest part of the image, by luminance entropy.
+    Entropy,
+}
+
+impl CropStrategy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CropStrategy::Center => "center",
+            CropStrategy::Entropy => "entropy",
+        }
+    }
+}
+
+/// The part of the source a cropped variant is cut from, recorded in the
+/// metadata so the crop can be reproduced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CropRegion {
+    /// The ratio as configured, e.g. `16:9`.
+    pub aspect: String,
+    pub strategy: CropStrategy,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Parses an aspect ratio written as `16:9` or `16/9`.
+pub fn parse_aspect(text: &str) -> Option<(u32, u32)> {
+    let (width, height) = text.split_once([':', '/'])?;
+    let width: u32 = width.trim().parse().ok()?;
+    let height: u32 = height.trim().parse().ok()?;
+    (width > 0 && height > 0).then_some((width, height))
+}
+
+/// The largest `aspect` window that fits a `src_width` x `src_height`
+/// source, centred on it.
+pub fn centre_crop(src_width: u32, src_height: u32, aspect: (u32, u32)) -> (u32, u32, u32, u32) {
+    let (aspect_width, aspect_height) = (aspect.0 as u64, aspect.1 as u64);
+    let (width, height) = if src_width as u64 * aspect_height >= src_height as u64 * aspect_width {
+        let width = (src_height as u64 * aspect_width + aspect_height / 2) / aspect_height;
+        (width.clamp(1, src_width as u64) as u32, src_height)
+    } else {
+        let height = (src_width as u64 * aspect_height + aspect_width / 2) / aspect_width;
+        (src_width, height.clamp(1, src_height as u64) as u32)
+    };
+    ((src_width - width) / 2, (src_height - height) / 2, width, height)
+}
+
+/// Settings for an image variant.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct VariantSetting {
+    pub width: u32,
+    /// Caps the height too, so tall portraits scale down further than `width` alone.
+    #[serde(default)]
+    pub max_height: Option<u32>,
+    #[serde(default)]
+    pub mode: ResizeMode,
+    /// Crops the source to this ratio, e.g. `16:9`, before resizing. Cropped
+    /// variants are named after the breakpoint, `uid_hero.jpg`.
+    #[serde(default)]
+    pub aspect: Option<String>,
+    #[serde(default)]
+    pub crop: CropStrategy,
+}
+
+impl VariantSetting {
+    /// Whether this variant would shrink a `src_width` x `src_height` source.
+    /// Variants are never upscaled, so smaller sources skip the wider
+    /// breakpoints.
+    pub fn downscales(&self, src_width: u32, src_height: u32) -> bool {
+        match self.mode {
+            ResizeMode::Width => self.width < src_width,
+            ResizeMode::LongestEdge => self.width < src_width.max(src_height),
+            ResizeMode::Fit => self.mode.scale(src_width, src_height, self.width, self.max_height) < 1.0,
+        }
+    }
+
+    /// Output size of this variant for a `src_width` x `src_height` source.
+    pub fn dimensions(&self, src_width: u32, src_height: u32) -> (u32, u32) {
+        self.mode.dimensions(src_width, src_height, self.width, self.max_height)
+    }
+
+    /// The centred crop window for a `src_width` x `src_height` source, when
+    /// this variant has an aspect ratio. Entropy crops are moved into place
+    /// once the pixels are loaded.
+    pub fn crop_region(&self, src_width: u32, src_height: u32) -> Option<CropRegion> {
+        let aspect = self.aspect.as_deref()?;
+        let (x, y, width, height) = centre_crop(src_width, src_height, parse_aspect(aspect)?);
+        Some(CropRegion { aspect: aspect.to_string(), strategy: self.crop, x, y, width, height })
+    }
+
+    /// File name suffix after the uid for the 1x copy: `_w800`, or
+    /// `_{name}` for cropped variants.
+    pub fn suffix(&self, name: &str) -> String {
+        match self.aspect {
+            Some(_) => format!("_{}", name),
+            None => format!("_w{}", self.width),
+        }
+    }
+}
+
+/// Layout of `variants.toml`, e.g.
+///
+/// ```toml
+/// [variants.mobile]
+/// width = 200
+/// max_height = 400       # optional
+/// mode = "longest_edge"  # optional: width (default), longest_edge or fit
+///
+/// [variants.hero]
+/// width = 1600
+/// aspect = "16:9"        # optional crop, uploaded as uid_hero.jpg
+/// crop = "entropy"       # optional: center (default) or entropy
+/// ```
+#[derive(Debug, Deserialize)]
+struct VariantsFile {
+    variants: HashMap<String, VariantSetting>,
+}
+
+// Image variant settings, loaded once from variants.toml
+lazy_static! {
+    pub static ref VARIANT_SETTINGS: HashMap<String, VariantSetting> = load_variant_settings();
+}
+
+fn default_variant_settings() -> HashMap<String, VariantSetting> {
+    let mut m = HashMap::new();
+    m.insert("mobile".to_string(), VariantSetting { width: 200, ..VariantSetting::default() });
+    m.insert("tablet".to_string(), VariantSetting { width: 400, ..VariantSetting::default() });
+    m.insert("desktop_md".to_string(), VariantSetting { width: 800, ..VariantSetting::default() });
+    m.insert("desktop_lg".to_string(), VariantSetting { width: 1200, ..VariantSetting::default() });
+    m
+}
+
+/// Output size for a variant: scaled to `width`, or further down if that
+/// would exceed `max_height`, keeping the source aspect ratio. Both sides are
+/// rounded and kept at least 1px, which encoders require.
+pub fn variant_dimensions(src_width: u32, src_height: u32, width: u32, max_height: Option<u32>) -> (u32, u32) {
+    ResizeMode::Width.dimensions(src_width, src_height, width, max_height)
+}
+
+fn scaled(src_width: u32, src_height: u32, scale: f32) -> (u32, u32) {
+    let width = ((src_width as f32 * scale).round() as u32).max(1);
+    let height = ((src_height as f32 * scale).round() as u32).max(1);
+    (width, height)
+}
+
+/// A single file to generate for a breakpoint at a given pixel density.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedVariant {
+    /// Breakpoint name, e.g. `desktop_md`.
+    pub name: String,
+    /// The breakpoint's nominal (1x) width, used in the file name.
+    pub breakpoint: u32,
+    /// 1 for standard variants, 2 for retina copies.
+    pub density: u8,
+    pub width: u32,
+    pub height: u32,
+    /// Set for variants cut to an aspect ratio before resizing.
+    pub crop: Option<CropRegion>,
+    /// Short content hash for `HASHED_FILENAMES`.
+    pub hash: Option<String>,
+}
+
+impl PlannedVariant {
+    /// File name suffix after the uid: `_w800` or `_w800@2x`, and `_hero` or
+    /// `_hero@2x` for cropped variants, followed by `.{hash}` when hashed.
+    pub fn suffix(&self) -> String {
+        let base = match self.crop {
+            Some(_) => format!("_{}", self.name),
+            None => format!("_w{}", self.breakpoint),
+        };
+        let suffix = match self.density {
+            1 => base,
+            density => format!("{}@{}x", base, density),
+        };
+        with_hash(suffix, self.hash.as_deref())
+    }
+}
+
+/// Appends `.{hash}` to a variant suffix, so `uid_w800` becomes
+/// `uid_w800.abc12345` and changed content gets a new URL.
+fn with_hash(suffix: String, hash: Option<&str>) -> String {
+    match hash {
+        Some(hash) => format!("{}.{}", suffix, hash),
+        None => suffix,
+    }
+}
+
+/// A centre-cropped square thumbnail for card layouts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SquareThumbnail {
+    /// Edge length in pixels.
+    pub size: u32,
+    /// Short content hash for `HASHED_FILENAMES`.
+    pub hash: Option<String>,
+}
+
+impl SquareThumbnail {
+    /// File name suffix after the uid: `_thumb150`, or `_thumb150.{hash}`.
+    pub fn suffix(&self) -> String {
+        with_hash(format!("_thumb{}", self.size), self.hash.as_deref())
+    }
+}
+
+/// The square thumbnail for a `src_width` x `src_height` source, unless
+/// thumbnails are off or the source's short side is smaller than `size`.
+pub fn plan_thumbnail(src_width: u32, src_height: u32, size: Option<u32>) -> Option<SquareThumbnail> {
+    size.filter(|&size| size <= src_width.min(src_height)).map(|size| SquareThumbnail { size, hash: None })
+}
+
+/// Every variant to generate for a `src_width` x `src_height` source, ordered
+/// by breakpoint. Breakpoints that would upscale are left out; with `retina`
+/// each breakpoint also gets a 2x copy, capped at the source size.
+pub fn plan_variants(src_width: u32, src_height: u32, retina: bool) -> Vec<PlannedVariant> {
+    let mut settings: Vec<_> = VARIANT_SETTINGS.iter().collect();
+    settings.sort_by_key(|(name, variant)| (variant.width, name.as_str()));
+
+    let mut plan = Vec::new();
+    for (name, variant) in settings {
+        // Cropped variants are sized from the crop window rather than the whole source
+        let crop = variant.crop_region(src_width, src_height);
+        let (base_width, base_height) = crop.as_ref().map_or((src_width, src_height), |crop| (crop.width, crop.height));
+        if !variant.downscales(base_width, base_height) {
+            debug!(
+                "Skipping {} variant: {}px is not smaller than the {}x{} source",
+                name,
+                variant.width,
+                base_width,
+                base_height
+            );
+            continue;
+        }
+
+        let (width, height) = variant.dimensions(base_width, base_height);
+        plan.push(PlannedVariant {
+            name: name.clone(),
+            breakpoint: variant.width,
+            density: 1,
+            width,
+            height,
+            crop: crop.clone(),
+            hash: None,
+        });
+
+        if retina {
+            let scale_2x = variant.mode.scale(base_width, base_height, variant.width * 2, variant.max_height.map(|h| h * 2));
+            let (width_2x, height_2x) = scaled(base_width, base_height, scale_2x.min(1.0));
+            if width_2x > width {
+                plan.push(PlannedVariant {
+                    name: name.clone(),
+                    breakpoint: variant.width,
+                    density: 2,
+                    width: width_2x,
+                    height: height_2x,
+                    crop,
+                    hash: None,
+                });
+            }
+        }
+    }
+    plan
+}
+
+/// Reads the variant breakpoints from `variants.toml`, falling back to the
+/// built-in defaults when the file is absent or invalid.
+fn load_variant_settings() -> HashMap<String, VariantSetting> {
+    let content = match fs::read_to_string(VARIANTS_FILE) {
+        Ok(content) => content,
+        Err(_) => return default_variant_settings(),
+    };
+
+    match toml::from_str::<VariantsFile>(&content) {
+        Ok(mut file) => {
+            debug!("Loaded {} variant settings from {}", file.variants.len(), VARIANTS_FILE);
+            for (name, variant) in &mut file.variants {
+                if let Some(aspect) = variant.aspect.clone().filter(|aspect| parse_aspect(aspect).is_none()) {
+                    warn!("Ignoring invalid aspect {:?} for {} variant in {}", aspect, name, VARIANTS_FILE);
+                    variant.aspect = None;
+                }
+            }
+            file.variants
+        }
+        Err(e) => {
+            warn!("Error parsing {}: {}. Using default variants.", VARIANTS_FILE, e);
+            default_variant_settings()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variants_never_upscale() {
+        let variant = VariantSetting { width: 1200, ..VariantSetting::default() };
+        assert!(variant.downscales(1600, 900));
+        assert!(!variant.downscales(1200, 900));
+        assert!(!variant.downscales(300, 200));
+    }
+
+    #[test]
+    fn longest_edge_gives_portraits_and_landscapes_the_same_footprint() {
+        let variant = VariantSetting { width: 400, mode: ResizeMode::LongestEdge, ..VariantSetting::default() };
+        assert_eq!(variant.dimensions(1600, 1200), (400, 300));
+        assert_eq!(variant.dimensions(1200, 1600), (300, 400));
+        // A narrow but tall source still shrinks
+        assert!(variant.downscales(300, 1600));
+        assert!(!variant.downscales(300, 400));
+    }
+
+    #[test]
+    fn fit_keeps_both_sides_inside_the_box() {
+        let square = VariantSetting { width: 400, mode: ResizeMode::Fit, ..VariantSetting::default() };
+        assert_eq!(square.dimensions(1600, 1200), (400, 300));
+        assert_eq!(square.dimensions(1200, 1600), (300, 400));
+
+        let banner = VariantSetting { width: 800, max_height: Some(200), mode: ResizeMode::Fit, ..VariantSetting::default() };
+        assert_eq!(banner.dimensions(1600, 1200), (267, 200));
+        assert!(!banner.downscales(600, 150));
+    }
+
+    #[test]
+    fn retina_copies_are_capped_at_the_source_width() {
+        let plan = plan_variants(1000, 500, true);
+        let mobile_2x = plan.iter().find(|v| v.breakpoint == 200 && v.density == 2).unwrap();
+        assert_eq!((mobile_2x.width, mobile_2x.height), (400, 200));
+        assert_eq!(mobile_2x.suffix(), "_w200@2x");
+        let hashed = PlannedVariant { hash: Some("abc12345".to_string()), ..mobile_2x.clone() };
+        assert_eq!(hashed.suffix(), "_w200@2x.abc12345");
+
+        let desktop_md_2x = plan.iter().find(|v| v.breakpoint == 800 && v.density == 2).unwrap();
+        assert_eq!((desktop_md_2x.width, desktop_md_2x.height), (1000, 500));
+
+        // 1200 would upscale a 1000px source, so it's skipped at both densities
+        assert!(plan.iter().all(|v| v.breakpoint != 1200));
+    }
+
+    #[test]
+    fn aspect_crops_take_the_largest_centred_window() {
+        assert_eq!(parse_aspect("16:9"), Some((16, 9)));
+        assert_eq!(parse_aspect(" 4 / 3 "), Some((4, 3)));
+        assert_eq!(parse_aspect("16:0"), None);
+        assert_eq!(parse_aspect("wide"), None);
+
+        // Too tall for 16:9, so the full width is kept
+        assert_eq!(centre_crop(1600, 1200, (16, 9)), (0, 150, 1600, 900));
+        // Too wide, so the full height is kept
+        assert_eq!(centre_crop(4000, 900, (16, 9)), (1200, 0, 1600, 900));
+
+        let hero = VariantSetting { width: 800, aspect: Some("16:9".to_string()), ..VariantSetting::default() };
+        let crop = hero.crop_region(1600, 1200).unwrap();
+        assert_eq!((crop.width, crop.height, crop.strategy), (1600, 900, CropStrategy::Center));
+        assert_eq!(hero.suffix("hero"), "_hero");
+        assert_eq!((hero.dimensions(crop.width, crop.height)), (800, 450));
+    }
+
+    #[test]
+    fn no_retina_copies_without_the_flag() {
+        assert!(plan_variants(1000, 500, false).iter().all(|v| v.density == 1));
+    }
+
+    #[test]
+    fn width_only_keeps_aspect_ratio() {
+        assert_eq!(variant_dimensions(1600, 1200, 800, None), (800, 600));
+    }
+
+    #[test]
+    fn max_height_limits_tall_portraits() {
+        assert_eq!(variant_dimensions(1000, 7500, 400, Some(600)), (80, 600));
+        // A landscape image already under the cap is scaled by width alone
+        assert_eq!(variant_dimensions(1600, 1200, 400, Some(600)), (400, 300));
+    }
+
+    #[test]
+    fn panoramas_never_get_a_zero_height() {
+        assert_eq!(variant_dimensions(10000, 1, 200, None), (200, 1));
+        assert!(plan_variants(10000, 1, true).iter().all(|v| v.width > 0 && v.height == 1));
+    }
+
+    #[test]
+    fn thumbnails_are_never_upscaled() {
+        assert_eq!(plan_thumbnail(800, 600, Some(150)), Some(SquareThumbnail { size: 150, hash: None }));
+        assert_eq!(plan_thumbnail(800, 100, Some(150)), None);
+        assert_eq!(plan_thumbnail(800, 600, None), None);
+        assert_eq!(SquareThumbnail { size: 150, hash: None }.suffix(), "_thumb150");
+        assert_eq!(SquareThumbnail { size: 150, hash: Some("abc12345".to_string()) }.suffix(), "_thumb150.abc12345");
+    }
+}
@@ -0,0 +1,43 @@
+use std::path::Path;
+
+use crate::error::UploadError;
+
+/// Re-encodes every frame of an animated GIF into a lossy animated WebP at
+/// `output`, keeping each frame's delay. Frames with no delay get 100 ms,
+/// which is what browsers show them for.
+#[cfg(feature = "animated-webp")]
+pub fn gif_to_animated_webp(gif: &Path, output: &Path, quality: u8) -> Result<(), UploadError> {
+    use image::codecs::gif::GifDecoder;
+    use image::AnimationDecoder;
+    use std::fs::{self, File};
+    use std::io::BufReader;
+    use webp_animation::{Encoder, EncoderOptions, EncodingConfig};
+
+    let to_error = |e: webp_animation::Error| UploadError::Other(format!("Animated WebP encode failed: {:?}", e));
+
+    let frames = GifDecoder::new(BufReader::new(File::open(gif)?))?.into_frames().collect_frames()?;
+    let Some(first) = frames.first() else {
+        return Err(UploadError::InvalidFile(format!("{:?} has no frames", gif)));
+    };
+    let options = EncoderOptions {
+        encoding_config: Some(EncodingConfig::new_lossy(f32::from(quality.clamp(1, 100)))),
+        ..EncoderOptions::default()
+    };
+    let mut encoder = Encoder::new_with_options(first.buffer().dimensions(), options).map_err(to_error)?;
+
+    let mut timestamp: i32 = 0;
+    for frame in &frames {
+        encoder.add_frame(frame.buffer().as_raw(), timestamp).map_err(to_error)?;
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let delay = numer / denom.max(1);
+        timestamp += if delay == 0 { 100 } else { delay as i32 };
+    }
+    let webp = encoder.finalize(timestamp).map_err(to_error)?;
+    fs::write(output, &*webp)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "animated-webp"))]
+pub fn gif_to_animated_webp(_gif: &Path, _output: &Path, _quality: u8) -> Result<(), UploadError> {
+    Err(UploadError::Other("Animated WebP needs a build with the animated-webp feature".to_string()))
+}